@@ -0,0 +1,361 @@
+/*!
+Structured trait attributes.
+
+Tokens minted with `nft_mint_with_attributes` carry a list of `trait_type -> value` attributes.
+They are indexed so that tokens can be listed by attribute and values counted per trait type,
+which is what rarity tools need. For marketplaces reading only the standard metadata, the
+attributes are also written into `extra` as `{"attributes": [{"trait_type": ..., "value": ...}]}`,
+next to whatever other keys `extra` already holds.
+*/
+use crate::roles::Role;
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+use near_contract_standards::non_fungible_token::events::NftMint;
+use near_contract_standards::non_fungible_token::metadata::TokenMetadata;
+use near_contract_standards::non_fungible_token::{refund_deposit, Token, TokenId};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde_json::{self, Map, Value};
+use near_sdk::{env, near, require, AccountId};
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Bound;
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Attribute {
+    pub trait_type: String,
+    pub value: String,
+}
+
+/// `extra` with the `attributes` key set to `attributes`. Panics if `extra` is not a JSON object.
+fn extra_with_attributes(extra: Option<&str>, attributes: &[Attribute]) -> String {
+    let mut object = match extra {
+        None => Map::new(),
+        Some(extra) => match serde_json::from_str(extra) {
+            Ok(Value::Object(object)) => object,
+            _ => env::panic_str("`extra` must be a JSON object to hold attributes"),
+        },
+    };
+    object.insert(
+        "attributes".to_string(),
+        serde_json::to_value(attributes).unwrap(),
+    );
+    Value::Object(object).to_string()
+}
+
+impl Contract {
+    fn index_attributes(&mut self, token_id: &TokenId, attributes: &[Attribute]) {
+        let mut trait_types = HashSet::new();
+        for attribute in attributes {
+            require!(
+                !attribute.trait_type.is_empty(),
+                "Trait type must not be empty"
+            );
+            require!(
+                trait_types.insert(&attribute.trait_type),
+                format!("Duplicate trait type {}", attribute.trait_type)
+            );
+            self.attribute_tokens.insert(
+                &(
+                    attribute.trait_type.clone(),
+                    attribute.value.clone(),
+                    token_id.clone(),
+                ),
+                &(),
+            );
+            let mut counts = self
+                .attribute_counts
+                .get(&attribute.trait_type)
+                .unwrap_or_default();
+            *counts.entry(attribute.value.clone()).or_insert(0) += 1;
+            self.attribute_counts.insert(&attribute.trait_type, &counts);
+        }
+        self.token_attributes.insert(token_id, &attributes.to_vec());
+    }
+
+    /// Drops `token_id` from the attribute indexes. Called when the token is burnt.
+    pub(crate) fn unindex_attributes(&mut self, token_id: &TokenId) {
+        let Some(attributes) = self.token_attributes.remove(token_id) else {
+            return;
+        };
+        for attribute in attributes {
+            self.attribute_tokens.remove(&(
+                attribute.trait_type.clone(),
+                attribute.value.clone(),
+                token_id.clone(),
+            ));
+            let mut counts = self
+                .attribute_counts
+                .get(&attribute.trait_type)
+                .unwrap_or_default();
+            if let Some(count) = counts.get_mut(&attribute.value) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.remove(&attribute.value);
+                }
+            }
+            if counts.is_empty() {
+                self.attribute_counts.remove(&attribute.trait_type);
+            } else {
+                self.attribute_counts.insert(&attribute.trait_type, &counts);
+            }
+        }
+    }
+}
+
+#[near]
+impl Contract {
+    /// Same as `nft_mint`, with `attributes` indexed and written into `extra`. Can be called by
+    /// any account holding the `Minter` role; the storage is paid from the attached deposit.
+    #[payable]
+    pub fn nft_mint_with_attributes(
+        &mut self,
+        token_id: TokenId,
+        token_owner_id: AccountId,
+        token_metadata: TokenMetadata,
+        attributes: Vec<Attribute>,
+    ) -> Token {
+        self.assert_role(Role::Minter);
+        let token_metadata = TokenMetadata {
+            extra: Some(extra_with_attributes(
+                token_metadata.extra.as_deref(),
+                &attributes,
+            )),
+            ..token_metadata
+        };
+        let initial_storage_usage = env::storage_usage();
+        let token = self.internal_mint(token_id, token_owner_id, token_metadata);
+        self.index_attributes(&token.token_id, &attributes);
+        refund_deposit(env::storage_usage() - initial_storage_usage);
+
+        NftMint {
+            owner_id: &token.owner_id,
+            token_ids: &[&token.token_id],
+            memo: None,
+        }
+        .emit();
+        token
+    }
+
+    /// Attributes `token_id` was minted with, empty if it has none.
+    pub fn nft_attributes(&self, token_id: TokenId) -> Vec<Attribute> {
+        self.owner_of(&token_id);
+        self.token_attributes.get(&token_id).unwrap_or_default()
+    }
+
+    /// Tokens whose `trait_type` attribute is `value`, by token id.
+    pub fn nft_tokens_by_attribute(
+        &self,
+        trait_type: String,
+        value: String,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+    ) -> Vec<Token> {
+        let start = u128::from(from_index.unwrap_or(U128(0)));
+        let first = (trait_type.clone(), value.clone(), String::new());
+        self.attribute_tokens
+            .range((Bound::Included(first), Bound::Unbounded))
+            .take_while(|((t, v, _), _)| *t == trait_type && *v == value)
+            .skip(start as usize)
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .filter_map(|((_, _, token_id), _)| self.tokens.nft_token(token_id))
+            .map(|token| self.token_view(token))
+            .collect()
+    }
+
+    /// Number of existing tokens per value of `trait_type`.
+    pub fn attribute_counts(&self, trait_type: String) -> BTreeMap<String, U64> {
+        self.attribute_counts
+            .get(&trait_type)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(value, count)| (value, count.into()))
+            .collect()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, NearToken};
+
+    use super::*;
+
+    fn token_metadata(extra: Option<&str>) -> TokenMetadata {
+        TokenMetadata {
+            extra: extra.map(str::to_string),
+            ..sample_token_metadata()
+        }
+    }
+
+    fn attributes(pairs: &[(&str, &str)]) -> Vec<Attribute> {
+        pairs
+            .iter()
+            .map(|(trait_type, value)| Attribute {
+                trait_type: trait_type.to_string(),
+                value: value.to_string(),
+            })
+            .collect()
+    }
+
+    fn call(context: &mut VMContextBuilder, deposit: NearToken) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(deposit)
+            .build());
+    }
+
+    /// Tokens "0" and "1" with a red background, "2" with a blue one, all owned by accounts(1).
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        for (token_id, background) in [("0", "red"), ("1", "red"), ("2", "blue")] {
+            call(context, MINT_STORAGE_COST);
+            contract.nft_mint_with_attributes(
+                token_id.to_string(),
+                accounts(1),
+                token_metadata(None),
+                attributes(&[("background", background), ("eyes", "laser")]),
+            );
+        }
+        contract
+    }
+
+    #[test]
+    fn test_mint_with_attributes() {
+        let mut context = get_context(accounts(0));
+        let contract = setup(&mut context);
+        assert_eq!(
+            contract.nft_attributes("2".to_string()),
+            attributes(&[("background", "blue"), ("eyes", "laser")])
+        );
+        let extra = contract
+            .nft_token("2".to_string())
+            .unwrap()
+            .metadata
+            .unwrap()
+            .extra;
+        assert_eq!(
+            serde_json::from_str::<Value>(&extra.unwrap()).unwrap(),
+            serde_json::json!({"attributes": [
+                {"trait_type": "background", "value": "blue"},
+                {"trait_type": "eyes", "value": "laser"},
+            ]})
+        );
+    }
+
+    #[test]
+    fn test_extra_keeps_other_keys() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, MINT_STORAGE_COST);
+        let token = contract.nft_mint_with_attributes(
+            "3".to_string(),
+            accounts(1),
+            token_metadata(Some(r#"{"series":"mars"}"#)),
+            attributes(&[("background", "red")]),
+        );
+        let extra: Value = serde_json::from_str(&token.metadata.unwrap().extra.unwrap()).unwrap();
+        assert_eq!(extra["series"], "mars");
+        assert_eq!(extra["attributes"][0]["value"], "red");
+    }
+
+    #[test]
+    fn test_tokens_by_attribute() {
+        let mut context = get_context(accounts(0));
+        let contract = setup(&mut context);
+        let token_ids = |tokens: Vec<Token>| {
+            tokens
+                .into_iter()
+                .map(|token| token.token_id)
+                .collect::<Vec<_>>()
+        };
+        let by_background = |value: &str, from_index, limit| {
+            token_ids(contract.nft_tokens_by_attribute(
+                "background".to_string(),
+                value.to_string(),
+                from_index,
+                limit,
+            ))
+        };
+        assert_eq!(by_background("red", None, None), vec!["0", "1"]);
+        assert_eq!(by_background("red", Some(U128(1)), Some(1)), vec!["1"]);
+        assert_eq!(by_background("blue", None, None), vec!["2"]);
+        assert!(by_background("green", None, None).is_empty());
+    }
+
+    #[test]
+    fn test_tokens_by_attribute_are_rendered() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        contract.set_svg_canvas(10, 10, vec!["background".to_string()]);
+        let tokens = contract.nft_tokens_by_attribute(
+            "background".to_string(),
+            "blue".to_string(),
+            None,
+            None,
+        );
+        assert_eq!(
+            tokens[0].metadata.as_ref().unwrap().media,
+            contract.nft_render("2".to_string())
+        );
+        assert!(tokens[0].metadata.as_ref().unwrap().media.is_some());
+    }
+
+    #[test]
+    fn test_counts_follow_burns() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        let counts = contract.attribute_counts("background".to_string());
+        assert_eq!(counts.get("red"), Some(&U64(2)));
+        assert_eq!(counts.get("blue"), Some(&U64(1)));
+        assert_eq!(
+            contract.attribute_counts("eyes".to_string()).get("laser"),
+            Some(&U64(3))
+        );
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(NearToken::from_yoctonear(1))
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.nft_burn("2".to_string());
+        assert_eq!(
+            contract
+                .attribute_counts("background".to_string())
+                .get("blue"),
+            None
+        );
+        assert!(contract
+            .nft_tokens_by_attribute("background".to_string(), "blue".to_string(), None, None)
+            .is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Duplicate trait type background")]
+    fn test_duplicate_trait_type() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, MINT_STORAGE_COST);
+        contract.nft_mint_with_attributes(
+            "3".to_string(),
+            accounts(1),
+            token_metadata(None),
+            attributes(&[("background", "red"), ("background", "blue")]),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "`extra` must be a JSON object to hold attributes")]
+    fn test_extra_not_an_object() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, MINT_STORAGE_COST);
+        contract.nft_mint_with_attributes(
+            "3".to_string(),
+            accounts(1),
+            token_metadata(Some("rare")),
+            attributes(&[("background", "red")]),
+        );
+    }
+}
@@ -0,0 +1,368 @@
+/*!
+Token URIs relative to `base_uri`.
+
+With relative URIs enabled, tokens only store what follows `base_uri`, and the views put it back
+together at read time: a relative `media` or `reference` becomes `{base_uri}/{path}`, and a token
+without `reference` gets `{base_uri}/{token_id}.json`. URIs with a scheme, such as `ipfs://` or
+`data:` ones, are returned as stored.
+
+A URI is absolute when it starts with a scheme as defined by RFC 3986, so paths with a `:` in
+their first segment, like `3:14.png` for a token of series `3`, are still relative, as long as
+what comes before the `:` is not a valid scheme.
+
+Tokens minted before the switch can be rewritten with `migrate_to_relative_uris`, which strips
+the `base_uri` prefix from their URIs and drops references matching the default. URIs whose path
+would then read as absolute, like `pool:1.png`, are left as they are.
+*/
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::metadata::TokenMetadata;
+use near_contract_standards::non_fungible_token::{refund_deposit, Token, TokenId};
+use near_sdk::{env, near, require};
+
+/// Scheme of `uri`: a letter followed by letters, digits, `+`, `-` or `.`, up to the first `:`.
+/// `None` for a relative reference.
+pub(crate) fn uri_scheme(uri: &str) -> Option<&str> {
+    let (scheme, _) = uri.split_once(':')?;
+    let mut chars = scheme.chars();
+    let is_scheme = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    is_scheme.then_some(scheme)
+}
+
+fn is_relative(uri: &str) -> bool {
+    uri_scheme(uri).is_none()
+}
+
+fn join(base_uri: &str, path: &str) -> String {
+    format!(
+        "{}/{}",
+        base_uri.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
+
+impl Contract {
+    fn base_uri(&self) -> Option<String> {
+        self.metadata.get().and_then(|metadata| metadata.base_uri)
+    }
+
+    /// Resolves the relative URIs of `token` against `base_uri`, if relative URIs are enabled.
+    pub(crate) fn with_absolute_uris(&self, mut token: Token) -> Token {
+        if !self.relative_uris {
+            return token;
+        }
+        let (Some(base_uri), Some(metadata)) = (self.base_uri(), token.metadata.as_mut()) else {
+            return token;
+        };
+        if let Some(media) = metadata.media.as_mut() {
+            if is_relative(media) {
+                *media = join(&base_uri, media);
+            }
+        }
+        metadata.reference = Some(match metadata.reference.take() {
+            None => join(&base_uri, &format!("{}.json", token.token_id)),
+            Some(reference) if is_relative(&reference) => join(&base_uri, &reference),
+            Some(reference) => reference,
+        });
+        token
+    }
+}
+
+#[near]
+impl Contract {
+    /// Sets `base_uri` in the contract metadata. The storage is paid from the attached deposit.
+    #[payable]
+    pub fn set_base_uri(&mut self, base_uri: Option<String>) {
+        self.assert_owner();
+        require!(
+            base_uri.is_some() || !self.relative_uris,
+            "Relative URIs need a base URI"
+        );
+        let mut metadata = self.metadata.get().unwrap();
+        let initial_storage_usage = env::storage_usage();
+        metadata.base_uri = base_uri;
+        self.metadata.set(&metadata);
+        refund_deposit(env::storage_usage().saturating_sub(initial_storage_usage));
+    }
+
+    /// Turns the resolution of relative URIs on or off. Turning it off returns the stored paths
+    /// as they are.
+    pub fn set_relative_uris(&mut self, enabled: bool) {
+        self.assert_owner();
+        require!(
+            !enabled || self.base_uri().is_some(),
+            "Relative URIs need a base URI"
+        );
+        self.relative_uris = enabled;
+    }
+
+    pub fn relative_uris(&self) -> bool {
+        self.relative_uris
+    }
+
+    /// Rewrites the absolute URIs of `token_ids` under `base_uri` into relative ones. Released
+    /// storage stays with the contract.
+    pub fn migrate_to_relative_uris(&mut self, token_ids: Vec<TokenId>) {
+        self.assert_owner();
+        require!(self.relative_uris, "Relative URIs are not enabled");
+        let base_uri = self.base_uri().unwrap();
+        let prefix = format!("{}/", base_uri.trim_end_matches('/'));
+        let strip = |uri: String| match uri.strip_prefix(&prefix) {
+            Some(path) if is_relative(path) => path.to_string(),
+            _ => uri,
+        };
+        for token_id in token_ids {
+            self.owner_of(&token_id);
+            let metadata = self
+                .tokens
+                .token_metadata_by_id
+                .as_ref()
+                .and_then(|by_id| by_id.get(&token_id))
+                .unwrap();
+            let default_reference = format!("{}.json", token_id);
+            let migrated = TokenMetadata {
+                media: metadata.media.clone().map(strip),
+                reference: metadata
+                    .reference
+                    .clone()
+                    .map(strip)
+                    .filter(|reference| reference != &default_reference),
+                ..metadata.clone()
+            };
+            if migrated != metadata {
+                self.internal_update_metadata(&token_id, &migrated);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+    use near_contract_standards::non_fungible_token::enumeration::NonFungibleTokenEnumeration;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    const BASE_URI: &str = "https://example.com/mars";
+
+    fn token_metadata(media: Option<&str>, reference: Option<&str>) -> TokenMetadata {
+        TokenMetadata {
+            media: media.map(str::to_string),
+            reference: reference.map(str::to_string),
+            ..sample_token_metadata()
+        }
+    }
+
+    fn mint(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        token_id: &str,
+        metadata: TokenMetadata,
+    ) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .build());
+        contract.nft_mint(token_id.to_string(), accounts(1), metadata, None);
+    }
+
+    /// Contract with `BASE_URI` set, relative URIs still disabled.
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .build());
+        contract.set_base_uri(Some(BASE_URI.to_string()));
+        contract
+    }
+
+    fn uris(contract: &Contract, token_id: &str) -> (Option<String>, Option<String>) {
+        let metadata = contract
+            .nft_token(token_id.to_string())
+            .unwrap()
+            .metadata
+            .unwrap();
+        (metadata.media, metadata.reference)
+    }
+
+    #[test]
+    fn test_compose_relative_uris() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        contract.set_relative_uris(true);
+        mint(&mut context, &mut contract, "0", token_metadata(None, None));
+        mint(
+            &mut context,
+            &mut contract,
+            "1",
+            token_metadata(Some("1.png"), Some("meta/1.json")),
+        );
+        mint(
+            &mut context,
+            &mut contract,
+            "2",
+            token_metadata(Some("ipfs://image"), None),
+        );
+
+        assert_eq!(
+            uris(&contract, "0"),
+            (None, Some(format!("{}/0.json", BASE_URI)))
+        );
+        assert_eq!(
+            uris(&contract, "1"),
+            (
+                Some(format!("{}/1.png", BASE_URI)),
+                Some(format!("{}/meta/1.json", BASE_URI))
+            )
+        );
+        assert_eq!(uris(&contract, "2").0, Some("ipfs://image".to_string()));
+        assert_eq!(
+            contract.nft_tokens(None, None)[1]
+                .metadata
+                .as_ref()
+                .unwrap()
+                .media,
+            Some(format!("{}/1.png", BASE_URI))
+        );
+    }
+
+    #[test]
+    fn test_stored_uris_without_relative_mode() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        mint(
+            &mut context,
+            &mut contract,
+            "0",
+            token_metadata(Some("0.png"), None),
+        );
+        assert_eq!(uris(&contract, "0"), (Some("0.png".to_string()), None));
+    }
+
+    #[test]
+    fn test_migrate_absolute_uris() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        let media = format!("{}/0.png", BASE_URI);
+        let reference = format!("{}/0.json", BASE_URI);
+        mint(
+            &mut context,
+            &mut contract,
+            "0",
+            token_metadata(Some(&media), Some(&reference)),
+        );
+        mint(
+            &mut context,
+            &mut contract,
+            "1",
+            token_metadata(Some("ar://image"), None),
+        );
+        contract.set_relative_uris(true);
+
+        let before = env::storage_usage();
+        contract.migrate_to_relative_uris(vec!["0".to_string(), "1".to_string()]);
+        assert!(env::storage_usage() < before);
+        let stored = contract
+            .tokens
+            .token_metadata_by_id
+            .as_ref()
+            .unwrap()
+            .get(&"0".to_string())
+            .unwrap();
+        assert_eq!(stored.media, Some("0.png".to_string()));
+        assert_eq!(stored.reference, None);
+        assert_eq!(uris(&contract, "0"), (Some(media), Some(reference)));
+        assert_eq!(uris(&contract, "1").0, Some("ar://image".to_string()));
+    }
+
+    #[test]
+    fn test_colon_in_relative_path() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        contract.set_relative_uris(true);
+        mint(
+            &mut context,
+            &mut contract,
+            "3:14",
+            token_metadata(Some("3:14.png"), None),
+        );
+        assert_eq!(
+            uris(&contract, "3:14"),
+            (
+                Some(format!("{}/3:14.png", BASE_URI)),
+                Some(format!("{}/3:14.json", BASE_URI))
+            )
+        );
+    }
+
+    #[test]
+    fn test_migrate_colon_in_path() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        let series_media = format!("{}/3:14.png", BASE_URI);
+        let pool_media = format!("{}/pool:1.png", BASE_URI);
+        mint(
+            &mut context,
+            &mut contract,
+            "3:14",
+            token_metadata(Some(&series_media), None),
+        );
+        mint(
+            &mut context,
+            &mut contract,
+            "1",
+            token_metadata(Some(&pool_media), None),
+        );
+        contract.set_relative_uris(true);
+        contract.migrate_to_relative_uris(vec!["3:14".to_string(), "1".to_string()]);
+
+        let stored_media = |token_id: &str| {
+            contract
+                .tokens
+                .token_metadata_by_id
+                .as_ref()
+                .unwrap()
+                .get(&token_id.to_string())
+                .unwrap()
+                .media
+        };
+        assert_eq!(stored_media("3:14"), Some("3:14.png".to_string()));
+        // "pool" would read as a scheme, so the URI stays absolute.
+        assert_eq!(stored_media("1"), Some(pool_media.clone()));
+        assert_eq!(uris(&contract, "3:14").0, Some(series_media));
+        assert_eq!(uris(&contract, "1").0, Some(pool_media));
+    }
+
+    #[test]
+    fn test_uri_scheme() {
+        assert_eq!(uri_scheme("ipfs://image"), Some("ipfs"));
+        assert_eq!(uri_scheme("data:application/json,{}"), Some("data"));
+        assert_eq!(uri_scheme("web+nft:0"), Some("web+nft"));
+        assert_eq!(uri_scheme("3:14.png"), None);
+        assert_eq!(uri_scheme("images/3:14.png"), None);
+        assert_eq!(uri_scheme("0.png"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Relative URIs are not enabled")]
+    fn test_migrate_without_relative_mode() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        mint(&mut context, &mut contract, "0", token_metadata(None, None));
+        contract.migrate_to_relative_uris(vec!["0".to_string()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Relative URIs need a base URI")]
+    fn test_relative_mode_without_base_uri() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        contract.set_relative_uris(true);
+    }
+}
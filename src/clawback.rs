@@ -0,0 +1,319 @@
+/*!
+Clawback of tokens by the contract owner, to recover tokens lost with a key or to phishing.
+
+Clawback is off until the owner enables it. A forced transfer is first proposed with
+`propose_force_transfer`, which anyone, the holder included, can see with
+`pending_force_transfers` and through an `nft_force_transfer_proposed` event. It can only be
+executed with `nft_force_transfer` once `CLAWBACK_DELAY` has passed, which leaves the holder
+time to contest it. The token is frozen meanwhile: it cannot be transferred, burnt or staked until
+the forced transfer is executed or cancelled, so that it cannot slip away from the proposal. A
+collection can renounce clawback for good with `renounce_clawback`.
+*/
+use crate::events::{ContractEvent, NftForceTransfer, NftForceTransferProposed};
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::{refund_deposit, TokenId};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{env, near, require, AccountId};
+
+/// Delay between proposing and executing a forced transfer: 48 hours, in nanoseconds.
+pub const CLAWBACK_DELAY: u64 = 48 * 60 * 60 * 1_000_000_000;
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClawbackStatus {
+    Disabled,
+    Enabled,
+    /// Permanently disabled.
+    Renounced,
+}
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingForceTransfer {
+    pub token_id: TokenId,
+    /// Holder when the transfer was proposed.
+    pub owner_id: AccountId,
+    pub receiver_id: AccountId,
+    pub reason: String,
+    pub executable_at: U64,
+}
+
+impl Contract {
+    /// Panics while a forced transfer of `token_id` is pending.
+    pub(crate) fn assert_no_pending_force_transfer(&self, token_id: &TokenId) {
+        require!(
+            !self.pending_force_transfers.contains_key(token_id),
+            "Token has a pending forced transfer"
+        );
+    }
+}
+
+#[near]
+impl Contract {
+    pub fn enable_clawback(&mut self) {
+        self.assert_owner();
+        require!(
+            self.clawback_status != ClawbackStatus::Renounced,
+            "Clawback was renounced"
+        );
+        self.clawback_status = ClawbackStatus::Enabled;
+    }
+
+    /// Permanently gives up clawback and drops pending forced transfers.
+    pub fn renounce_clawback(&mut self) {
+        self.assert_owner();
+        self.clawback_status = ClawbackStatus::Renounced;
+        self.pending_force_transfers.clear();
+    }
+
+    pub fn clawback_status(&self) -> ClawbackStatus {
+        self.clawback_status
+    }
+
+    /// Proposes to move `token_id` to `receiver_id` once `CLAWBACK_DELAY` has passed, replacing
+    /// any pending proposal for the token, and freezes the token until then. The storage is paid
+    /// from the attached deposit.
+    #[payable]
+    pub fn propose_force_transfer(
+        &mut self,
+        token_id: TokenId,
+        receiver_id: AccountId,
+        reason: String,
+    ) -> PendingForceTransfer {
+        self.assert_owner();
+        require!(
+            self.clawback_status == ClawbackStatus::Enabled,
+            "Clawback is not enabled"
+        );
+        let owner_id = self.owner_of(&token_id);
+        require!(
+            owner_id != env::current_account_id(),
+            "Token is held by the contract"
+        );
+
+        let initial_storage_usage = env::storage_usage();
+        let pending = PendingForceTransfer {
+            token_id: token_id.clone(),
+            owner_id,
+            receiver_id,
+            reason,
+            executable_at: env::block_timestamp().saturating_add(CLAWBACK_DELAY).into(),
+        };
+        self.pending_force_transfers.insert(&token_id, &pending);
+        refund_deposit(env::storage_usage().saturating_sub(initial_storage_usage));
+
+        ContractEvent::NftForceTransferProposed(&[NftForceTransferProposed {
+            token_id: &pending.token_id,
+            owner_id: &pending.owner_id,
+            receiver_id: &pending.receiver_id,
+            reason: &pending.reason,
+            executable_at: pending.executable_at,
+        }])
+        .emit();
+        pending
+    }
+
+    /// Drops the forced transfer proposed for `token_id`, unfreezing the token.
+    pub fn cancel_force_transfer(&mut self, token_id: TokenId) {
+        self.assert_owner();
+        require!(
+            self.pending_force_transfers.remove(&token_id).is_some(),
+            "No pending forced transfer for this token"
+        );
+    }
+
+    /// Executes the forced transfer proposed for `token_id`, which must match `receiver_id` and
+    /// `reason`. Storage for the receiver is paid from the attached deposit.
+    #[payable]
+    pub fn nft_force_transfer(
+        &mut self,
+        token_id: TokenId,
+        receiver_id: AccountId,
+        reason: String,
+    ) {
+        self.assert_owner();
+        require!(
+            self.clawback_status == ClawbackStatus::Enabled,
+            "Clawback is not enabled"
+        );
+        let pending = self
+            .pending_force_transfers
+            .get(&token_id)
+            .unwrap_or_else(|| env::panic_str("No pending forced transfer for this token"));
+        require!(
+            pending.receiver_id == receiver_id && pending.reason == reason,
+            "Forced transfer does not match the proposal"
+        );
+        require!(
+            env::block_timestamp() >= pending.executable_at.0,
+            "Forced transfer is still time-locked"
+        );
+        require!(
+            self.owner_of(&token_id) == pending.owner_id,
+            "Token changed hands since the proposal"
+        );
+        self.assert_not_staked(&token_id);
+        self.assert_not_attached(&token_id);
+
+        let initial_storage_usage = env::storage_usage();
+        self.pending_force_transfers.remove(&token_id);
+        self.internal_move(
+            &token_id,
+            &pending.owner_id,
+            &receiver_id,
+            Some(reason.clone()),
+        );
+        refund_deposit(env::storage_usage().saturating_sub(initial_storage_usage));
+
+        ContractEvent::NftForceTransfer(&[NftForceTransfer {
+            token_id: &token_id,
+            old_owner_id: &pending.owner_id,
+            new_owner_id: &receiver_id,
+            admin_id: &env::predecessor_account_id(),
+            reason: &reason,
+        }])
+        .emit();
+    }
+
+    pub fn pending_force_transfer(&self, token_id: TokenId) -> Option<PendingForceTransfer> {
+        self.pending_force_transfers.get(&token_id)
+    }
+
+    pub fn pending_force_transfers(
+        &self,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+    ) -> Vec<PendingForceTransfer> {
+        let start = u128::from(from_index.unwrap_or(U128(0)));
+        self.pending_force_transfers
+            .iter()
+            .skip(start as usize)
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .map(|(_, pending)| pending)
+            .collect()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, NearToken};
+
+    use super::*;
+
+    const ONE_YOCTONEAR: NearToken = NearToken::from_yoctonear(1);
+
+    fn call(context: &mut VMContextBuilder, predecessor_id: AccountId, deposit: NearToken) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(deposit)
+            .predecessor_account_id(predecessor_id)
+            .build());
+    }
+
+    /// Token "0" of accounts(1), with clawback to accounts(2) proposed at time 0.
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        contract.enable_clawback();
+        call(context, accounts(0), MINT_STORAGE_COST);
+        contract.nft_mint("0".to_string(), accounts(1), sample_token_metadata(), None);
+        call(context, accounts(0), MINT_STORAGE_COST);
+        contract.propose_force_transfer("0".to_string(), accounts(2), "phished".to_string());
+        contract
+    }
+
+    fn force_transfer(context: &mut VMContextBuilder, contract: &mut Contract, now: u64) {
+        context.block_timestamp(now);
+        call(context, accounts(0), MINT_STORAGE_COST);
+        contract.nft_force_transfer("0".to_string(), accounts(2), "phished".to_string());
+    }
+
+    #[test]
+    fn test_force_transfer_after_delay() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        assert_eq!(
+            contract.pending_force_transfers(None, None),
+            vec![PendingForceTransfer {
+                token_id: "0".to_string(),
+                owner_id: accounts(1),
+                receiver_id: accounts(2),
+                reason: "phished".to_string(),
+                executable_at: U64(CLAWBACK_DELAY),
+            }]
+        );
+
+        force_transfer(&mut context, &mut contract, CLAWBACK_DELAY);
+        assert_eq!(
+            contract.nft_token("0".to_string()).unwrap().owner_id,
+            accounts(2)
+        );
+        assert!(contract.pending_force_transfer("0".to_string()).is_none());
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.contains("\"event\":\"nft_force_transfer\"")));
+    }
+
+    #[test]
+    #[should_panic(expected = "Forced transfer is still time-locked")]
+    fn test_force_transfer_before_delay() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        force_transfer(&mut context, &mut contract, CLAWBACK_DELAY - 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Token has a pending forced transfer")]
+    fn test_token_is_frozen_while_pending() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_transfer(accounts(3), "0".to_string(), None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Token has a pending forced transfer")]
+    fn test_token_cannot_be_burnt_while_pending() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_burn("0".to_string());
+    }
+
+    #[test]
+    fn test_cancel_unfreezes_token() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        contract.cancel_force_transfer("0".to_string());
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_transfer(accounts(3), "0".to_string(), None, None);
+        assert_eq!(
+            contract.nft_token("0".to_string()).unwrap().owner_id,
+            accounts(3)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Clawback is not enabled")]
+    fn test_renounce_is_permanent() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        contract.renounce_clawback();
+        assert_eq!(contract.clawback_status(), ClawbackStatus::Renounced);
+        assert!(contract.pending_force_transfers(None, None).is_empty());
+        force_transfer(&mut context, &mut contract, CLAWBACK_DELAY);
+    }
+
+    #[test]
+    #[should_panic(expected = "Clawback was renounced")]
+    fn test_cannot_enable_after_renounce() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        contract.renounce_clawback();
+        contract.enable_clawback();
+    }
+}
@@ -0,0 +1,275 @@
+/*!
+Composable tokens.
+
+A token can be attached to another token of this contract, e.g. equipment to a character. While
+attached, the child is held by the contract account itself and belongs to whoever owns the root
+of its tree (`nft_root_owner`), so transferring a parent moves all its descendants along without
+touching them. Attached children cannot be transferred, approved for transfer or burnt until they
+are detached by the root owner.
+*/
+use crate::storage::storage_cost;
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+use near_contract_standards::non_fungible_token::{refund_deposit, Token, TokenId};
+use near_sdk::{assert_one_yocto, env, near, require, AccountId, Promise, StorageUsage};
+
+impl Contract {
+    pub(crate) fn assert_not_attached(&self, token_id: &TokenId) {
+        require!(
+            !self.parent_of.contains_key(token_id),
+            "Token is attached to a parent"
+        );
+    }
+
+    pub(crate) fn assert_no_children(&self, token_id: &TokenId) {
+        require!(
+            !self.children_of.contains_key(token_id),
+            "Token has attached children"
+        );
+    }
+
+    fn root_owner(&self, token_id: &TokenId) -> AccountId {
+        let mut token_id = token_id.clone();
+        while let Some(parent_id) = self.parent_of.get(&token_id) {
+            token_id = parent_id;
+        }
+        self.owner_of(&token_id)
+    }
+
+    fn is_descendant(&self, token_id: &TokenId, ancestor_id: &TokenId) -> bool {
+        let mut token_id = token_id.clone();
+        while let Some(parent_id) = self.parent_of.get(&token_id) {
+            if &parent_id == ancestor_id {
+                return true;
+            }
+            token_id = parent_id;
+        }
+        false
+    }
+
+    /// Charges storage added since `initial_storage_usage` to the attached deposit, or sends the
+    /// released storage back to the caller along with the deposit.
+    fn settle_attached_deposit(&self, initial_storage_usage: StorageUsage) {
+        let storage_usage = env::storage_usage();
+        if storage_usage >= initial_storage_usage {
+            refund_deposit(storage_usage - initial_storage_usage);
+        } else {
+            let refund = storage_cost(initial_storage_usage - storage_usage)
+                .saturating_add(env::attached_deposit());
+            Promise::new(env::predecessor_account_id()).transfer(refund);
+        }
+    }
+}
+
+#[near]
+impl Contract {
+    /// Attaches `child_id` to `parent_id`. The caller must own `child_id` directly and be the
+    /// root owner of `parent_id`. The storage is paid from the attached deposit.
+    #[payable]
+    pub fn nft_attach(&mut self, child_id: TokenId, parent_id: TokenId) {
+        let owner_id = env::predecessor_account_id();
+        require!(
+            self.owner_of(&child_id) == owner_id,
+            "Predecessor must be token owner."
+        );
+        require!(
+            self.root_owner(&parent_id) == owner_id,
+            "Predecessor must own the parent token"
+        );
+        require!(
+            child_id != parent_id && !self.is_descendant(&parent_id, &child_id),
+            "Cannot attach a token to itself or its descendants"
+        );
+        self.assert_transferable(&child_id);
+
+        let initial_storage_usage = env::storage_usage();
+        self.internal_move(&child_id, &owner_id, &env::current_account_id(), None);
+        self.parent_of.insert(&child_id, &parent_id);
+        let mut children = self.children_of.get(&parent_id).unwrap_or_default();
+        children.push(child_id);
+        self.children_of.insert(&parent_id, &children);
+        self.settle_attached_deposit(initial_storage_usage);
+    }
+
+    /// Detaches `child_id`, along with its own children, and sends it to `receiver_id`. Only the
+    /// root owner of `child_id` can call it, with exactly 1 yoctoNEAR attached. Added storage is
+    /// paid from the caller's prepaid storage balance and released storage is refunded.
+    #[payable]
+    pub fn nft_detach(&mut self, child_id: TokenId, receiver_id: AccountId) {
+        assert_one_yocto();
+        let parent_id = self
+            .parent_of
+            .get(&child_id)
+            .unwrap_or_else(|| env::panic_str("Token is not attached"));
+        require!(
+            self.root_owner(&child_id) == env::predecessor_account_id(),
+            "Predecessor must own the parent token"
+        );
+
+        let initial_storage_usage = env::storage_usage();
+        self.parent_of.remove(&child_id);
+        let mut children = self.children_of.get(&parent_id).unwrap_or_default();
+        children.retain(|id| id != &child_id);
+        if children.is_empty() {
+            self.children_of.remove(&parent_id);
+        } else {
+            self.children_of.insert(&parent_id, &children);
+        }
+        self.internal_move(&child_id, &env::current_account_id(), &receiver_id, None);
+        if env::storage_usage() > initial_storage_usage {
+            self.settle_prepaid_storage(&env::predecessor_account_id(), initial_storage_usage);
+        } else {
+            self.settle_attached_deposit(initial_storage_usage);
+        }
+    }
+
+    /// Tokens directly attached to `token_id`, in the order they were attached.
+    pub fn nft_children(&self, token_id: TokenId) -> Vec<Token> {
+        self.children_of
+            .get(&token_id)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|child_id| self.tokens.nft_token(child_id))
+            .map(|token| self.token_view(token))
+            .collect()
+    }
+
+    pub fn nft_parent(&self, token_id: TokenId) -> Option<TokenId> {
+        self.parent_of.get(&token_id)
+    }
+
+    /// Owner of the top-most ancestor of `token_id`, or of `token_id` itself if not attached.
+    pub fn nft_root_owner(&self, token_id: TokenId) -> AccountId {
+        self.root_owner(&token_id)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, NearToken};
+
+    use super::*;
+
+    const ATTACH_STORAGE_COST: NearToken = NearToken::from_millinear(10);
+    const ONE_YOCTONEAR: NearToken = NearToken::from_yoctonear(1);
+
+    fn call(context: &mut VMContextBuilder, predecessor_id: AccountId, deposit: NearToken) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(deposit)
+            .predecessor_account_id(predecessor_id)
+            .build());
+    }
+
+    /// Character "hero" holding "sword", which holds "gem", all owned by accounts(1).
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        for token_id in ["hero", "sword", "gem"] {
+            call(context, accounts(0), MINT_STORAGE_COST);
+            contract.nft_mint(
+                token_id.to_string(),
+                accounts(1),
+                sample_token_metadata(),
+                None,
+            );
+        }
+        attach(context, &mut contract, "sword", "hero");
+        attach(context, &mut contract, "gem", "sword");
+        contract
+    }
+
+    fn attach(context: &mut VMContextBuilder, contract: &mut Contract, child: &str, parent: &str) {
+        call(context, accounts(1), ATTACH_STORAGE_COST);
+        contract.nft_attach(child.to_string(), parent.to_string());
+    }
+
+    fn token_ids(tokens: Vec<Token>) -> Vec<TokenId> {
+        tokens.into_iter().map(|token| token.token_id).collect()
+    }
+
+    #[test]
+    fn test_children_follow_parent() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        assert_eq!(
+            token_ids(contract.nft_children("hero".to_string())),
+            vec!["sword"]
+        );
+        assert_eq!(
+            contract.nft_parent("gem".to_string()),
+            Some("sword".to_string())
+        );
+        assert_eq!(contract.nft_root_owner("gem".to_string()), accounts(1));
+        assert_eq!(
+            contract.nft_token("gem".to_string()).unwrap().owner_id,
+            accounts(0)
+        );
+
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_transfer(accounts(2), "hero".to_string(), None, None);
+        assert_eq!(contract.nft_root_owner("gem".to_string()), accounts(2));
+
+        // The new owner can take the sword, gem included, out of the bundle.
+        call(&mut context, accounts(2), ATTACH_STORAGE_COST);
+        contract.nft_storage_deposit(None);
+        call(&mut context, accounts(2), ONE_YOCTONEAR);
+        contract.nft_detach("sword".to_string(), accounts(3));
+        assert!(contract.nft_children("hero".to_string()).is_empty());
+        assert_eq!(
+            contract.nft_token("sword".to_string()).unwrap().owner_id,
+            accounts(3)
+        );
+        assert_eq!(contract.nft_root_owner("gem".to_string()), accounts(3));
+    }
+
+    #[test]
+    #[should_panic(expected = "Token is attached to a parent")]
+    fn test_attached_child_cannot_be_transferred() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_transfer(accounts(2), "sword".to_string(), None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot attach a token to itself or its descendants")]
+    fn test_attach_rejects_cycles() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_detach("sword".to_string(), accounts(1));
+        // "gem" is still attached to "sword".
+        attach(&mut context, &mut contract, "sword", "gem");
+    }
+
+    #[test]
+    #[should_panic(expected = "Predecessor must own the parent token")]
+    fn test_detach_by_non_root_owner() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(2), ONE_YOCTONEAR);
+        contract.nft_detach("gem".to_string(), accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_detach_without_deposit() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), NearToken::from_yoctonear(0));
+        contract.nft_detach("sword".to_string(), accounts(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Token has attached children")]
+    fn test_burn_parent_with_children() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_burn("hero".to_string());
+    }
+}
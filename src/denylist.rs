@@ -0,0 +1,188 @@
+/*!
+Account denylist for compliance.
+
+Accounts with the `Compliance` role can deny accounts. A denied account cannot receive tokens,
+whether minted or transferred, cannot send or approve tokens, and cannot be approved. Tokens it
+already holds are frozen: they stay with it but cannot be transferred, approved or burnt until
+the account is removed from the denylist.
+*/
+use crate::events::{ContractEvent, DenylistUpdate};
+use crate::roles::Role;
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::TokenId;
+use near_sdk::{env, near, require, AccountId};
+
+impl Contract {
+    pub(crate) fn assert_not_denied(&self, account_id: &AccountId) {
+        if self.denylist.contains(account_id) {
+            env::panic_str(&format!("{} is denied", account_id));
+        }
+    }
+
+    /// Panics if `token_id` is held by a denied account.
+    pub(crate) fn assert_not_frozen(&self, token_id: &TokenId) {
+        require!(
+            !self.denylist.contains(&self.owner_of(token_id)),
+            "Token is frozen"
+        );
+    }
+}
+
+#[near]
+impl Contract {
+    /// Adds `account_id` to the denylist, freezing the tokens it holds.
+    pub fn deny_account(&mut self, account_id: AccountId, reason: Option<String>) {
+        self.assert_role(Role::Compliance);
+        if self.denylist.insert(&account_id) {
+            ContractEvent::AccountDenied(&[DenylistUpdate {
+                account_id: &account_id,
+                admin_id: &env::predecessor_account_id(),
+                reason: reason.as_deref(),
+            }])
+            .emit();
+        }
+    }
+
+    /// Removes `account_id` from the denylist, unfreezing the tokens it holds.
+    pub fn undeny_account(&mut self, account_id: AccountId, reason: Option<String>) {
+        self.assert_role(Role::Compliance);
+        if self.denylist.remove(&account_id) {
+            ContractEvent::AccountUndenied(&[DenylistUpdate {
+                account_id: &account_id,
+                admin_id: &env::predecessor_account_id(),
+                reason: reason.as_deref(),
+            }])
+            .emit();
+        }
+    }
+
+    pub fn is_denied(&self, account_id: AccountId) -> bool {
+        self.denylist.contains(&account_id)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_contract_standards::non_fungible_token::approval::NonFungibleTokenApproval;
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, NearToken};
+
+    use super::*;
+
+    const ONE_YOCTONEAR: NearToken = NearToken::from_yoctonear(1);
+
+    fn call(context: &mut VMContextBuilder, predecessor_id: AccountId, deposit: NearToken) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(deposit)
+            .predecessor_account_id(predecessor_id)
+            .build());
+    }
+
+    /// Token "0" of accounts(1), with accounts(4) as compliance officer who denied accounts(3).
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        contract.grant_role(Role::Compliance, accounts(4));
+        call(context, accounts(0), MINT_STORAGE_COST);
+        contract.nft_mint("0".to_string(), accounts(1), sample_token_metadata(), None);
+        call(context, accounts(4), NearToken::from_yoctonear(0));
+        contract.deny_account(accounts(3), Some("sanctioned".to_string()));
+        contract
+    }
+
+    #[test]
+    fn test_deny_emits_event() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        assert!(contract.is_denied(accounts(3)));
+        assert_eq!(
+            get_logs(),
+            vec![format!(
+                r#"EVENT_JSON:{{"standard":"example_nft","version":"1.0.0","event":"account_denied","data":[{{"account_id":"{}","admin_id":"{}","reason":"sanctioned"}}]}}"#,
+                accounts(3),
+                accounts(4)
+            )]
+        );
+
+        call(&mut context, accounts(4), NearToken::from_yoctonear(0));
+        contract.undeny_account(accounts(3), None);
+        assert!(!contract.is_denied(accounts(3)));
+        assert!(get_logs()[0].contains("\"event\":\"account_undenied\""));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unauthorized")]
+    fn test_deny_without_role() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), NearToken::from_yoctonear(0));
+        contract.deny_account(accounts(2), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "is denied")]
+    fn test_mint_to_denied_account() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(0), MINT_STORAGE_COST);
+        contract.nft_mint("1".to_string(), accounts(3), sample_token_metadata(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "is denied")]
+    fn test_transfer_to_denied_account() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_transfer(accounts(3), "0".to_string(), None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "is denied")]
+    fn test_approve_denied_account() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), MINT_STORAGE_COST);
+        contract.nft_approve("0".to_string(), accounts(3), None);
+    }
+
+    fn deny_holder(context: &mut VMContextBuilder, contract: &mut Contract) {
+        call(context, accounts(4), NearToken::from_yoctonear(0));
+        contract.deny_account(accounts(1), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Token is frozen")]
+    fn test_tokens_of_denied_account_are_frozen() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        deny_holder(&mut context, &mut contract);
+        // Frozen, not seized.
+        assert_eq!(
+            contract.nft_token("0".to_string()).unwrap().owner_id,
+            accounts(1)
+        );
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_burn("0".to_string());
+    }
+
+    #[test]
+    fn test_undeny_unfreezes_tokens() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        deny_holder(&mut context, &mut contract);
+        call(&mut context, accounts(4), NearToken::from_yoctonear(0));
+        contract.undeny_account(accounts(1), None);
+
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_transfer(accounts(2), "0".to_string(), None, None);
+        assert_eq!(
+            contract.nft_token("0".to_string()).unwrap().owner_id,
+            accounts(2)
+        );
+    }
+}
@@ -0,0 +1,102 @@
+/*!
+Events of the contract's own extensions, logged in the NEP-297 format next to the standard NEP-171
+ones emitted by `near-contract-standards`:
+
+```text
+EVENT_JSON:{"standard":"example_nft","version":"1.0.0","event":"nft_redeem","data":[...]}
+```
+*/
+use near_contract_standards::non_fungible_token::TokenId;
+use near_sdk::json_types::U64;
+use near_sdk::serde::Serialize;
+use near_sdk::{env, serde_json, AccountId};
+
+const STANDARD: &str = "example_nft";
+const VERSION: &str = "1.0.0";
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+#[must_use = "don't forget to `.emit()` this event"]
+pub(crate) enum ContractEvent<'a> {
+    NftRedeem(&'a [NftRedeem<'a>]),
+    AccountDenied(&'a [DenylistUpdate<'a>]),
+    AccountUndenied(&'a [DenylistUpdate<'a>]),
+    NftForceTransferProposed(&'a [NftForceTransferProposed<'a>]),
+    NftForceTransfer(&'a [NftForceTransfer<'a>]),
+    NftMetadataUpdate(&'a [NftMetadataUpdate<'a>]),
+    NftStatsUpdate(&'a [NftStatsUpdate<'a>]),
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct EventLog<'a> {
+    standard: &'static str,
+    version: &'static str,
+    #[serde(flatten)]
+    event: &'a ContractEvent<'a>,
+}
+
+impl ContractEvent<'_> {
+    pub(crate) fn emit(self) {
+        let log = EventLog {
+            standard: STANDARD,
+            version: VERSION,
+            event: &self,
+        };
+        let json = serde_json::to_string(&log).unwrap_or_else(|_| env::abort());
+        env::log_str(&format!("EVENT_JSON:{}", json));
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub(crate) struct NftRedeem<'a> {
+    pub owner_id: &'a AccountId,
+    pub token_id: &'a TokenId,
+    pub verifier_id: &'a AccountId,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub(crate) struct DenylistUpdate<'a> {
+    pub account_id: &'a AccountId,
+    pub admin_id: &'a AccountId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'a str>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub(crate) struct NftForceTransferProposed<'a> {
+    pub token_id: &'a TokenId,
+    pub owner_id: &'a AccountId,
+    pub receiver_id: &'a AccountId,
+    pub reason: &'a str,
+    pub executable_at: U64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub(crate) struct NftForceTransfer<'a> {
+    pub token_id: &'a TokenId,
+    pub old_owner_id: &'a AccountId,
+    pub new_owner_id: &'a AccountId,
+    pub admin_id: &'a AccountId,
+    pub reason: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub(crate) struct NftMetadataUpdate<'a> {
+    pub token_ids: &'a [&'a TokenId],
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub(crate) struct NftStatsUpdate<'a> {
+    pub token_id: &'a TokenId,
+    pub game_server_id: &'a AccountId,
+    pub level: u32,
+    pub xp: U64,
+}
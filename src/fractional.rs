@@ -0,0 +1,434 @@
+/*!
+Fractional ownership through NEP-141 shares.
+
+`fractionalize` locks a token in the contract account and mints `total_shares` shares to its
+owner. The shares are a standard NEP-141 token served by this contract (`ft_transfer`,
+`ft_balance_of`, ...), with NEP-145 storage management for share holders.
+
+Anyone can `buyout` the token by paying the reserve price set at fractionalization: the token
+goes to the buyer and the proceeds stay in the contract, where share holders `redeem_shares` for
+their pro-rata part. Since a contract serves a single NEP-141 token, only one token can be
+fractionalized at a time; another one can be fractionalized once all the shares of the previous
+one were redeemed.
+*/
+use crate::storage::storage_cost;
+use crate::{Contract, ContractExt};
+use near_contract_standards::fungible_token::core::FungibleTokenCore;
+use near_contract_standards::fungible_token::events::{FtBurn, FtMint};
+use near_contract_standards::fungible_token::metadata::{
+    FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
+};
+use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
+use near_contract_standards::non_fungible_token::{refund_deposit, TokenId};
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
+use near_sdk::json_types::U128;
+use near_sdk::{
+    assert_one_yocto, env, near, require, AccountId, NearToken, Promise, PromiseOrValue,
+};
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fractionalization {
+    pub token_id: TokenId,
+    pub total_shares: U128,
+    pub reserve_price: NearToken,
+    pub buyer_id: Option<AccountId>,
+    /// Proceeds of the buyout not redeemed yet.
+    pub proceeds: NearToken,
+}
+
+impl Contract {
+    fn fractionalization_or_panic(&self, token_id: &TokenId) -> Fractionalization {
+        self.fractionalization
+            .clone()
+            .filter(|fractionalization| &fractionalization.token_id == token_id)
+            .unwrap_or_else(|| env::panic_str("Token is not fractionalized"))
+    }
+
+    /// Panics while `token_id` is locked for its share holders. The contract account holds it,
+    /// so only `buyout` may move it, not even a call from the contract account itself.
+    pub(crate) fn assert_not_fractionalized(&self, token_id: &TokenId) {
+        require!(
+            !self
+                .fractionalization
+                .as_ref()
+                .is_some_and(|fractionalization| {
+                    &fractionalization.token_id == token_id && fractionalization.buyer_id.is_none()
+                }),
+            "Token is fractionalized"
+        );
+    }
+}
+
+#[near]
+impl Contract {
+    /// Locks `token_id` and mints `total_shares` shares to its owner, who can then sell the
+    /// token as a whole to anyone paying `reserve_price`. The storage is paid from the attached
+    /// deposit.
+    #[payable]
+    pub fn fractionalize(
+        &mut self,
+        token_id: TokenId,
+        total_shares: U128,
+        reserve_price: NearToken,
+    ) -> Fractionalization {
+        let owner_id = env::predecessor_account_id();
+        require!(
+            self.owner_of(&token_id) == owner_id,
+            "Predecessor must be token owner."
+        );
+        require!(
+            self.fractionalization.is_none(),
+            "Another token is already fractionalized"
+        );
+        require!(
+            total_shares.0 > 0 && total_shares.0 <= u64::MAX.into(),
+            "Total shares must be between 1 and 2^64 - 1"
+        );
+        self.assert_transferable(&token_id);
+
+        let initial_storage_usage = env::storage_usage();
+        self.internal_move(&token_id, &owner_id, &env::current_account_id(), None);
+        if !self.shares.accounts.contains_key(&owner_id) {
+            self.shares.internal_register_account(&owner_id);
+        }
+        self.shares.internal_deposit(&owner_id, total_shares.0);
+        let fractionalization = Fractionalization {
+            token_id,
+            total_shares,
+            reserve_price,
+            buyer_id: None,
+            proceeds: NearToken::from_yoctonear(0),
+        };
+        self.fractionalization = Some(fractionalization.clone());
+        refund_deposit(env::storage_usage().saturating_sub(initial_storage_usage));
+
+        FtMint {
+            owner_id: &owner_id,
+            amount: total_shares,
+            memo: Some("fractionalize"),
+        }
+        .emit();
+        fractionalization
+    }
+
+    /// Buys the fractionalized `token_id` at its reserve price. The attached deposit must also
+    /// cover the storage the buyer's ownership takes; the rest is refunded.
+    #[payable]
+    pub fn buyout(&mut self, token_id: TokenId) {
+        let mut fractionalization = self.fractionalization_or_panic(&token_id);
+        require!(
+            fractionalization.buyer_id.is_none(),
+            "Token was already bought out"
+        );
+        let buyer_id = env::predecessor_account_id();
+
+        let initial_storage_usage = env::storage_usage();
+        fractionalization.buyer_id = Some(buyer_id.clone());
+        fractionalization.proceeds = fractionalization.reserve_price;
+        self.fractionalization = Some(fractionalization.clone());
+        self.internal_move(&token_id, &env::current_account_id(), &buyer_id, None);
+        let required = fractionalization.reserve_price.saturating_add(storage_cost(
+            env::storage_usage().saturating_sub(initial_storage_usage),
+        ));
+        let deposit = env::attached_deposit();
+        require!(
+            deposit >= required,
+            format!("Must attach {} to buy out", required.exact_amount_display())
+        );
+        let refund = deposit.saturating_sub(required);
+        if !refund.is_zero() {
+            Promise::new(buyer_id).transfer(refund);
+        }
+    }
+
+    /// Burns all the shares of the caller after a buyout and pays their part of the proceeds.
+    /// Returns the amount paid.
+    #[payable]
+    pub fn redeem_shares(&mut self) -> NearToken {
+        assert_one_yocto();
+        let mut fractionalization = self
+            .fractionalization
+            .clone()
+            .unwrap_or_else(|| env::panic_str("No token is fractionalized"));
+        require!(
+            fractionalization.buyer_id.is_some(),
+            "Token was not bought out yet"
+        );
+        let account_id = env::predecessor_account_id();
+        let shares = self.shares.ft_balance_of(account_id.clone()).0;
+        require!(shares > 0, "No shares to redeem");
+
+        // Paying from what is left keeps the rounding dust for the last holder. Splitting the
+        // division keeps the products below `u128::MAX` since the supply fits in a `u64`.
+        let proceeds = fractionalization.proceeds.as_yoctonear();
+        let supply = self.shares.total_supply;
+        let payout = NearToken::from_yoctonear(
+            proceeds / supply * shares + proceeds % supply * shares / supply,
+        );
+        self.shares.internal_withdraw(&account_id, shares);
+        fractionalization.proceeds = fractionalization.proceeds.saturating_sub(payout);
+        self.fractionalization = if self.shares.total_supply == 0 {
+            None
+        } else {
+            Some(fractionalization)
+        };
+        FtBurn {
+            owner_id: &account_id,
+            amount: U128(shares),
+            memo: Some("redeem_shares"),
+        }
+        .emit();
+        Promise::new(account_id).transfer(payout.saturating_add(NearToken::from_yoctonear(1)));
+        payout
+    }
+
+    pub fn fractionalization(&self) -> Option<Fractionalization> {
+        self.fractionalization.clone()
+    }
+}
+
+#[near]
+impl FungibleTokenCore for Contract {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        self.shares.ft_transfer(receiver_id, amount, memo)
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        self.shares.ft_transfer_call(receiver_id, amount, memo, msg)
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.shares.ft_total_supply()
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.shares.ft_balance_of(account_id)
+    }
+}
+
+#[near]
+impl FungibleTokenResolver for Contract {
+    #[private]
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        let (used_amount, burned_amount) =
+            self.shares
+                .internal_ft_resolve_transfer(&sender_id, receiver_id, amount);
+        if burned_amount > 0 {
+            FtBurn {
+                owner_id: &sender_id,
+                amount: U128(burned_amount),
+                memo: None,
+            }
+            .emit();
+        }
+        used_amount.into()
+    }
+}
+
+#[near]
+impl StorageManagement for Contract {
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        self.shares.storage_deposit(account_id, registration_only)
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<NearToken>) -> StorageBalance {
+        self.shares.storage_withdraw(amount)
+    }
+
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        if let Some((account_id, balance)) = self.shares.internal_storage_unregister(force) {
+            if balance > 0 {
+                FtBurn {
+                    owner_id: &account_id,
+                    amount: U128(balance),
+                    memo: Some("storage_unregister"),
+                }
+                .emit();
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        self.shares.storage_balance_bounds()
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.shares.storage_balance_of(account_id)
+    }
+}
+
+#[near]
+impl FungibleTokenMetadataProvider for Contract {
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        let metadata = self.metadata.get().unwrap();
+        FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: format!("{} shares", metadata.name),
+            symbol: format!("{}-SHARE", metadata.symbol),
+            icon: metadata.icon,
+            reference: None,
+            reference_hash: None,
+            decimals: 0,
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+
+    use near_sdk::test_utils::{accounts, get_created_receipts, get_logs, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    const RESERVE_PRICE: NearToken = NearToken::from_near(10);
+    const ONE_YOCTONEAR: NearToken = NearToken::from_yoctonear(1);
+
+    fn call(context: &mut VMContextBuilder, predecessor_id: AccountId, deposit: NearToken) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(deposit)
+            .predecessor_account_id(predecessor_id)
+            .build());
+    }
+
+    /// Token "0" of accounts(1) split into 100 shares, 30 of which were sent to accounts(2).
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        call(context, accounts(0), MINT_STORAGE_COST);
+        contract.nft_mint("0".to_string(), accounts(1), sample_token_metadata(), None);
+        call(context, accounts(1), MINT_STORAGE_COST);
+        contract.fractionalize("0".to_string(), U128(100), RESERVE_PRICE);
+
+        call(context, accounts(2), MINT_STORAGE_COST);
+        contract.storage_deposit(None, None);
+        call(context, accounts(1), ONE_YOCTONEAR);
+        contract.ft_transfer(accounts(2), U128(30), None);
+        contract
+    }
+
+    fn buyout(context: &mut VMContextBuilder, contract: &mut Contract) {
+        call(
+            context,
+            accounts(3),
+            RESERVE_PRICE.saturating_add(MINT_STORAGE_COST),
+        );
+        contract.buyout("0".to_string());
+    }
+
+    #[test]
+    fn test_fractionalize_locks_token_and_mints_shares() {
+        let mut context = get_context(accounts(0));
+        let contract = setup(&mut context);
+        assert_eq!(
+            contract.nft_token("0".to_string()).unwrap().owner_id,
+            accounts(0)
+        );
+        assert_eq!(contract.ft_total_supply(), U128(100));
+        assert_eq!(contract.ft_balance_of(accounts(1)), U128(70));
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(30));
+        assert_eq!(contract.ft_metadata().decimals, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Token is fractionalized")]
+    fn test_locked_token_cannot_be_transferred() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_transfer(accounts(2), "0".to_string(), None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Token is fractionalized")]
+    fn test_locked_token_cannot_be_moved_by_contract_account() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(0), ONE_YOCTONEAR);
+        contract.nft_transfer(accounts(2), "0".to_string(), None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Token is fractionalized")]
+    fn test_locked_token_cannot_be_burnt_by_contract_account() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(0), ONE_YOCTONEAR);
+        contract.nft_burn("0".to_string());
+    }
+
+    #[test]
+    fn test_buyout_and_redeem_shares() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        buyout(&mut context, &mut contract);
+        assert_eq!(
+            contract.nft_token("0".to_string()).unwrap().owner_id,
+            accounts(3)
+        );
+        assert_eq!(
+            contract.fractionalization().unwrap().proceeds,
+            RESERVE_PRICE
+        );
+
+        call(&mut context, accounts(2), ONE_YOCTONEAR);
+        assert_eq!(contract.redeem_shares(), NearToken::from_near(3));
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(0));
+        assert!(get_logs()[0].contains("\"event\":\"ft_burn\""));
+        assert!(get_created_receipts()
+            .iter()
+            .any(|receipt| receipt.receiver_id == accounts(2)));
+
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        assert_eq!(contract.redeem_shares(), NearToken::from_near(7));
+        assert_eq!(contract.ft_total_supply(), U128(0));
+        assert!(contract.fractionalization().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Token was not bought out yet")]
+    fn test_redeem_before_buyout() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(2), ONE_YOCTONEAR);
+        contract.redeem_shares();
+    }
+
+    #[test]
+    #[should_panic(expected = "Must attach")]
+    fn test_buyout_below_reserve_price() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(3), NearToken::from_near(9));
+        contract.buyout("0".to_string());
+    }
+}
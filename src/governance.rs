@@ -0,0 +1,594 @@
+/*!
+Holder-weighted governance.
+
+Any account holding at least `proposal_threshold` tokens can open a proposal. Opening a proposal
+takes a holder snapshot, and every vote is weighted by the number of tokens the voter held at
+that snapshot, so tokens bought after the proposal was created carry no weight. Votes are only
+accepted between `voting_starts_at` and `voting_ends_at`.
+
+Once voting ended anyone can call `finalize_proposal`, which records whether the proposal passed.
+A proposal can carry a function call action. It is only executed on finalization if the proposal
+passed and the owner approved the action beforehand with `approve_proposal_action`. Actions can
+neither call this contract, which would act as its own owner, nor a vault contract, whose tokens
+it holds. Their deposit is paid from the treasury (see `membership`): approving an action sets
+the deposit aside, and it goes back to the treasury if the action is not executed.
+
+Proposals and votes are paid for by their authors, like minted tokens. On top of its storage, a
+proposal costs a non-refundable `proposal_bond`, kept by the contract: every proposal takes a
+snapshot, which makes the next transfers of its tokens copy their owners aside.
+*/
+use crate::storage::storage_cost;
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::refund_deposit;
+use near_sdk::json_types::{Base64VecU8, U128, U64};
+use near_sdk::{env, near, require, AccountId, Gas, NearToken, Promise};
+
+/// Bond charged by `create_proposal` until the owner sets another one.
+pub(crate) const DEFAULT_PROPOSAL_BOND: NearToken = NearToken::from_millinear(100);
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Vote {
+    Yes,
+    No,
+    Abstain,
+}
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProposalStatus {
+    /// Voting has not ended or the proposal was not finalized yet.
+    Open,
+    /// More yes than no votes.
+    Passed,
+    Rejected,
+}
+
+/// Function call executed when a proposal passes, with its deposit paid from the treasury.
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProposalAction {
+    pub receiver_id: AccountId,
+    pub method_name: String,
+    pub args: Base64VecU8,
+    pub deposit: NearToken,
+    pub gas: U64,
+}
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Proposal {
+    pub id: u64,
+    pub proposer_id: AccountId,
+    pub description: String,
+    /// Snapshot used to weight the votes.
+    pub snapshot_id: u64,
+    /// Block timestamps in nanoseconds.
+    pub voting_starts_at: U64,
+    pub voting_ends_at: U64,
+    pub yes: U128,
+    pub no: U128,
+    pub abstain: U128,
+    pub status: ProposalStatus,
+    pub action: Option<ProposalAction>,
+    pub action_approved: bool,
+    pub action_executed: bool,
+}
+
+impl Contract {
+    fn proposal_or_panic(&self, proposal_id: u64) -> Proposal {
+        self.proposals
+            .get(proposal_id)
+            .unwrap_or_else(|| env::panic_str("Proposal not found"))
+    }
+
+    /// Whether `action` calls an account the contract holds no privileges or tokens with.
+    fn is_external_action(&self, action: &ProposalAction) -> bool {
+        action.receiver_id != env::current_account_id()
+            && !self.vault_contracts.contains(&action.receiver_id)
+    }
+
+    fn assert_external_action(&self, action: &ProposalAction) {
+        require!(
+            self.is_external_action(action),
+            "Proposal actions cannot call this contract or a vault contract"
+        );
+    }
+}
+
+#[near]
+impl Contract {
+    /// Sets the number of tokens an account must hold to create a proposal. Defaults to 1.
+    pub fn set_proposal_threshold(&mut self, threshold: u64) {
+        self.assert_owner();
+        require!(threshold > 0, "Threshold must be at least 1");
+        self.proposal_threshold = threshold;
+    }
+
+    pub fn proposal_threshold(&self) -> u64 {
+        self.proposal_threshold
+    }
+
+    /// Sets the bond charged for every new proposal.
+    pub fn set_proposal_bond(&mut self, bond: NearToken) {
+        self.assert_owner();
+        self.proposal_bond = bond;
+    }
+
+    pub fn proposal_bond(&self) -> NearToken {
+        self.proposal_bond
+    }
+
+    /// Creates a proposal voted on by the current holders and returns its id. The attached
+    /// deposit must cover `proposal_bond` and the storage; the rest is refunded.
+    #[payable]
+    pub fn create_proposal(
+        &mut self,
+        description: String,
+        voting_starts_at: U64,
+        voting_ends_at: U64,
+        action: Option<ProposalAction>,
+    ) -> u64 {
+        let proposer_id = env::predecessor_account_id();
+        let held = self.owner_balance(&proposer_id);
+        require!(
+            held >= self.proposal_threshold,
+            "Not enough tokens held to create a proposal"
+        );
+        require!(
+            voting_starts_at.0 < voting_ends_at.0,
+            "Voting must start before it ends"
+        );
+        require!(
+            voting_ends_at.0 > env::block_timestamp(),
+            "Voting must end in the future"
+        );
+
+        if let Some(action) = &action {
+            self.assert_external_action(action);
+        }
+
+        let initial_storage_usage = env::storage_usage();
+        let id = self.proposals.len();
+        let snapshot_id = self.internal_take_snapshot();
+        self.proposals.push(&Proposal {
+            id,
+            proposer_id: proposer_id.clone(),
+            description,
+            snapshot_id,
+            voting_starts_at,
+            voting_ends_at,
+            yes: U128(0),
+            no: U128(0),
+            abstain: U128(0),
+            status: ProposalStatus::Open,
+            action,
+            action_approved: false,
+            action_executed: false,
+        });
+        let required = self
+            .proposal_bond
+            .saturating_add(storage_cost(env::storage_usage() - initial_storage_usage));
+        let deposit = env::attached_deposit();
+        require!(
+            deposit >= required,
+            format!(
+                "Must attach {} to create a proposal",
+                required.exact_amount_display()
+            )
+        );
+        let refund = deposit.saturating_sub(required);
+        if !refund.is_zero() {
+            Promise::new(proposer_id).transfer(refund);
+        }
+        id
+    }
+
+    /// Allows the action of `proposal_id` to be executed if the proposal passes, setting its
+    /// deposit aside from the treasury.
+    pub fn approve_proposal_action(&mut self, proposal_id: u64) {
+        self.assert_owner();
+        let mut proposal = self.proposal_or_panic(proposal_id);
+        let Some(action) = &proposal.action else {
+            env::panic_str("Proposal has no action");
+        };
+        require!(
+            proposal.status == ProposalStatus::Open,
+            "Proposal already finalized"
+        );
+        require!(
+            !proposal.action_approved,
+            "Proposal action already approved"
+        );
+        self.assert_external_action(action);
+        require!(action.deposit <= self.treasury, "Treasury balance too low");
+        self.treasury = self.treasury.saturating_sub(action.deposit);
+        proposal.action_approved = true;
+        self.proposals.replace(proposal_id, &proposal);
+    }
+
+    /// Votes on `proposal_id` with the tokens held at the proposal snapshot. Each account can
+    /// vote once. The storage of the vote is paid from the attached deposit.
+    #[payable]
+    pub fn vote(&mut self, proposal_id: u64, vote: Vote) {
+        let voter_id = env::predecessor_account_id();
+        let mut proposal = self.proposal_or_panic(proposal_id);
+        let now = env::block_timestamp();
+        require!(
+            now >= proposal.voting_starts_at.0 && now < proposal.voting_ends_at.0,
+            "Voting is not open"
+        );
+        let weight = self.internal_supply_for_owner_at(proposal.snapshot_id, &voter_id) as u128;
+        require!(weight > 0, "No tokens held at the proposal snapshot");
+
+        let initial_storage_usage = env::storage_usage();
+        require!(
+            self.proposal_votes
+                .insert(&(proposal_id, voter_id), &vote)
+                .is_none(),
+            "Already voted"
+        );
+        let tally = match vote {
+            Vote::Yes => &mut proposal.yes,
+            Vote::No => &mut proposal.no,
+            Vote::Abstain => &mut proposal.abstain,
+        };
+        tally.0 += weight;
+        self.proposals.replace(proposal_id, &proposal);
+        refund_deposit(env::storage_usage() - initial_storage_usage);
+    }
+
+    /// Records the outcome of `proposal_id` once voting ended and executes its action if the
+    /// proposal passed and the action was approved by the owner. Otherwise the deposit set aside
+    /// for the action returns to the treasury. Anyone can call it.
+    pub fn finalize_proposal(&mut self, proposal_id: u64) -> ProposalStatus {
+        let mut proposal = self.proposal_or_panic(proposal_id);
+        require!(
+            proposal.status == ProposalStatus::Open,
+            "Proposal already finalized"
+        );
+        require!(
+            env::block_timestamp() >= proposal.voting_ends_at.0,
+            "Voting has not ended"
+        );
+
+        proposal.status = if proposal.yes.0 > proposal.no.0 {
+            ProposalStatus::Passed
+        } else {
+            ProposalStatus::Rejected
+        };
+        if let Some(action) = proposal
+            .action
+            .as_ref()
+            .filter(|_| proposal.action_approved)
+        {
+            // A vault contract allowed since the approval is not called either.
+            if proposal.status == ProposalStatus::Passed && self.is_external_action(action) {
+                Promise::new(action.receiver_id.clone()).function_call(
+                    action.method_name.clone(),
+                    action.args.0.clone(),
+                    action.deposit,
+                    Gas::from_gas(action.gas.0),
+                );
+                proposal.action_executed = true;
+            } else {
+                self.treasury = self.treasury.saturating_add(action.deposit);
+            }
+        }
+        self.proposals.replace(proposal_id, &proposal);
+        proposal.status
+    }
+
+    pub fn proposal(&self, proposal_id: u64) -> Option<Proposal> {
+        self.proposals.get(proposal_id)
+    }
+
+    pub fn proposals(&self, from_index: Option<U128>, limit: Option<u64>) -> Vec<Proposal> {
+        let start = u128::from(from_index.unwrap_or(U128(0))) as u64;
+        (start..self.proposals.len())
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .filter_map(|id| self.proposals.get(id))
+            .collect()
+    }
+
+    pub fn proposal_vote(&self, proposal_id: u64, account_id: AccountId) -> Option<Vote> {
+        self.proposal_votes.get(&(proposal_id, account_id))
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+
+    use near_sdk::test_utils::{accounts, get_created_receipts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    const PROPOSAL_DEPOSIT: NearToken = NearToken::from_millinear(10);
+    const ONE_YOCTONEAR: NearToken = NearToken::from_yoctonear(1);
+    const VOTING_ENDS_AT: u64 = 1_000;
+
+    fn call(context: &mut VMContextBuilder, predecessor_id: AccountId, deposit: NearToken) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(deposit)
+            .predecessor_account_id(predecessor_id)
+            .build());
+    }
+
+    /// accounts(1) holds two tokens and accounts(2) holds one.
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        for (token_id, owner_id) in [("0", accounts(1)), ("1", accounts(1)), ("2", accounts(2))] {
+            call(context, accounts(0), MINT_STORAGE_COST);
+            contract.nft_mint(
+                token_id.to_string(),
+                owner_id,
+                sample_token_metadata(),
+                None,
+            );
+        }
+        contract
+    }
+
+    fn create_proposal(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        action: Option<ProposalAction>,
+    ) -> u64 {
+        call(
+            context,
+            accounts(1),
+            DEFAULT_PROPOSAL_BOND.saturating_add(PROPOSAL_DEPOSIT),
+        );
+        contract.create_proposal(
+            "Fund the community wallet".to_string(),
+            U64(0),
+            U64(VOTING_ENDS_AT),
+            action,
+        )
+    }
+
+    fn vote(context: &mut VMContextBuilder, contract: &mut Contract, voter_id: AccountId, v: Vote) {
+        call(context, voter_id, PROPOSAL_DEPOSIT);
+        contract.vote(0, v);
+    }
+
+    fn donation(receiver_id: AccountId) -> ProposalAction {
+        ProposalAction {
+            receiver_id,
+            method_name: "donate".to_string(),
+            args: Base64VecU8(b"{}".to_vec()),
+            deposit: NearToken::from_near(1),
+            gas: U64(Gas::from_tgas(10).as_gas()),
+        }
+    }
+
+    fn approve(context: &mut VMContextBuilder, contract: &mut Contract) {
+        call(context, accounts(0), NearToken::from_yoctonear(0));
+        contract.approve_proposal_action(0);
+    }
+
+    fn finalize(context: &mut VMContextBuilder, contract: &mut Contract) -> ProposalStatus {
+        context.block_timestamp(VOTING_ENDS_AT);
+        call(context, accounts(3), NearToken::from_yoctonear(0));
+        contract.finalize_proposal(0)
+    }
+
+    #[test]
+    fn test_votes_weighted_by_snapshot() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        create_proposal(&mut context, &mut contract, None);
+
+        // Moving a token after the proposal was created does not move its voting power.
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_transfer(accounts(2), "0".to_string(), None, None);
+
+        vote(&mut context, &mut contract, accounts(1), Vote::No);
+        vote(&mut context, &mut contract, accounts(2), Vote::Yes);
+
+        let proposal = contract.proposal(0).unwrap();
+        assert_eq!(proposal.no, U128(2));
+        assert_eq!(proposal.yes, U128(1));
+        assert_eq!(contract.proposal_vote(0, accounts(2)), Some(Vote::Yes));
+        assert_eq!(
+            finalize(&mut context, &mut contract),
+            ProposalStatus::Rejected
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough tokens held to create a proposal")]
+    fn test_create_proposal_below_threshold() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        contract.set_proposal_threshold(3);
+        create_proposal(&mut context, &mut contract, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "to create a proposal")]
+    fn test_create_proposal_without_bond() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), PROPOSAL_DEPOSIT);
+        contract.create_proposal(
+            "Fund the community wallet".to_string(),
+            U64(0),
+            U64(VOTING_ENDS_AT),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_proposal_bond_is_kept() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        contract.set_proposal_bond(NearToken::from_near(1));
+        call(&mut context, accounts(1), NearToken::from_near(2));
+        let initial_storage_usage = env::storage_usage();
+        contract.create_proposal(
+            "Fund the community wallet".to_string(),
+            U64(0),
+            U64(VOTING_ENDS_AT),
+            None,
+        );
+        let storage = crate::storage::storage_cost(env::storage_usage() - initial_storage_usage);
+        let refund = NearToken::from_near(1).saturating_sub(storage);
+        assert!(get_created_receipts().iter().any(|receipt| {
+            receipt.receiver_id == accounts(1)
+                && matches!(
+                    receipt.actions.as_slice(),
+                    [near_sdk::mock::MockAction::Transfer { deposit, .. }] if *deposit == refund
+                )
+        }));
+    }
+
+    #[test]
+    #[should_panic(expected = "Already voted")]
+    fn test_vote_twice() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        create_proposal(&mut context, &mut contract, None);
+        vote(&mut context, &mut contract, accounts(2), Vote::Yes);
+        vote(&mut context, &mut contract, accounts(2), Vote::No);
+    }
+
+    #[test]
+    #[should_panic(expected = "No tokens held at the proposal snapshot")]
+    fn test_vote_with_tokens_bought_later() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        create_proposal(&mut context, &mut contract, None);
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_transfer(accounts(3), "0".to_string(), None, None);
+        vote(&mut context, &mut contract, accounts(3), Vote::Yes);
+    }
+
+    #[test]
+    #[should_panic(expected = "Voting is not open")]
+    fn test_vote_after_voting_ended() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        create_proposal(&mut context, &mut contract, None);
+        context.block_timestamp(VOTING_ENDS_AT);
+        vote(&mut context, &mut contract, accounts(1), Vote::Yes);
+    }
+
+    #[test]
+    #[should_panic(expected = "Voting has not ended")]
+    fn test_finalize_before_voting_ended() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        create_proposal(&mut context, &mut contract, None);
+        contract.finalize_proposal(0);
+    }
+
+    #[test]
+    fn test_passed_proposal_executes_approved_action() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        contract.treasury = NearToken::from_near(3);
+        create_proposal(&mut context, &mut contract, Some(donation(accounts(4))));
+        approve(&mut context, &mut contract);
+        assert_eq!(contract.treasury_balance(), NearToken::from_near(2));
+        vote(&mut context, &mut contract, accounts(1), Vote::Yes);
+        vote(&mut context, &mut contract, accounts(2), Vote::Abstain);
+
+        assert_eq!(
+            finalize(&mut context, &mut contract),
+            ProposalStatus::Passed
+        );
+        assert!(contract.proposal(0).unwrap().action_executed);
+        assert!(get_created_receipts()
+            .iter()
+            .any(|receipt| receipt.receiver_id == accounts(4)));
+        assert_eq!(contract.treasury_balance(), NearToken::from_near(2));
+    }
+
+    #[test]
+    fn test_rejected_proposal_returns_action_deposit() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        contract.treasury = NearToken::from_near(1);
+        create_proposal(&mut context, &mut contract, Some(donation(accounts(4))));
+        approve(&mut context, &mut contract);
+        assert_eq!(contract.treasury_balance(), NearToken::from_near(0));
+        vote(&mut context, &mut contract, accounts(1), Vote::No);
+
+        assert_eq!(
+            finalize(&mut context, &mut contract),
+            ProposalStatus::Rejected
+        );
+        assert_eq!(contract.treasury_balance(), NearToken::from_near(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Treasury balance too low")]
+    fn test_approve_action_beyond_treasury() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        create_proposal(&mut context, &mut contract, Some(donation(accounts(4))));
+        approve(&mut context, &mut contract);
+    }
+
+    #[test]
+    #[should_panic(expected = "Proposal actions cannot call this contract or a vault contract")]
+    fn test_action_calling_this_contract() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        create_proposal(
+            &mut context,
+            &mut contract,
+            Some(donation(env::current_account_id())),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Proposal actions cannot call this contract or a vault contract")]
+    fn test_action_calling_vault_contract() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        contract.add_vault_contracts(vec![accounts(4)]);
+        create_proposal(&mut context, &mut contract, Some(donation(accounts(4))));
+    }
+
+    #[test]
+    fn test_action_to_new_vault_contract_is_not_executed() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        contract.treasury = NearToken::from_near(1);
+        create_proposal(&mut context, &mut contract, Some(donation(accounts(4))));
+        approve(&mut context, &mut contract);
+        contract.add_vault_contracts(vec![accounts(4)]);
+        vote(&mut context, &mut contract, accounts(1), Vote::Yes);
+
+        assert_eq!(
+            finalize(&mut context, &mut contract),
+            ProposalStatus::Passed
+        );
+        assert!(!contract.proposal(0).unwrap().action_executed);
+        assert_eq!(contract.treasury_balance(), NearToken::from_near(1));
+    }
+
+    #[test]
+    fn test_unapproved_action_is_not_executed() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        create_proposal(&mut context, &mut contract, Some(donation(accounts(4))));
+        vote(&mut context, &mut contract, accounts(1), Vote::Yes);
+
+        assert_eq!(
+            finalize(&mut context, &mut contract),
+            ProposalStatus::Passed
+        );
+        assert!(!contract.proposal(0).unwrap().action_executed);
+        assert!(get_created_receipts()
+            .iter()
+            .all(|receipt| receipt.receiver_id != accounts(4)));
+    }
+}
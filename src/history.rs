@@ -0,0 +1,504 @@
+/*!
+Bounded on-chain provenance history per token.
+
+When enabled with `set_history_max_entries`, every mint, transfer and burn of a token appends an
+entry to its history. Only the latest `history_max_entries` entries are kept; older ones are
+dropped as new ones come in. Entries written by a transfer are paid from the prepaid storage
+balance (`nft_storage_deposit`) of the account calling the transfer, while the mint and burn
+entries are settled together with the storage of the token itself.
+*/
+use crate::storage::{borsh_len, prefix_len, record_bytes};
+use crate::{Contract, ContractExt, StorageKey};
+use near_contract_standards::non_fungible_token::TokenId;
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{env, near, AccountId};
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum HistoryEvent {
+    Mint,
+    Transfer,
+    Burn,
+}
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    pub event: HistoryEvent,
+    /// Previous owner. `None` for mints.
+    pub from: Option<AccountId>,
+    /// New owner. `None` for burns.
+    pub to: Option<AccountId>,
+    pub memo: Option<String>,
+    /// Block timestamp in nanoseconds.
+    pub timestamp: U64,
+    /// Account whose prepaid storage balance paid for the entry. `None` for entries settled
+    /// with the storage of the token.
+    pub paid_by: Option<AccountId>,
+}
+
+impl HistoryEntry {
+    pub(crate) fn new(
+        event: HistoryEvent,
+        from: Option<&AccountId>,
+        to: Option<&AccountId>,
+        memo: Option<String>,
+    ) -> Self {
+        Self {
+            event,
+            from: from.cloned(),
+            to: to.cloned(),
+            memo,
+            timestamp: env::block_timestamp().into(),
+            paid_by: None,
+        }
+    }
+}
+
+/// Index of the oldest kept entry and of the next entry to be written for a token.
+#[near]
+#[derive(Clone, Copy, Default)]
+pub struct HistoryBounds {
+    start: u64,
+    end: u64,
+}
+
+impl Contract {
+    /// Appends `entry` to the history of `token_id`, dropping the oldest entries beyond the
+    /// configured maximum. Does nothing while history is disabled.
+    pub(crate) fn record_history(&mut self, token_id: &TokenId, entry: HistoryEntry) {
+        if self.history_max_entries == 0 {
+            return;
+        }
+        let mut bounds = self.history_bounds.get(token_id).unwrap_or_default();
+        self.history.insert(&(token_id.clone(), bounds.end), &entry);
+        bounds.end += 1;
+        while bounds.end - bounds.start > self.history_max_entries {
+            self.history.remove(&(token_id.clone(), bounds.start));
+            bounds.start += 1;
+        }
+        self.history_bounds.insert(token_id, &bounds);
+    }
+
+    /// Records a transfer of `token_id` and charges its storage to the prepaid balance of the
+    /// account which called the transfer.
+    pub(crate) fn record_transfer_history(
+        &mut self,
+        token_id: &TokenId,
+        old_owner_id: &AccountId,
+        new_owner_id: &AccountId,
+        memo: Option<String>,
+    ) {
+        let payer_id = env::predecessor_account_id();
+        let initial_storage_usage = env::storage_usage();
+        self.record_history(
+            token_id,
+            HistoryEntry {
+                paid_by: Some(payer_id.clone()),
+                ..HistoryEntry::new(
+                    HistoryEvent::Transfer,
+                    Some(old_owner_id),
+                    Some(new_owner_id),
+                    memo,
+                )
+            },
+        );
+        self.settle_prepaid_storage(&payer_id, initial_storage_usage);
+    }
+
+    /// Drops the entry of a transfer that `nft_resolve_transfer` rolled back, crediting the
+    /// released storage back to the account which paid for the entry. If that account has no
+    /// prepaid storage balance anymore, the released storage stays with the contract rather
+    /// than opening a balance record the contract would pay for.
+    pub(crate) fn revert_transfer_history(
+        &mut self,
+        token_id: &TokenId,
+        previous_owner_id: &AccountId,
+        receiver_id: &AccountId,
+    ) {
+        let mut bounds = match self.history_bounds.get(token_id) {
+            Some(bounds) if bounds.end > bounds.start => bounds,
+            _ => return,
+        };
+        let key = (token_id.clone(), bounds.end - 1);
+        let entry = match self.history.get(&key) {
+            Some(entry)
+                if entry.event == HistoryEvent::Transfer
+                    && entry.from.as_ref() == Some(previous_owner_id)
+                    && entry.to.as_ref() == Some(receiver_id) =>
+            {
+                entry
+            }
+            _ => return,
+        };
+
+        let initial_storage_usage = env::storage_usage();
+        self.history.remove(&key);
+        bounds.end -= 1;
+        self.history_bounds.insert(token_id, &bounds);
+        if let Some(payer_id) = entry
+            .paid_by
+            .filter(|payer_id| self.storage_balances.get(payer_id).is_some())
+        {
+            self.settle_prepaid_storage(&payer_id, initial_storage_usage);
+        }
+    }
+
+    /// Change in storage from recording `entry` for `token_id`, used by the storage estimates.
+    pub(crate) fn history_entry_storage_delta(
+        &self,
+        token_id: &TokenId,
+        entry: &HistoryEntry,
+    ) -> i64 {
+        if self.history_max_entries == 0 {
+            return 0;
+        }
+        let key_len = prefix_len(StorageKey::History) + borsh_len(&(token_id.clone(), 0u64));
+        let mut bytes = record_bytes(key_len, borsh_len(entry)) as i64;
+        let Some(bounds) = self.history_bounds.get(token_id) else {
+            return bytes
+                + record_bytes(
+                    prefix_len(StorageKey::HistoryBounds) + borsh_len(token_id),
+                    borsh_len(&HistoryBounds::default()),
+                ) as i64;
+        };
+        // Every entry beyond the maximum once `entry` is added is dropped, which can be more
+        // than one after the maximum was lowered.
+        let kept = bounds.end - bounds.start + 1;
+        let dropped = kept.saturating_sub(self.history_max_entries);
+        for index in bounds.start..bounds.start + dropped {
+            if let Some(oldest) = self.history.get(&(token_id.clone(), index)) {
+                bytes -= record_bytes(key_len, borsh_len(&oldest)) as i64;
+            }
+        }
+        bytes
+    }
+}
+
+#[near]
+impl Contract {
+    /// Sets how many entries are kept per token. `0` disables history.
+    pub fn set_history_max_entries(&mut self, max_entries: u64) {
+        self.assert_owner();
+        self.history_max_entries = max_entries;
+    }
+
+    pub fn history_max_entries(&self) -> u64 {
+        self.history_max_entries
+    }
+
+    /// Returns the kept history of `token_id`, oldest first. `from_index` is relative to the
+    /// oldest kept entry.
+    pub fn nft_history(
+        &self,
+        token_id: TokenId,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+    ) -> Vec<HistoryEntry> {
+        let bounds = self.history_bounds.get(&token_id).unwrap_or_default();
+        let start = bounds
+            .start
+            .saturating_add(from_index.map_or(0, |index| index.0 as u64));
+        let limit = limit.unwrap_or(bounds.end - bounds.start);
+        (start..bounds.end)
+            .take(limit as usize)
+            .filter_map(|index| self.history.get(&(token_id.clone(), index)))
+            .collect()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_contract_standards::non_fungible_token::approval::NonFungibleTokenApproval;
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, NearToken};
+
+    use super::*;
+
+    const STORAGE_DEPOSIT: NearToken = NearToken::from_millinear(10);
+    const ONE_YOCTONEAR: NearToken = NearToken::from_yoctonear(1);
+
+    /// Contract owned by accounts(0) keeping `max_entries` per token, with token "0" minted to
+    /// accounts(1) and a prepaid storage balance for accounts(1).
+    fn setup(context: &mut VMContextBuilder, max_entries: u64) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        contract.set_history_max_entries(max_entries);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .predecessor_account_id(accounts(0))
+            .build());
+        contract.nft_mint("0".to_string(), accounts(1), sample_token_metadata(), None);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(STORAGE_DEPOSIT)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.nft_storage_deposit(None);
+        contract
+    }
+
+    fn transfer(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        memo: &str,
+    ) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(ONE_YOCTONEAR)
+            .predecessor_account_id(sender_id)
+            .build());
+        contract.nft_transfer(receiver_id, "0".to_string(), None, Some(memo.to_string()));
+    }
+
+    #[test]
+    fn test_history_records_mint_transfer_and_burn() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context, 10);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(1),
+            accounts(2),
+            "gift",
+        );
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(ONE_YOCTONEAR)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.nft_burn("0".to_string());
+
+        let history = contract.nft_history("0".to_string(), None, None);
+        let events: Vec<_> = history.iter().map(|entry| entry.event.clone()).collect();
+        assert_eq!(
+            events,
+            vec![
+                HistoryEvent::Mint,
+                HistoryEvent::Transfer,
+                HistoryEvent::Burn
+            ]
+        );
+        assert_eq!(history[0].to, Some(accounts(1)));
+        assert_eq!(history[1].from, Some(accounts(1)));
+        assert_eq!(history[1].to, Some(accounts(2)));
+        assert_eq!(history[1].memo, Some("gift".to_string()));
+        assert_eq!(history[2].from, Some(accounts(2)));
+        assert_eq!(history[2].to, None);
+    }
+
+    #[test]
+    fn test_transfer_history_charged_to_sender() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context, 10);
+        let balance_before = contract.nft_storage_balance_of(accounts(1));
+        let entry = HistoryEntry {
+            paid_by: Some(accounts(1)),
+            ..HistoryEntry::new(
+                HistoryEvent::Transfer,
+                Some(&accounts(1)),
+                Some(&accounts(2)),
+                Some("gift".to_string()),
+            )
+        };
+        let entry_bytes = contract.history_entry_storage_delta(&"0".to_string(), &entry);
+        let used = crate::storage::storage_cost(entry_bytes as u64);
+
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(1),
+            accounts(2),
+            "gift",
+        );
+
+        assert!(!used.is_zero());
+        assert_eq!(
+            contract.nft_storage_balance_of(accounts(1)),
+            balance_before.saturating_sub(used)
+        );
+    }
+
+    #[test]
+    fn test_transfer_history_after_lowering_max_entries() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context, 10);
+        for account_id in [accounts(2), accounts(3)] {
+            testing_env!(context
+                .storage_usage(env::storage_usage())
+                .attached_deposit(STORAGE_DEPOSIT)
+                .predecessor_account_id(account_id)
+                .build());
+            contract.nft_storage_deposit(None);
+        }
+        transfer(&mut context, &mut contract, accounts(1), accounts(2), "a");
+        transfer(&mut context, &mut contract, accounts(2), accounts(3), "b");
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_history_max_entries(1);
+
+        // The next entry drops the three kept so far.
+        let balance_before = contract.nft_storage_balance_of(accounts(3));
+        let entry = HistoryEntry {
+            paid_by: Some(accounts(3)),
+            ..HistoryEntry::new(
+                HistoryEvent::Transfer,
+                Some(&accounts(3)),
+                Some(&accounts(4)),
+                Some("c".to_string()),
+            )
+        };
+        let delta = contract.history_entry_storage_delta(&"0".to_string(), &entry);
+        assert!(delta < 0);
+        transfer(&mut context, &mut contract, accounts(3), accounts(4), "c");
+
+        assert_eq!(contract.nft_history("0".to_string(), None, None).len(), 1);
+        assert_eq!(
+            contract.nft_storage_balance_of(accounts(3)),
+            balance_before.saturating_add(crate::storage::storage_cost(delta.unsigned_abs()))
+        );
+    }
+
+    #[test]
+    fn test_reverted_transfer_credits_payer() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context, 10);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.nft_approve("0".to_string(), accounts(3), None);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(STORAGE_DEPOSIT)
+            .predecessor_account_id(accounts(3))
+            .build());
+        contract.nft_storage_deposit(None);
+        let owner_balance = contract.nft_storage_balance_of(accounts(1));
+        let payer_balance = contract.nft_storage_balance_of(accounts(3));
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(ONE_YOCTONEAR)
+            .predecessor_account_id(accounts(3))
+            .build());
+        contract.nft_transfer(accounts(2), "0".to_string(), None, None);
+        assert!(contract.nft_storage_balance_of(accounts(3)) < payer_balance);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .predecessor_account_id(accounts(0))
+            .build());
+        contract.revert_transfer_history(&"0".to_string(), &accounts(1), &accounts(2));
+        assert_eq!(contract.nft_storage_balance_of(accounts(3)), payer_balance);
+        assert_eq!(contract.nft_storage_balance_of(accounts(1)), owner_balance);
+        assert_eq!(contract.nft_history("0".to_string(), None, None).len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Prepaid storage balance of charlie is too low")]
+    fn test_transfer_without_prepaid_storage_panics() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context, 10);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(1),
+            accounts(2),
+            "gift",
+        );
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(3),
+            "resale",
+        );
+    }
+
+    #[test]
+    fn test_history_is_bounded_and_paginated() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context, 2);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(1),
+            accounts(2),
+            "first",
+        );
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(STORAGE_DEPOSIT)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.nft_storage_deposit(None);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(3),
+            "second",
+        );
+
+        let history = contract.nft_history("0".to_string(), None, None);
+        let memos: Vec<_> = history.iter().map(|entry| entry.memo.clone()).collect();
+        assert_eq!(
+            memos,
+            vec![Some("first".to_string()), Some("second".to_string())]
+        );
+
+        let page = contract.nft_history("0".to_string(), Some(U128(1)), Some(1));
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].memo, Some("second".to_string()));
+    }
+
+    #[test]
+    fn test_history_disabled_by_default() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context, 0);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(1),
+            accounts(2),
+            "unused",
+        );
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(3),
+            "unused",
+        );
+        // accounts(2) has no prepaid balance, which is fine while history is disabled.
+        assert!(contract.nft_history("0".to_string(), None, None).is_empty());
+    }
+
+    #[test]
+    fn test_estimate_mint_storage_includes_history() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context, 10);
+
+        let estimate =
+            contract.estimate_mint_storage("1".to_string(), accounts(2), sample_token_metadata());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .predecessor_account_id(accounts(0))
+            .build());
+        let initial_storage_usage = env::storage_usage();
+        contract.nft_mint("1".to_string(), accounts(2), sample_token_metadata(), None);
+        let actual = crate::storage::storage_cost(env::storage_usage() - initial_storage_usage);
+        assert_eq!(estimate, actual);
+    }
+}
@@ -15,28 +15,151 @@ NOTES:
   - To prevent the deployed contract from being modified or deleted, it should not have any access
     keys on its account.
 */
+use near_contract_standards::fungible_token::FungibleToken;
 use near_contract_standards::non_fungible_token::approval::NonFungibleTokenApproval;
 use near_contract_standards::non_fungible_token::core::{
     NonFungibleTokenCore, NonFungibleTokenResolver,
 };
 use near_contract_standards::non_fungible_token::enumeration::NonFungibleTokenEnumeration;
+use near_contract_standards::non_fungible_token::events::{NftBurn, NftMint};
 use near_contract_standards::non_fungible_token::metadata::{
     NFTContractMetadata, NonFungibleTokenMetadataProvider, TokenMetadata, NFT_METADATA_SPEC,
 };
-use near_contract_standards::non_fungible_token::NonFungibleToken;
+use near_contract_standards::non_fungible_token::{
+    refund_approved_account_ids, refund_deposit, NonFungibleToken,
+};
 use near_contract_standards::non_fungible_token::{Token, TokenId};
-use near_sdk::collections::LazyOption;
+use near_sdk::collections::{LazyOption, LookupMap, LookupSet, TreeMap, UnorderedSet, Vector};
 use near_sdk::json_types::U128;
 use near_sdk::{
-    env, near, require, AccountId, BorshStorageKey, PanicOnDefault, Promise, PromiseOrValue,
+    assert_one_yocto, env, near, require, AccountId, BorshStorageKey, NearToken, PanicOnDefault,
+    Promise, PromiseOrValue, PublicKey,
 };
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::attributes::Attribute;
+use crate::clawback::{ClawbackStatus, PendingForceTransfer};
+use crate::fractional::Fractionalization;
+use crate::governance::{Proposal, Vote, DEFAULT_PROPOSAL_BOND};
+use crate::history::{HistoryBounds, HistoryEntry, HistoryEvent};
+use crate::membership::MembershipConfig;
+use crate::metadata_rules::MetadataRules;
+use crate::mint_pool::{assert_not_pool_token_id, PoolItem};
+use crate::multisig::{MultisigConfig, MultisigProposal};
+use crate::redemption::Redemption;
+use crate::render::SvgCanvas;
+use crate::reveal::Reveal;
+use crate::roles::Role;
+use crate::snapshot::{Checkpoints, Snapshot};
+use crate::sponsor::{DailyUsage, SponsorPool};
+use crate::staking::{RewardAccount, Stake};
+use crate::stats::{LevelThreshold, TokenStats};
+use crate::storage::storage_cost;
+use crate::timelock::QueuedAction;
+use crate::transfer_policy::TransferPolicyMode;
+use crate::validity::{assert_valid_validity_window, ValidityEnforcement};
+use crate::vault::VaultEntry;
+
+mod attributes;
+mod base_uri;
+mod clawback;
+mod composable;
+mod denylist;
+mod events;
+mod fractional;
+mod governance;
+mod history;
+mod membership;
+mod metadata_rules;
+mod mint_pool;
+mod multisig;
+mod permit;
+mod redemption;
+mod render;
+mod reveal;
+mod roles;
+mod signing_keys;
+mod snapshot;
+mod sponsor;
+mod staking;
+mod stats;
+mod storage;
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod test_utils;
+mod timelock;
+mod transfer_policy;
+mod validity;
+mod vault;
 
 #[derive(PanicOnDefault)]
 #[near(contract_state)]
 pub struct Contract {
     tokens: NonFungibleToken,
     metadata: LazyOption<NFTContractMetadata>,
+    roles: LookupSet<(Role, AccountId)>,
+    sponsor_pool: SponsorPool,
+    sponsor_usage: LookupMap<AccountId, DailyUsage>,
+    sponsored_tokens: LookupSet<TokenId>,
+    storage_balances: LookupMap<AccountId, NearToken>,
+    history_max_entries: u64,
+    history: LookupMap<(TokenId, u64), HistoryEntry>,
+    history_bounds: LookupMap<TokenId, HistoryBounds>,
+    snapshots: Vector<Snapshot>,
+    token_checkpoints: Checkpoints<TokenId, Option<AccountId>>,
+    balance_checkpoints: Checkpoints<AccountId, u64>,
+    proposal_threshold: u64,
+    proposals: Vector<Proposal>,
+    proposal_votes: LookupMap<(u64, AccountId), Vote>,
+    stakes: LookupMap<TokenId, Stake>,
+    reward_rate_per_second: u128,
+    series_reward_rates: LookupMap<String, u128>,
+    reward_token: Option<AccountId>,
+    reward_accounts: LookupMap<AccountId, RewardAccount>,
+    reward_points: LookupMap<AccountId, u128>,
+    validity_enforcement: ValidityEnforcement,
+    expiring_tokens: TreeMap<(u64, TokenId), ()>,
+    signing_keys: LookupMap<AccountId, PublicKey>,
+    redemption_lock: bool,
+    redemptions: Vector<Redemption>,
+    redemption_index: LookupMap<TokenId, u64>,
+    membership_config: Option<MembershipConfig>,
+    paid_through: LookupMap<TokenId, u64>,
+    treasury: NearToken,
+    parent_of: LookupMap<TokenId, TokenId>,
+    children_of: LookupMap<TokenId, Vec<TokenId>>,
+    vault: LookupMap<TokenId, VaultEntry>,
+    shares: FungibleToken,
+    fractionalization: Option<Fractionalization>,
+    permit_nonces: LookupSet<(AccountId, [u8; 32])>,
+    transfer_policy_mode: TransferPolicyMode,
+    transfer_policy_accounts: UnorderedSet<AccountId>,
+    transfer_policy_exempt_series: LookupSet<String>,
+    denylist: LookupSet<AccountId>,
+    clawback_status: ClawbackStatus,
+    pending_force_transfers: TreeMap<TokenId, PendingForceTransfer>,
+    admin_delay: u64,
+    queued_actions: TreeMap<u64, QueuedAction>,
+    next_action_id: u64,
+    multisig: Option<MultisigConfig>,
+    multisig_proposals: TreeMap<u64, MultisigProposal>,
+    next_multisig_proposal_id: u64,
+    reveal: Option<Reveal>,
+    reveal_entries: Vector<TokenMetadata>,
+    mint_pool: Vector<PoolItem>,
+    mint_pool_loaded: u64,
+    random_mints: u64,
+    random_mint_price: NearToken,
+    token_attributes: LookupMap<TokenId, Vec<Attribute>>,
+    attribute_tokens: TreeMap<(String, String, TokenId), ()>,
+    attribute_counts: LookupMap<String, BTreeMap<String, u64>>,
+    svg_canvas: Option<SvgCanvas>,
+    svg_layers: LookupMap<(String, String), String>,
+    relative_uris: bool,
+    metadata_rules: MetadataRules,
+    token_stats: LookupMap<TokenId, TokenStats>,
+    level_thresholds: Vec<LevelThreshold>,
+    proposal_bond: NearToken,
+    vault_contracts: UnorderedSet<AccountId>,
 }
 
 const DATA_IMAGE_SVG_NEAR_ICON: &str = "data:image/svg+xml,%3Csvg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 288 288'%3E%3Cg id='l' data-name='l'%3E%3Cpath d='M187.58,79.81l-30.1,44.69a3.2,3.2,0,0,0,4.75,4.2L191.86,103a1.2,1.2,0,0,1,2,.91v80.46a1.2,1.2,0,0,1-2.12.77L102.18,77.93A15.35,15.35,0,0,0,90.47,72.5H87.34A15.34,15.34,0,0,0,72,87.84V201.16A15.34,15.34,0,0,0,87.34,216.5h0a15.35,15.35,0,0,0,13.08-7.31l30.1-44.69a3.2,3.2,0,0,0-4.75-4.2L96.14,186a1.2,1.2,0,0,1-2-.91V104.61a1.2,1.2,0,0,1,2.12-.77l89.55,107.23a15.35,15.35,0,0,0,11.71,5.43h3.13A15.34,15.34,0,0,0,216,201.16V87.84A15.34,15.34,0,0,0,200.66,72.5h0A15.35,15.35,0,0,0,187.58,79.81Z'/%3E%3C/g%3E%3C/svg%3E";
@@ -49,6 +172,47 @@ enum StorageKey {
     TokenMetadata,
     Enumeration,
     Approval,
+    Roles,
+    SponsorUsage,
+    SponsoredTokens,
+    StorageBalances,
+    History,
+    HistoryBounds,
+    Snapshots,
+    TokenCheckpoints,
+    BalanceCheckpoints,
+    Proposals,
+    ProposalVotes,
+    Stakes,
+    SeriesRewardRates,
+    RewardAccounts,
+    RewardPoints,
+    ExpiringTokens,
+    SigningKeys,
+    Redemptions,
+    RedemptionIndex,
+    PaidThrough,
+    ParentOf,
+    ChildrenOf,
+    Vault,
+    Shares,
+    PermitNonces,
+    TransferPolicyAccounts,
+    TransferPolicyExemptSeries,
+    Denylist,
+    PendingForceTransfers,
+    QueuedActions,
+    MultisigProposals,
+    RevealEntries,
+    MintPool,
+    TokenAttributes,
+    AttributeTokens,
+    AttributeCounts,
+    SvgLayers,
+    TokenStats,
+    VaultContracts,
+    TokenCheckpointLens,
+    BalanceCheckpointLens,
 }
 
 #[near]
@@ -84,6 +248,76 @@ impl Contract {
                 Some(StorageKey::Approval),
             ),
             metadata: LazyOption::new(StorageKey::Metadata, Some(&metadata)),
+            roles: LookupSet::new(StorageKey::Roles),
+            sponsor_pool: SponsorPool::default(),
+            sponsor_usage: LookupMap::new(StorageKey::SponsorUsage),
+            sponsored_tokens: LookupSet::new(StorageKey::SponsoredTokens),
+            storage_balances: LookupMap::new(StorageKey::StorageBalances),
+            history_max_entries: 0,
+            history: LookupMap::new(StorageKey::History),
+            history_bounds: LookupMap::new(StorageKey::HistoryBounds),
+            snapshots: Vector::new(StorageKey::Snapshots),
+            token_checkpoints: Checkpoints::new(
+                StorageKey::TokenCheckpointLens,
+                StorageKey::TokenCheckpoints,
+            ),
+            balance_checkpoints: Checkpoints::new(
+                StorageKey::BalanceCheckpointLens,
+                StorageKey::BalanceCheckpoints,
+            ),
+            proposal_threshold: 1,
+            proposals: Vector::new(StorageKey::Proposals),
+            proposal_votes: LookupMap::new(StorageKey::ProposalVotes),
+            stakes: LookupMap::new(StorageKey::Stakes),
+            reward_rate_per_second: 0,
+            series_reward_rates: LookupMap::new(StorageKey::SeriesRewardRates),
+            reward_token: None,
+            reward_accounts: LookupMap::new(StorageKey::RewardAccounts),
+            reward_points: LookupMap::new(StorageKey::RewardPoints),
+            validity_enforcement: ValidityEnforcement::Disabled,
+            expiring_tokens: TreeMap::new(StorageKey::ExpiringTokens),
+            signing_keys: LookupMap::new(StorageKey::SigningKeys),
+            redemption_lock: false,
+            redemptions: Vector::new(StorageKey::Redemptions),
+            redemption_index: LookupMap::new(StorageKey::RedemptionIndex),
+            membership_config: None,
+            paid_through: LookupMap::new(StorageKey::PaidThrough),
+            treasury: NearToken::from_yoctonear(0),
+            parent_of: LookupMap::new(StorageKey::ParentOf),
+            children_of: LookupMap::new(StorageKey::ChildrenOf),
+            vault: LookupMap::new(StorageKey::Vault),
+            shares: FungibleToken::new(StorageKey::Shares),
+            fractionalization: None,
+            permit_nonces: LookupSet::new(StorageKey::PermitNonces),
+            transfer_policy_mode: TransferPolicyMode::Open,
+            transfer_policy_accounts: UnorderedSet::new(StorageKey::TransferPolicyAccounts),
+            transfer_policy_exempt_series: LookupSet::new(StorageKey::TransferPolicyExemptSeries),
+            denylist: LookupSet::new(StorageKey::Denylist),
+            clawback_status: ClawbackStatus::Disabled,
+            pending_force_transfers: TreeMap::new(StorageKey::PendingForceTransfers),
+            admin_delay: 0,
+            queued_actions: TreeMap::new(StorageKey::QueuedActions),
+            next_action_id: 0,
+            multisig: None,
+            multisig_proposals: TreeMap::new(StorageKey::MultisigProposals),
+            next_multisig_proposal_id: 0,
+            reveal: None,
+            reveal_entries: Vector::new(StorageKey::RevealEntries),
+            mint_pool: Vector::new(StorageKey::MintPool),
+            mint_pool_loaded: 0,
+            random_mints: 0,
+            random_mint_price: NearToken::from_yoctonear(0),
+            token_attributes: LookupMap::new(StorageKey::TokenAttributes),
+            attribute_tokens: TreeMap::new(StorageKey::AttributeTokens),
+            attribute_counts: LookupMap::new(StorageKey::AttributeCounts),
+            svg_canvas: None,
+            svg_layers: LookupMap::new(StorageKey::SvgLayers),
+            relative_uris: false,
+            metadata_rules: MetadataRules::default(),
+            token_stats: LookupMap::new(StorageKey::TokenStats),
+            level_thresholds: Vec::new(),
+            proposal_bond: DEFAULT_PROPOSAL_BOND,
+            vault_contracts: UnorderedSet::new(StorageKey::VaultContracts),
         }
     }
 
@@ -93,20 +327,220 @@ impl Contract {
     /// in this call. `self.tokens.mint` will also require it to be Some, since
     /// `StorageKey::TokenMetadata` was provided at initialization.
     ///
+    /// Can be called by the owner or any account holding the `Minter` role. With
+    /// `sponsored: true` the storage is paid from the sponsor pool and the attached deposit is
+    /// refunded in full.
+    ///
     #[payable]
     pub fn nft_mint(
         &mut self,
         token_id: TokenId,
         token_owner_id: AccountId,
         token_metadata: TokenMetadata,
+        sponsored: Option<bool>,
     ) -> Token {
-        assert_eq!(
-            env::predecessor_account_id(),
-            self.tokens.owner_id,
-            "Unauthorized"
+        self.assert_role(Role::Minter);
+        let initial_storage_usage = env::storage_usage();
+        let token = self.internal_mint(token_id, token_owner_id, token_metadata);
+
+        if sponsored.unwrap_or(false) {
+            self.charge_sponsor_pool(&token.token_id, &token.owner_id, initial_storage_usage);
+            let deposit = env::attached_deposit();
+            if !deposit.is_zero() {
+                Promise::new(env::predecessor_account_id()).transfer(deposit);
+            }
+        } else {
+            refund_deposit(env::storage_usage() - initial_storage_usage);
+        }
+
+        NftMint {
+            owner_id: &token.owner_id,
+            token_ids: &[&token.token_id],
+            memo: None,
+        }
+        .emit();
+        token
+    }
+
+    /// Burn the token with ID=`token_id`. Only its owner can burn it.
+    ///
+    /// Approvals are refunded as in `nft_revoke_all`. The storage released by the token itself
+    /// goes back to the sponsor pool if the token was a sponsored mint. Otherwise it stays with
+    /// the contract, since the minter paid for it rather than the holder. Burning a vault wrapper
+    /// sends the underlying token to the owner.
+    #[payable]
+    pub fn nft_burn(&mut self, token_id: TokenId) {
+        assert_one_yocto();
+        let owner_id = self.owner_of(&token_id);
+        require!(
+            env::predecessor_account_id() == owner_id,
+            "Predecessor must be token owner."
+        );
+        self.assert_not_staked(&token_id);
+        self.assert_burnable_in_time(&token_id);
+        self.assert_no_children(&token_id);
+        self.assert_not_frozen(&token_id);
+        self.assert_not_fractionalized(&token_id);
+        self.assert_no_pending_force_transfer(&token_id);
+
+        if let Some(approved_account_ids) = self
+            .tokens
+            .approvals_by_id
+            .as_mut()
+            .and_then(|by_id| by_id.remove(&token_id))
+        {
+            refund_approved_account_ids(owner_id.clone(), &approved_account_ids);
+        }
+
+        let sponsored = self.sponsored_tokens.contains(&token_id);
+        let initial_storage_usage = env::storage_usage();
+        self.record_history(
+            &token_id,
+            HistoryEntry::new(HistoryEvent::Burn, Some(&owner_id), None, None),
+        );
+        self.checkpoint_owner_change(&token_id, Some(&owner_id), None);
+        self.internal_burn(&token_id, &owner_id);
+        self.release_from_vault(&token_id, &owner_id);
+        if sponsored {
+            self.refill_sponsor_pool(storage_cost(initial_storage_usage - env::storage_usage()));
+        }
+
+        NftBurn {
+            owner_id: &owner_id,
+            token_ids: &[&token_id],
+            authorized_id: None,
+            memo: None,
+        }
+        .emit();
+    }
+}
+
+impl Contract {
+    /// Stores a new token and everything the contract keeps alongside it, without charging for
+    /// the storage or emitting the mint event. Ids of the pool series are left to
+    /// `nft_mint_random`.
+    fn internal_mint(
+        &mut self,
+        token_id: TokenId,
+        token_owner_id: AccountId,
+        token_metadata: TokenMetadata,
+    ) -> Token {
+        assert_not_pool_token_id(&token_id);
+        self.store_new_token(token_id, token_owner_id, token_metadata)
+    }
+
+    /// Same as `internal_mint`, for any token id.
+    fn store_new_token(
+        &mut self,
+        token_id: TokenId,
+        token_owner_id: AccountId,
+        token_metadata: TokenMetadata,
+    ) -> Token {
+        self.assert_not_denied(&token_owner_id);
+        self.assert_not_revealed_id(&token_id);
+        self.metadata_rules.assert_valid(&token_metadata);
+        assert_valid_validity_window(&token_metadata);
+        self.index_expiry(&token_id, &token_metadata);
+        self.checkpoint_owner_change(&token_id, None, Some(&token_owner_id));
+        let token = self.tokens.internal_mint_with_refund(
+            token_id,
+            token_owner_id,
+            Some(token_metadata),
+            None,
+        );
+        self.record_history(
+            &token.token_id,
+            HistoryEntry::new(HistoryEvent::Mint, None, Some(&token.owner_id), None),
+        );
+        token
+    }
+
+    /// Replaces the metadata of `token_id`, keeping the expiry index up to date. The caller is
+    /// responsible for authorization and storage.
+    fn internal_update_metadata(&mut self, token_id: &TokenId, metadata: &TokenMetadata) {
+        assert_valid_validity_window(metadata);
+        self.unindex_expiry(token_id);
+        if let Some(token_metadata_by_id) = &mut self.tokens.token_metadata_by_id {
+            token_metadata_by_id.insert(token_id, metadata);
+        }
+        self.index_expiry(token_id, metadata);
+    }
+
+    /// Moves `token_id` from `from` to `to` on behalf of the contract, keeping snapshots and
+    /// history up to date. Approvals are refunded to `from`. The caller is responsible for
+    /// authorization and storage.
+    fn internal_move(
+        &mut self,
+        token_id: &TokenId,
+        from: &AccountId,
+        to: &AccountId,
+        memo: Option<String>,
+    ) {
+        self.assert_not_denied(&env::predecessor_account_id());
+        self.assert_not_denied(to);
+        self.checkpoint_owner_change(token_id, Some(from), Some(to));
+        let (_, approved_account_ids) =
+            self.tokens
+                .internal_transfer(from, to, token_id, None, memo.clone());
+        if let Some(approved_account_ids) = approved_account_ids {
+            refund_approved_account_ids(from.clone(), &approved_account_ids);
+        }
+        self.record_history(
+            token_id,
+            HistoryEntry::new(HistoryEvent::Transfer, Some(from), Some(to), memo),
         );
+    }
+
+    /// Removes every record kept for `token_id`. The history of the token is kept.
+    fn internal_burn(&mut self, token_id: &TokenId, owner_id: &AccountId) {
+        self.unindex_expiry(token_id);
+        self.unindex_attributes(token_id);
+        self.remove_stats(token_id);
+        self.remove_redemption_index(token_id);
+        self.tokens.owner_by_id.remove(token_id);
+        if let Some(token_metadata_by_id) = &mut self.tokens.token_metadata_by_id {
+            token_metadata_by_id.remove(token_id);
+        }
+        if let Some(tokens_per_owner) = &mut self.tokens.tokens_per_owner {
+            if let Some(mut owner_tokens) = tokens_per_owner.remove(owner_id) {
+                owner_tokens.remove(token_id);
+                if !owner_tokens.is_empty() {
+                    tokens_per_owner.insert(owner_id, &owner_tokens);
+                }
+            }
+        }
+        if let Some(approvals_by_id) = &mut self.tokens.approvals_by_id {
+            approvals_by_id.remove(token_id);
+        }
+        if let Some(next_approval_id_by_id) = &mut self.tokens.next_approval_id_by_id {
+            next_approval_id_by_id.remove(token_id);
+        }
+        self.sponsored_tokens.remove(token_id);
+        self.paid_through.remove(token_id);
+    }
+
+    /// `token` as returned by the views, with its on-chain image and absolute URIs filled in.
+    fn token_view(&self, token: Token) -> Token {
+        self.with_absolute_uris(self.with_rendered_media(token))
+    }
+
+    /// Panics if one of the contract's extensions currently prevents `token_id` from being
+    /// transferred.
+    fn assert_transferable(&self, token_id: &TokenId) {
+        self.assert_not_staked(token_id);
+        self.assert_transferable_in_time(token_id);
+        self.assert_not_locked_by_redemption(token_id);
+        self.assert_not_attached(token_id);
+        self.assert_not_frozen(token_id);
+        self.assert_not_fractionalized(token_id);
+        self.assert_no_pending_force_transfer(token_id);
+    }
+
+    fn owner_of(&self, token_id: &TokenId) -> AccountId {
         self.tokens
-            .internal_mint(token_id, token_owner_id, Some(token_metadata))
+            .owner_by_id
+            .get(token_id)
+            .unwrap_or_else(|| env::panic_str("Token not found"))
     }
 }
 
@@ -120,8 +554,19 @@ impl NonFungibleTokenCore for Contract {
         approval_id: Option<u64>,
         memo: Option<String>,
     ) {
-        self.tokens
-            .nft_transfer(receiver_id, token_id, approval_id, memo);
+        let old_owner_id = self.owner_of(&token_id);
+        self.assert_transferable(&token_id);
+        self.assert_sender_allowed(&token_id, &old_owner_id);
+        self.assert_not_denied(&env::predecessor_account_id());
+        self.assert_not_denied(&receiver_id);
+        self.checkpoint_owner_change(&token_id, Some(&old_owner_id), Some(&receiver_id));
+        self.tokens.nft_transfer(
+            receiver_id.clone(),
+            token_id.clone(),
+            approval_id,
+            memo.clone(),
+        );
+        self.record_transfer_history(&token_id, &old_owner_id, &receiver_id, memo);
     }
 
     #[payable]
@@ -133,12 +578,27 @@ impl NonFungibleTokenCore for Contract {
         memo: Option<String>,
         msg: String,
     ) -> PromiseOrValue<bool> {
-        self.tokens
-            .nft_transfer_call(receiver_id, token_id, approval_id, memo, msg)
+        let old_owner_id = self.owner_of(&token_id);
+        self.assert_transferable(&token_id);
+        self.assert_sender_allowed(&token_id, &old_owner_id);
+        self.assert_not_denied(&env::predecessor_account_id());
+        self.assert_not_denied(&receiver_id);
+        self.checkpoint_owner_change(&token_id, Some(&old_owner_id), Some(&receiver_id));
+        let result = self.tokens.nft_transfer_call(
+            receiver_id.clone(),
+            token_id.clone(),
+            approval_id,
+            memo.clone(),
+            msg,
+        );
+        self.record_transfer_history(&token_id, &old_owner_id, &receiver_id, memo);
+        result
     }
 
     fn nft_token(&self, token_id: TokenId) -> Option<Token> {
-        self.tokens.nft_token(token_id)
+        self.tokens
+            .nft_token(token_id)
+            .map(|token| self.token_view(token))
     }
 }
 
@@ -152,12 +612,21 @@ impl NonFungibleTokenResolver for Contract {
         token_id: TokenId,
         approved_account_ids: Option<HashMap<AccountId, u64>>,
     ) -> bool {
-        self.tokens.nft_resolve_transfer(
-            previous_owner_id,
-            receiver_id,
-            token_id,
+        // The token may be returned below. Copying the current values is harmless if it is not,
+        // since nothing changed since they were last written.
+        if self.tokens.owner_by_id.get(&token_id).as_ref() == Some(&receiver_id) {
+            self.checkpoint_owner_change(&token_id, Some(&receiver_id), Some(&previous_owner_id));
+        }
+        let transferred = self.tokens.nft_resolve_transfer(
+            previous_owner_id.clone(),
+            receiver_id.clone(),
+            token_id.clone(),
             approved_account_ids,
-        )
+        );
+        if !transferred {
+            self.revert_transfer_history(&token_id, &previous_owner_id, &receiver_id);
+        }
+        transferred
     }
 }
 
@@ -170,6 +639,8 @@ impl NonFungibleTokenApproval for Contract {
         account_id: AccountId,
         msg: Option<String>,
     ) -> Option<Promise> {
+        self.assert_not_frozen(&token_id);
+        self.assert_not_denied(&account_id);
         self.tokens.nft_approve(token_id, account_id, msg)
     }
 
@@ -201,7 +672,11 @@ impl NonFungibleTokenEnumeration for Contract {
     }
 
     fn nft_tokens(&self, from_index: Option<U128>, limit: Option<u64>) -> Vec<Token> {
-        self.tokens.nft_tokens(from_index, limit)
+        self.tokens
+            .nft_tokens(from_index, limit)
+            .into_iter()
+            .map(|token| self.token_view(token))
+            .collect()
     }
 
     fn nft_supply_for_owner(&self, account_id: AccountId) -> U128 {
@@ -216,6 +691,9 @@ impl NonFungibleTokenEnumeration for Contract {
     ) -> Vec<Token> {
         self.tokens
             .nft_tokens_for_owner(account_id, from_index, limit)
+            .into_iter()
+            .map(|token| self.token_view(token))
+            .collect()
     }
 }
 
@@ -228,7 +706,8 @@ impl NonFungibleTokenMetadataProvider for Contract {
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use crate::test_utils::{get_context, sample_token_metadata};
+    use near_sdk::test_utils::{accounts, get_created_receipts};
     use near_sdk::{testing_env, NearToken};
     use std::collections::HashMap;
 
@@ -239,32 +718,6 @@ mod tests {
     const MINT_STORAGE_COST: NearToken = NearToken::from_yoctonear(5870000000000000000000);
     const APPROVE_STORAGE_COST: NearToken = NearToken::from_yoctonear(150000000000000000000);
 
-    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
-        let mut builder = VMContextBuilder::new();
-        builder
-            .current_account_id(accounts(0))
-            .signer_account_id(predecessor_account_id.clone())
-            .predecessor_account_id(predecessor_account_id);
-        builder
-    }
-
-    fn sample_token_metadata() -> TokenMetadata {
-        TokenMetadata {
-            title: Some("Olympus Mons".into()),
-            description: Some("The tallest mountain in the charted solar system".into()),
-            media: None,
-            media_hash: None,
-            copies: Some(1u64),
-            issued_at: None,
-            expires_at: None,
-            starts_at: None,
-            updated_at: None,
-            extra: None,
-            reference: None,
-            reference_hash: None,
-        }
-    }
-
     #[test]
     fn test_new() {
         let mut context = get_context(accounts(1));
@@ -295,7 +748,7 @@ mod tests {
             .build());
 
         let token_id = "0".to_string();
-        let token = contract.nft_mint(token_id.clone(), accounts(0), sample_token_metadata());
+        let token = contract.nft_mint(token_id.clone(), accounts(0), sample_token_metadata(), None);
         assert_eq!(token.token_id, token_id);
         assert_eq!(token.owner_id, accounts(0));
         assert_eq!(token.metadata.unwrap(), sample_token_metadata());
@@ -314,7 +767,7 @@ mod tests {
             .predecessor_account_id(accounts(0))
             .build());
         let token_id = "0".to_string();
-        contract.nft_mint(token_id.clone(), accounts(0), sample_token_metadata());
+        contract.nft_mint(token_id.clone(), accounts(0), sample_token_metadata(), None);
 
         testing_env!(context
             .storage_usage(env::storage_usage())
@@ -339,6 +792,116 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_burn() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .predecessor_account_id(accounts(0))
+            .build());
+        let token_id = "0".to_string();
+        contract.nft_mint(token_id.clone(), accounts(1), sample_token_metadata(), None);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(ONE_YOCTONEAR)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.nft_burn(token_id.clone());
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .account_balance(env::account_balance())
+            .is_view(true)
+            .attached_deposit(ZERO_NEAR)
+            .build());
+        assert_eq!(contract.nft_token(token_id), None);
+        assert_eq!(contract.nft_total_supply(), U128(0));
+        assert_eq!(contract.nft_supply_for_owner(accounts(1)), U128(0));
+    }
+
+    #[test]
+    fn test_burn_keeps_released_storage() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .predecessor_account_id(accounts(0))
+            .build());
+        contract.nft_mint("0".to_string(), accounts(1), sample_token_metadata(), None);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(ONE_YOCTONEAR)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.nft_burn("0".to_string());
+        assert!(get_created_receipts().is_empty());
+    }
+
+    #[test]
+    fn test_internal_move_refunds_approvals() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .predecessor_account_id(accounts(0))
+            .build());
+        let token_id = "0".to_string();
+        contract.nft_mint(token_id.clone(), accounts(1), sample_token_metadata(), None);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.nft_approve(token_id.clone(), accounts(2), None);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(ZERO_NEAR)
+            .predecessor_account_id(accounts(0))
+            .build());
+        contract.internal_move(&token_id, &accounts(1), &accounts(3), None);
+        assert!(!contract.nft_is_approved(token_id, accounts(2), None));
+        assert!(get_created_receipts()
+            .iter()
+            .any(|receipt| receipt.receiver_id == accounts(1)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Predecessor must be token owner.")]
+    fn test_burn_by_non_owner() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .predecessor_account_id(accounts(0))
+            .build());
+        let token_id = "0".to_string();
+        contract.nft_mint(token_id.clone(), accounts(1), sample_token_metadata(), None);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(ONE_YOCTONEAR)
+            .predecessor_account_id(accounts(0))
+            .build());
+        contract.nft_burn(token_id);
+    }
+
     #[test]
     fn test_approve() {
         let mut context = get_context(accounts(0));
@@ -351,7 +914,7 @@ mod tests {
             .predecessor_account_id(accounts(0))
             .build());
         let token_id = "0".to_string();
-        contract.nft_mint(token_id.clone(), accounts(0), sample_token_metadata());
+        contract.nft_mint(token_id.clone(), accounts(0), sample_token_metadata(), None);
 
         // alice approves bob
         testing_env!(context
@@ -382,7 +945,7 @@ mod tests {
             .predecessor_account_id(accounts(0))
             .build());
         let token_id = "0".to_string();
-        contract.nft_mint(token_id.clone(), accounts(0), sample_token_metadata());
+        contract.nft_mint(token_id.clone(), accounts(0), sample_token_metadata(), None);
 
         // alice approves bob
         testing_env!(context
@@ -420,7 +983,7 @@ mod tests {
             .predecessor_account_id(accounts(0))
             .build());
         let token_id = "0".to_string();
-        contract.nft_mint(token_id.clone(), accounts(0), sample_token_metadata());
+        contract.nft_mint(token_id.clone(), accounts(0), sample_token_metadata(), None);
 
         // alice approves bob
         testing_env!(context
@@ -0,0 +1,275 @@
+/*!
+Renewable memberships.
+
+Once the owner set a `MembershipConfig`, every token is a membership paid through a given time.
+Anyone can `renew` a token for a number of periods at the configured price. A membership still
+counts during `grace_period` after it lapsed, and renewing within the grace period extends it
+from its previous end, so members keep a continuous membership. Renewals after that start from
+the time of the renewal.
+
+Proceeds go to the treasury, which the owner can withdraw from and which pays the deposits of
+approved governance actions.
+*/
+use crate::storage::storage_cost;
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::TokenId;
+use near_sdk::json_types::U64;
+use near_sdk::{assert_one_yocto, env, near, require, AccountId, NearToken, Promise};
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct MembershipConfig {
+    /// Length of a period in nanoseconds.
+    pub period: U64,
+    pub price_per_period: NearToken,
+    /// How long a lapsed membership keeps counting, in nanoseconds.
+    pub grace_period: U64,
+}
+
+impl Contract {
+    fn membership_config_or_panic(&self) -> MembershipConfig {
+        self.membership_config
+            .clone()
+            .unwrap_or_else(|| env::panic_str("Memberships are not enabled"))
+    }
+
+    fn is_membership_active(&self, token_id: &TokenId) -> bool {
+        let Some(config) = &self.membership_config else {
+            return false;
+        };
+        self.paid_through.get(token_id).is_some_and(|paid_through| {
+            env::block_timestamp() < paid_through.saturating_add(config.grace_period.0)
+        })
+    }
+}
+
+#[near]
+impl Contract {
+    /// Enables memberships, or updates their terms. Only affects future renewals.
+    pub fn set_membership_config(&mut self, config: MembershipConfig) {
+        self.assert_owner();
+        require!(config.period.0 > 0, "Period must not be empty");
+        self.membership_config = Some(config);
+    }
+
+    pub fn membership_config(&self) -> Option<MembershipConfig> {
+        self.membership_config.clone()
+    }
+
+    /// Extends the membership of `token_id` by `periods`. Anyone can pay for it. The attached
+    /// deposit must cover the price and, on the first renewal of a token, the storage of its
+    /// paid-through timestamp; the rest is refunded. Returns the new paid-through timestamp.
+    #[payable]
+    pub fn renew(&mut self, token_id: TokenId, periods: u32) -> U64 {
+        let config = self.membership_config_or_panic();
+        self.owner_of(&token_id);
+        require!(periods > 0, "Must renew for at least one period");
+
+        let now = env::block_timestamp();
+        let start = match self.paid_through.get(&token_id) {
+            Some(paid_through) if now < paid_through.saturating_add(config.grace_period.0) => {
+                paid_through
+            }
+            _ => now,
+        };
+        let paid_through = start.saturating_add(config.period.0.saturating_mul(periods.into()));
+
+        let initial_storage_usage = env::storage_usage();
+        self.paid_through.insert(&token_id, &paid_through);
+        let price = config
+            .price_per_period
+            .checked_mul(periods.into())
+            .unwrap_or_else(|| env::panic_str("Price overflow"));
+        let required =
+            price.saturating_add(storage_cost(env::storage_usage() - initial_storage_usage));
+        let deposit = env::attached_deposit();
+        require!(
+            deposit >= required,
+            format!("Must attach {} to renew", required.exact_amount_display())
+        );
+        self.treasury = self.treasury.saturating_add(price);
+        let refund = deposit.saturating_sub(required);
+        if !refund.is_zero() {
+            Promise::new(env::predecessor_account_id()).transfer(refund);
+        }
+        paid_through.into()
+    }
+
+    /// Timestamp in nanoseconds `token_id` is paid through, `None` if it was never renewed.
+    pub fn nft_paid_through(&self, token_id: TokenId) -> Option<U64> {
+        self.paid_through.get(&token_id).map(U64)
+    }
+
+    /// Whether `token_id` is paid through now, grace period included.
+    pub fn nft_is_membership_active(&self, token_id: TokenId) -> bool {
+        self.is_membership_active(&token_id)
+    }
+
+    /// Whether `account_id` holds at least one active membership.
+    pub fn is_member(&self, account_id: AccountId) -> bool {
+        self.tokens
+            .tokens_per_owner
+            .as_ref()
+            .and_then(|by_owner| by_owner.get(&account_id))
+            .is_some_and(|tokens| tokens.iter().any(|id| self.is_membership_active(&id)))
+    }
+
+    pub fn treasury_balance(&self) -> NearToken {
+        self.treasury
+    }
+
+    /// Withdraws `amount` from the treasury, all of it by default, to `receiver_id` or the owner.
+    #[payable]
+    pub fn treasury_withdraw(
+        &mut self,
+        amount: Option<NearToken>,
+        receiver_id: Option<AccountId>,
+    ) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        let amount = amount.unwrap_or(self.treasury);
+        require!(amount <= self.treasury, "Treasury balance too low");
+        self.treasury = self.treasury.saturating_sub(amount);
+        Promise::new(receiver_id.unwrap_or_else(|| self.tokens.owner_id.clone())).transfer(amount)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+
+    use near_sdk::test_utils::{accounts, get_created_receipts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    const PRICE: NearToken = NearToken::from_near(1);
+    const PERIOD: u64 = 1_000;
+    const GRACE_PERIOD: u64 = 100;
+    const ONE_YOCTONEAR: NearToken = NearToken::from_yoctonear(1);
+
+    /// Membership "0" owned by accounts(1), not renewed yet.
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        contract.set_membership_config(MembershipConfig {
+            period: U64(PERIOD),
+            price_per_period: PRICE,
+            grace_period: U64(GRACE_PERIOD),
+        });
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .build());
+        contract.nft_mint("0".to_string(), accounts(1), sample_token_metadata(), None);
+        contract
+    }
+
+    /// Renews "0" by `periods` at `now`, paid by accounts(2).
+    fn renew(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        now: u64,
+        periods: u32,
+    ) -> U64 {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .block_timestamp(now)
+            .attached_deposit(
+                PRICE
+                    .saturating_mul(periods.into())
+                    .saturating_add(MINT_STORAGE_COST)
+            )
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.renew("0".to_string(), periods)
+    }
+
+    fn at(context: &mut VMContextBuilder, now: u64) {
+        testing_env!(context.block_timestamp(now).build());
+    }
+
+    #[test]
+    fn test_renew_and_is_member() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        assert!(!contract.is_member(accounts(1)));
+
+        assert_eq!(
+            renew(&mut context, &mut contract, 10, 2),
+            U64(10 + 2 * PERIOD)
+        );
+        assert_eq!(contract.treasury_balance(), PRICE.saturating_mul(2));
+        assert!(contract.is_member(accounts(1)));
+        assert!(!contract.is_member(accounts(2)));
+
+        // Still a member during the grace period only.
+        at(&mut context, 10 + 2 * PERIOD + GRACE_PERIOD - 1);
+        assert!(contract.is_member(accounts(1)));
+        at(&mut context, 10 + 2 * PERIOD + GRACE_PERIOD);
+        assert!(!contract.is_member(accounts(1)));
+    }
+
+    #[test]
+    fn test_renew_within_grace_period_extends_previous_end() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        renew(&mut context, &mut contract, 0, 1);
+        assert_eq!(
+            renew(&mut context, &mut contract, PERIOD + GRACE_PERIOD - 1, 1),
+            U64(2 * PERIOD)
+        );
+    }
+
+    #[test]
+    fn test_renew_after_grace_period_starts_now() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        renew(&mut context, &mut contract, 0, 1);
+        let now = PERIOD + GRACE_PERIOD;
+        assert_eq!(
+            renew(&mut context, &mut contract, now, 1),
+            U64(now + PERIOD)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Must attach")]
+    fn test_renew_underpaid() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(PRICE)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.renew("0".to_string(), 2);
+    }
+
+    #[test]
+    fn test_treasury_withdraw() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        renew(&mut context, &mut contract, 0, 3);
+
+        testing_env!(context
+            .attached_deposit(ONE_YOCTONEAR)
+            .predecessor_account_id(accounts(0))
+            .build());
+        contract.treasury_withdraw(Some(PRICE), Some(accounts(3)));
+        assert_eq!(contract.treasury_balance(), PRICE.saturating_mul(2));
+        assert!(get_created_receipts()
+            .iter()
+            .any(|receipt| receipt.receiver_id == accounts(3)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Memberships are not enabled")]
+    fn test_renew_without_config() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        testing_env!(context.attached_deposit(PRICE).build());
+        contract.renew("0".to_string(), 1);
+    }
+}
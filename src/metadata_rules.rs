@@ -0,0 +1,284 @@
+/*!
+Validation of `TokenMetadata` at mint.
+
+Every mint path checks the metadata against the `MetadataRules` set by the owner: length limits
+on the free-form fields and URIs, hashes which must be base64 sha256 digests of an URI that is
+actually set, and an allowlist of URI schemes. Relative URIs, resolved against `base_uri` as
+described in NEP-177, have no scheme and are always accepted, even with a `:` in their path like
+`3:14.png`.
+*/
+use crate::base_uri::uri_scheme;
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::metadata::TokenMetadata;
+use near_sdk::json_types::Base64VecU8;
+use near_sdk::{near, require};
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetadataRules {
+    /// Limits in bytes, `None` for no limit.
+    pub max_title_len: Option<u32>,
+    pub max_description_len: Option<u32>,
+    pub max_extra_len: Option<u32>,
+    /// Applies to `media` and `reference`.
+    pub max_uri_len: Option<u32>,
+    /// Whether `media_hash` and `reference_hash` must be 32 bytes and come with their URI.
+    pub check_hashes: bool,
+    /// Schemes allowed in absolute URIs, `None` to allow any.
+    pub allowed_schemes: Option<Vec<String>>,
+}
+
+impl Default for MetadataRules {
+    fn default() -> Self {
+        Self {
+            max_title_len: Some(256),
+            max_description_len: Some(4096),
+            max_extra_len: Some(8192),
+            max_uri_len: Some(2048),
+            check_hashes: true,
+            allowed_schemes: Some(
+                ["ipfs", "ar", "https", "data"]
+                    .iter()
+                    .map(|scheme| scheme.to_string())
+                    .collect(),
+            ),
+        }
+    }
+}
+
+fn assert_max_len(field: &str, value: &Option<String>, max_len: Option<u32>) {
+    if let (Some(value), Some(max_len)) = (value, max_len) {
+        require!(
+            value.len() <= max_len as usize,
+            format!("{} exceeds {} bytes", field, max_len)
+        );
+    }
+}
+
+fn assert_valid_hash(
+    field: &str,
+    hash: &Option<Base64VecU8>,
+    uri_field: &str,
+    uri: &Option<String>,
+) {
+    if let Some(hash) = hash {
+        require!(
+            hash.0.len() == 32,
+            format!("{} must be a base64 encoded sha256 hash", field)
+        );
+        require!(uri.is_some(), format!("{} requires {}", field, uri_field));
+    }
+}
+
+impl MetadataRules {
+    fn assert_allowed_uri(&self, field: &str, uri: &Option<String>) {
+        assert_max_len(field, uri, self.max_uri_len);
+        let (Some(uri), Some(allowed_schemes)) = (uri, &self.allowed_schemes) else {
+            return;
+        };
+        if let Some(scheme) = uri_scheme(uri) {
+            require!(
+                allowed_schemes.iter().any(|allowed| allowed == scheme),
+                format!("{} scheme {} is not allowed", field, scheme)
+            );
+        }
+    }
+
+    /// Panics with the first rule `metadata` breaks.
+    pub(crate) fn assert_valid(&self, metadata: &TokenMetadata) {
+        assert_max_len("title", &metadata.title, self.max_title_len);
+        assert_max_len(
+            "description",
+            &metadata.description,
+            self.max_description_len,
+        );
+        assert_max_len("extra", &metadata.extra, self.max_extra_len);
+        self.assert_allowed_uri("media", &metadata.media);
+        self.assert_allowed_uri("reference", &metadata.reference);
+        if self.check_hashes {
+            assert_valid_hash("media_hash", &metadata.media_hash, "media", &metadata.media);
+            assert_valid_hash(
+                "reference_hash",
+                &metadata.reference_hash,
+                "reference",
+                &metadata.reference,
+            );
+        }
+    }
+}
+
+#[near]
+impl Contract {
+    pub fn set_metadata_rules(&mut self, rules: MetadataRules) {
+        self.assert_owner();
+        self.metadata_rules = rules;
+    }
+
+    pub fn metadata_rules(&self) -> MetadataRules {
+        self.metadata_rules.clone()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_sdk::test_utils::accounts;
+    use near_sdk::{env, testing_env};
+
+    use super::*;
+
+    fn valid_token_metadata() -> TokenMetadata {
+        TokenMetadata {
+            media: Some(
+                "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi".into(),
+            ),
+            media_hash: Some(Base64VecU8(vec![0; 32])),
+            ..sample_token_metadata()
+        }
+    }
+
+    /// Mints `metadata` as token "0" under the default rules.
+    fn mint(metadata: TokenMetadata) {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .build());
+        contract.nft_mint("0".to_string(), accounts(1), metadata, None);
+    }
+
+    #[test]
+    fn test_valid_metadata() {
+        mint(valid_token_metadata());
+    }
+
+    #[test]
+    fn test_relative_and_data_uris() {
+        mint(TokenMetadata {
+            media: Some("images/0.png".into()),
+            reference: Some("data:application/json,{}".into()),
+            ..valid_token_metadata()
+        });
+    }
+
+    #[test]
+    fn test_colon_in_relative_path() {
+        mint(TokenMetadata {
+            media: Some("3:14.png".into()),
+            reference: Some("meta/3:14.json".into()),
+            ..valid_token_metadata()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "title exceeds 256 bytes")]
+    fn test_title_too_long() {
+        mint(TokenMetadata {
+            title: Some("a".repeat(257)),
+            ..valid_token_metadata()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "description exceeds 4096 bytes")]
+    fn test_description_too_long() {
+        mint(TokenMetadata {
+            description: Some("a".repeat(4097)),
+            ..valid_token_metadata()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "extra exceeds 8192 bytes")]
+    fn test_extra_too_long() {
+        mint(TokenMetadata {
+            extra: Some("a".repeat(8193)),
+            ..valid_token_metadata()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "reference exceeds 2048 bytes")]
+    fn test_uri_too_long() {
+        mint(TokenMetadata {
+            reference: Some(format!("https://example.com/{}", "a".repeat(2048))),
+            ..valid_token_metadata()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "media_hash must be a base64 encoded sha256 hash")]
+    fn test_media_hash_wrong_length() {
+        mint(TokenMetadata {
+            media_hash: Some(Base64VecU8(vec![0; 31])),
+            ..valid_token_metadata()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "reference_hash must be a base64 encoded sha256 hash")]
+    fn test_reference_hash_wrong_length() {
+        mint(TokenMetadata {
+            reference: Some("ipfs://reference".into()),
+            reference_hash: Some(Base64VecU8(vec![0; 33])),
+            ..valid_token_metadata()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "media_hash requires media")]
+    fn test_media_hash_without_media() {
+        mint(TokenMetadata {
+            media: None,
+            ..valid_token_metadata()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "reference_hash requires reference")]
+    fn test_reference_hash_without_reference() {
+        mint(TokenMetadata {
+            reference_hash: Some(Base64VecU8(vec![0; 32])),
+            ..valid_token_metadata()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "media scheme http is not allowed")]
+    fn test_scheme_not_allowed() {
+        mint(TokenMetadata {
+            media: Some("http://example.com/0.png".into()),
+            ..valid_token_metadata()
+        });
+    }
+
+    #[test]
+    fn test_relaxed_rules() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        contract.set_metadata_rules(MetadataRules {
+            max_title_len: None,
+            check_hashes: false,
+            allowed_schemes: None,
+            ..MetadataRules::default()
+        });
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .build());
+        contract.nft_mint(
+            "0".to_string(),
+            accounts(1),
+            TokenMetadata {
+                title: Some("a".repeat(257)),
+                media: Some("http://example.com/0.png".into()),
+                reference_hash: Some(Base64VecU8(vec![0; 8])),
+                ..valid_token_metadata()
+            },
+            None,
+        );
+    }
+}
@@ -0,0 +1,290 @@
+/*!
+Random mints from a pre-loaded metadata pool.
+
+The owner loads the items of a generative collection into the pool, in as many chunks as needed.
+Items are checked against the metadata rules as they are loaded. Each item gets an id from the
+order it was loaded in, and its token the id `pool:<item id>`. Ids of this series are reserved:
+the other mints reject them, so they cannot collide with tokens minted otherwise. Anyone can then
+`nft_mint_random`, which mints a random item still in the pool to the caller. Items are drawn
+uniformly from what is left by swapping the drawn item with the last one and popping it.
+
+The randomness comes from `env::random_seed` mixed with the number of random mints so far, since
+the seed is the same for every mint of a block.
+*/
+use crate::storage::storage_cost;
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::events::NftMint;
+use near_contract_standards::non_fungible_token::metadata::TokenMetadata;
+use near_contract_standards::non_fungible_token::{refund_deposit, Token, TokenId};
+use near_sdk::json_types::U64;
+use near_sdk::{env, near, require, NearToken, Promise};
+
+/// Series of the tokens minted from the pool.
+pub const POOL_SERIES: &str = "pool";
+
+/// Panics if `token_id` belongs to the series reserved to `nft_mint_random`.
+pub(crate) fn assert_not_pool_token_id(token_id: &TokenId) {
+    require!(
+        !token_id.starts_with(&format!("{}:", POOL_SERIES)),
+        "Token ids of the pool series are reserved for random mints"
+    );
+}
+
+#[near(serializers = [borsh])]
+pub struct PoolItem {
+    pub item_id: u64,
+    pub metadata: TokenMetadata,
+}
+
+impl Contract {
+    fn random_pool_index(&self) -> u64 {
+        let mut bytes = env::random_seed();
+        bytes.extend(self.random_mints.to_le_bytes());
+        let hash = env::sha256(&bytes);
+        let mut head = [0u8; 16];
+        head.copy_from_slice(&hash[..16]);
+        (u128::from_le_bytes(head) % u128::from(self.mint_pool.len())) as u64
+    }
+}
+
+#[near]
+impl Contract {
+    /// Appends `items` to the pool, panicking on the first one breaking the metadata rules. The
+    /// storage is paid from the attached deposit.
+    #[payable]
+    pub fn load_mint_pool(&mut self, items: Vec<TokenMetadata>) {
+        self.assert_owner();
+        let initial_storage_usage = env::storage_usage();
+        for metadata in items {
+            self.metadata_rules.assert_valid(&metadata);
+            self.mint_pool.push(&PoolItem {
+                item_id: self.mint_pool_loaded,
+                metadata,
+            });
+            self.mint_pool_loaded += 1;
+        }
+        refund_deposit(env::storage_usage() - initial_storage_usage);
+    }
+
+    /// Price of a random mint, on top of its storage. Proceeds go to the treasury.
+    pub fn set_random_mint_price(&mut self, price: NearToken) {
+        self.assert_owner();
+        self.random_mint_price = price;
+    }
+
+    pub fn random_mint_price(&self) -> NearToken {
+        self.random_mint_price
+    }
+
+    /// Mints a random item of the pool to the caller. The attached deposit must cover the price
+    /// and the storage of the token; the rest is refunded.
+    #[payable]
+    pub fn nft_mint_random(&mut self) -> Token {
+        require!(!self.mint_pool.is_empty(), "Mint pool is empty");
+        let index = self.random_pool_index();
+        let item = self.mint_pool.swap_remove(index);
+        self.random_mints += 1;
+
+        let receiver_id = env::predecessor_account_id();
+        let initial_storage_usage = env::storage_usage();
+        let token = self.store_new_token(
+            format!("{}:{}", POOL_SERIES, item.item_id),
+            receiver_id.clone(),
+            item.metadata,
+        );
+        let required = self
+            .random_mint_price
+            .saturating_add(storage_cost(env::storage_usage() - initial_storage_usage));
+        let deposit = env::attached_deposit();
+        require!(
+            deposit >= required,
+            format!("Must attach {} to mint", required.exact_amount_display())
+        );
+        self.treasury = self.treasury.saturating_add(self.random_mint_price);
+        let refund = deposit.saturating_sub(required);
+        if !refund.is_zero() {
+            Promise::new(receiver_id).transfer(refund);
+        }
+
+        NftMint {
+            owner_id: &token.owner_id,
+            token_ids: &[&token.token_id],
+            memo: None,
+        }
+        .emit();
+        token
+    }
+
+    /// Number of items left to mint.
+    pub fn mint_pool_size(&self) -> U64 {
+        self.mint_pool.len().into()
+    }
+
+    /// Number of items loaded so far, minted or not.
+    pub fn mint_pool_loaded(&self) -> U64 {
+        self.mint_pool_loaded.into()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, AccountId};
+
+    use super::*;
+
+    const POOL_SIZE: u64 = 5;
+
+    fn token_metadata(title: &str) -> TokenMetadata {
+        TokenMetadata {
+            title: Some(title.into()),
+            ..sample_token_metadata()
+        }
+    }
+
+    fn call(context: &mut VMContextBuilder, predecessor_id: AccountId, seed: u8) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .random_seed([seed; 32])
+            .attached_deposit(MINT_STORAGE_COST)
+            .predecessor_account_id(predecessor_id)
+            .build());
+    }
+
+    /// `POOL_SIZE` items titled "Item <id>", loaded in two chunks.
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        let items: Vec<_> = (0..POOL_SIZE)
+            .map(|i| token_metadata(&format!("Item {}", i)))
+            .collect();
+        call(context, accounts(0), 0);
+        contract.load_mint_pool(items[..2].to_vec());
+        call(context, accounts(0), 0);
+        contract.load_mint_pool(items[2..].to_vec());
+        contract
+    }
+
+    #[test]
+    fn test_mint_whole_pool() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        assert_eq!(contract.mint_pool_size(), U64(POOL_SIZE));
+        assert_eq!(contract.mint_pool_loaded(), U64(POOL_SIZE));
+
+        let mut minted = vec![];
+        for seed in 0..POOL_SIZE {
+            call(&mut context, accounts(1), seed as u8);
+            let token = contract.nft_mint_random();
+            assert_eq!(token.owner_id, accounts(1));
+            let item_id = token.token_id.strip_prefix("pool:").unwrap();
+            assert_eq!(
+                token.metadata.unwrap().title,
+                Some(format!("Item {}", item_id))
+            );
+            minted.push(item_id.parse::<u64>().unwrap());
+        }
+        minted.sort_unstable();
+        assert_eq!(minted, (0..POOL_SIZE).collect::<Vec<_>>());
+        assert_eq!(contract.mint_pool_size(), U64(0));
+        assert_eq!(contract.mint_pool_loaded(), U64(POOL_SIZE));
+    }
+
+    #[test]
+    fn test_same_seed_draws_different_items() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), 7);
+        let first = contract.nft_mint_random();
+        call(&mut context, accounts(1), 7);
+        let second = contract.nft_mint_random();
+        assert_ne!(first.token_id, second.token_id);
+        assert!(contract.nft_token(second.token_id).is_some());
+    }
+
+    #[test]
+    fn test_price_goes_to_treasury() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(0), 0);
+        contract.set_random_mint_price(NearToken::from_millinear(50));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(NearToken::from_millinear(150))
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.nft_mint_random();
+        assert_eq!(contract.treasury_balance(), NearToken::from_millinear(50));
+    }
+
+    #[test]
+    #[should_panic(expected = "Must attach")]
+    fn test_price_not_covered() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(0), 0);
+        contract.set_random_mint_price(NearToken::from_near(1));
+        call(&mut context, accounts(1), 0);
+        contract.nft_mint_random();
+    }
+
+    #[test]
+    #[should_panic(expected = "Mint pool is empty")]
+    fn test_mint_from_empty_pool() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        call(&mut context, accounts(1), 0);
+        contract.nft_mint_random();
+    }
+
+    #[test]
+    fn test_pool_ids_do_not_collide() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(0), 0);
+        contract.nft_mint("0".to_string(), accounts(2), token_metadata("Box"), None);
+        for seed in 0..POOL_SIZE {
+            call(&mut context, accounts(1), seed as u8);
+            contract.nft_mint_random();
+        }
+        assert_eq!(
+            contract.nft_token("0".to_string()).unwrap().owner_id,
+            accounts(2)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Token ids of the pool series are reserved for random mints")]
+    fn test_mint_pool_id_directly() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(0), 0);
+        contract.nft_mint(
+            "pool:0".to_string(),
+            accounts(2),
+            token_metadata("Item 0"),
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "title exceeds 256 bytes")]
+    fn test_load_invalid_metadata() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(0), 0);
+        contract.load_mint_pool(vec![token_metadata(&"a".repeat(257))]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unauthorized")]
+    fn test_load_by_non_owner() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), 0);
+        contract.load_mint_pool(vec![token_metadata("Item")]);
+    }
+}
@@ -0,0 +1,479 @@
+/*!
+M-of-N approvals for privileged calls.
+
+Once the owner configured a multisig, the owner key alone no longer controls the contract:
+owner-only methods reject direct calls and the owner loses its implicit roles. Privileged actions
+are instead proposed by one of the approvers and executed as soon as `threshold` approvers
+confirmed them, the proposer included. Changing the approver set is itself a privileged action,
+made with a `FunctionCall` of `set_multisig`.
+
+As with the admin delay, the owner must be an account other than the contract, whose own calls
+pass every owner check. A multisig and an admin delay cannot be combined: multisig actions run as
+soon as they are confirmed, which would bypass the delay.
+
+Proposers pay for their proposal and attach a deposit which covers the storage of the action
+(e.g. a minted token); what is left is refunded on execution or cancellation.
+*/
+use crate::events::{ContractEvent, NftMetadataUpdate};
+use crate::roles::Role;
+use crate::storage::storage_cost;
+use crate::timelock::assert_timelocked_method;
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::events::NftMint;
+use near_contract_standards::non_fungible_token::metadata::TokenMetadata;
+use near_contract_standards::non_fungible_token::TokenId;
+use near_sdk::json_types::{Base64VecU8, U128, U64};
+use near_sdk::{env, near, require, AccountId, Gas, NearToken, Promise, StorageUsage};
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct MultisigConfig {
+    pub approvers: Vec<AccountId>,
+    pub threshold: u32,
+}
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum MultisigAction {
+    Mint {
+        token_id: TokenId,
+        receiver_id: AccountId,
+        metadata: TokenMetadata,
+    },
+    UpdateMetadata {
+        token_id: TokenId,
+        metadata: TokenMetadata,
+    },
+    /// Deploys `code` to this contract.
+    Upgrade {
+        code: Base64VecU8,
+    },
+    GrantRole {
+        role: Role,
+        account_id: AccountId,
+    },
+    RevokeRole {
+        role: Role,
+        account_id: AccountId,
+    },
+    /// Calls an owner-only method of this contract, e.g. `set_multisig`.
+    FunctionCall {
+        method_name: String,
+        args: Base64VecU8,
+        deposit: NearToken,
+        gas: U64,
+    },
+}
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct MultisigProposal {
+    pub id: u64,
+    pub proposer_id: AccountId,
+    pub action: MultisigAction,
+    /// Held for the storage of the action, and attached to `FunctionCall`s.
+    pub deposit: NearToken,
+    pub confirmations: Vec<AccountId>,
+}
+
+impl Contract {
+    fn assert_approver(&self) -> MultisigConfig {
+        let config = self
+            .multisig
+            .clone()
+            .unwrap_or_else(|| env::panic_str("Multisig is not enabled"));
+        require!(
+            config.approvers.contains(&env::predecessor_account_id()),
+            "Predecessor is not an approver"
+        );
+        config
+    }
+
+    fn multisig_proposal_or_panic(&self, proposal_id: u64) -> MultisigProposal {
+        self.multisig_proposals
+            .get(&proposal_id)
+            .unwrap_or_else(|| env::panic_str("Proposal not found"))
+    }
+
+    /// Refunds to the proposer what is left of its deposit once the storage taken since
+    /// `initial_storage_usage` was paid for.
+    fn settle_multisig_deposit(
+        &self,
+        proposal: &MultisigProposal,
+        held: NearToken,
+        initial_storage_usage: StorageUsage,
+    ) {
+        let storage_usage = env::storage_usage();
+        let refund = if storage_usage >= initial_storage_usage {
+            let cost = storage_cost(storage_usage - initial_storage_usage);
+            require!(
+                held >= cost,
+                format!(
+                    "Proposal deposit does not cover {} of storage",
+                    cost.exact_amount_display()
+                )
+            );
+            held.saturating_sub(cost)
+        } else {
+            held.saturating_add(storage_cost(initial_storage_usage - storage_usage))
+        };
+        if !refund.is_zero() {
+            Promise::new(proposal.proposer_id.clone()).transfer(refund);
+        }
+    }
+
+    fn execute_multisig_proposal(&mut self, proposal: MultisigProposal) {
+        let initial_storage_usage = env::storage_usage();
+        self.multisig_proposals.remove(&proposal.id);
+        let mut held = proposal.deposit;
+        match proposal.action.clone() {
+            MultisigAction::Mint {
+                token_id,
+                receiver_id,
+                metadata,
+            } => {
+                let token = self.internal_mint(token_id, receiver_id, metadata);
+                NftMint {
+                    owner_id: &token.owner_id,
+                    token_ids: &[&token.token_id],
+                    memo: None,
+                }
+                .emit();
+            }
+            MultisigAction::UpdateMetadata { token_id, metadata } => {
+                self.owner_of(&token_id);
+                self.internal_update_metadata(&token_id, &metadata);
+                ContractEvent::NftMetadataUpdate(&[NftMetadataUpdate {
+                    token_ids: &[&token_id],
+                }])
+                .emit();
+            }
+            MultisigAction::Upgrade { code } => {
+                Promise::new(env::current_account_id()).deploy_contract(code.0);
+            }
+            MultisigAction::GrantRole { role, account_id } => {
+                self.roles.insert(&(role, account_id));
+            }
+            MultisigAction::RevokeRole { role, account_id } => {
+                self.roles.remove(&(role, account_id));
+            }
+            MultisigAction::FunctionCall {
+                method_name,
+                args,
+                deposit,
+                gas,
+            } => {
+                require!(
+                    held >= deposit,
+                    "Proposal deposit does not cover the call deposit"
+                );
+                held = held.saturating_sub(deposit);
+                Promise::new(env::current_account_id()).function_call(
+                    method_name,
+                    args.0,
+                    deposit,
+                    Gas::from_gas(gas.0),
+                );
+            }
+        }
+        self.settle_multisig_deposit(&proposal, held, initial_storage_usage);
+    }
+}
+
+#[near]
+impl Contract {
+    /// Hands privileged calls over to `approvers`, `threshold` of which must confirm each of them.
+    /// Once enabled, it can only be changed through a multisig proposal.
+    pub fn set_multisig(&mut self, approvers: Vec<AccountId>, threshold: u32) {
+        self.assert_owner();
+        require!(
+            self.tokens.owner_id != env::current_account_id(),
+            "The owner must be a separate account to hand its actions to a multisig"
+        );
+        require!(
+            self.admin_delay == 0,
+            "Multisig actions cannot be time-locked, remove the admin delay first"
+        );
+        require!(
+            threshold > 0 && threshold as usize <= approvers.len(),
+            "Threshold must be between 1 and the number of approvers"
+        );
+        let mut unique = approvers.clone();
+        unique.sort();
+        unique.dedup();
+        require!(unique.len() == approvers.len(), "Duplicate approver");
+        self.multisig = Some(MultisigConfig {
+            approvers,
+            threshold,
+        });
+    }
+
+    pub fn multisig_config(&self) -> Option<MultisigConfig> {
+        self.multisig.clone()
+    }
+
+    /// Proposes `action`, confirmed by the proposer. The attached deposit pays for the proposal;
+    /// the rest is held for the action. Returns the proposal id.
+    #[payable]
+    pub fn multisig_propose(&mut self, action: MultisigAction) -> u64 {
+        let config = self.assert_approver();
+        if let MultisigAction::FunctionCall { method_name, .. } = &action {
+            assert_timelocked_method(method_name);
+        }
+
+        let initial_storage_usage = env::storage_usage();
+        let call_deposit = match &action {
+            MultisigAction::FunctionCall { deposit, .. } => *deposit,
+            _ => NearToken::from_yoctonear(0),
+        };
+        let mut proposal = MultisigProposal {
+            id: self.next_multisig_proposal_id,
+            proposer_id: env::predecessor_account_id(),
+            action,
+            deposit: NearToken::from_yoctonear(0),
+            confirmations: vec![env::predecessor_account_id()],
+        };
+        self.next_multisig_proposal_id += 1;
+        self.multisig_proposals.insert(&proposal.id, &proposal);
+        let cost = storage_cost(env::storage_usage() - initial_storage_usage);
+        let attached = env::attached_deposit();
+        require!(
+            attached >= cost,
+            format!("Must attach {} to propose", cost.exact_amount_display())
+        );
+        proposal.deposit = attached.saturating_sub(cost);
+        require!(
+            proposal.deposit >= call_deposit,
+            format!(
+                "Must attach {} to propose",
+                cost.saturating_add(call_deposit).exact_amount_display()
+            )
+        );
+        self.multisig_proposals.insert(&proposal.id, &proposal);
+
+        if config.threshold == 1 {
+            self.execute_multisig_proposal(proposal.clone());
+        }
+        proposal.id
+    }
+
+    /// Confirms `proposal_id`, executing it once it reached the threshold. Returns whether it was
+    /// executed.
+    pub fn multisig_confirm(&mut self, proposal_id: u64) -> bool {
+        let config = self.assert_approver();
+        let mut proposal = self.multisig_proposal_or_panic(proposal_id);
+        let approver_id = env::predecessor_account_id();
+        require!(
+            !proposal.confirmations.contains(&approver_id),
+            "Already confirmed"
+        );
+        proposal.confirmations.push(approver_id);
+        // Approvers removed since they confirmed do not count.
+        let confirmations = proposal
+            .confirmations
+            .iter()
+            .filter(|account_id| config.approvers.contains(account_id))
+            .count();
+        if confirmations >= config.threshold as usize {
+            self.execute_multisig_proposal(proposal);
+            true
+        } else {
+            self.multisig_proposals.insert(&proposal_id, &proposal);
+            false
+        }
+    }
+
+    /// Withdraws a pending proposal. Only its proposer can call it.
+    pub fn multisig_cancel(&mut self, proposal_id: u64) {
+        let proposal = self.multisig_proposal_or_panic(proposal_id);
+        require!(
+            proposal.proposer_id == env::predecessor_account_id(),
+            "Only the proposer can cancel"
+        );
+        let initial_storage_usage = env::storage_usage();
+        self.multisig_proposals.remove(&proposal_id);
+        self.settle_multisig_deposit(&proposal, proposal.deposit, initial_storage_usage);
+    }
+
+    pub fn multisig_proposal(&self, proposal_id: u64) -> Option<MultisigProposal> {
+        self.multisig_proposals.get(&proposal_id)
+    }
+
+    /// Pending proposals, oldest first.
+    pub fn multisig_proposals(
+        &self,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+    ) -> Vec<MultisigProposal> {
+        let start = u128::from(from_index.unwrap_or(U128(0)));
+        self.multisig_proposals
+            .iter()
+            .skip(start as usize)
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .map(|(_, proposal)| proposal)
+            .collect()
+    }
+
+    pub fn multisig_confirmations(&self, proposal_id: u64) -> Vec<AccountId> {
+        self.multisig_proposals
+            .get(&proposal_id)
+            .map(|proposal| proposal.confirmations)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    fn call(context: &mut VMContextBuilder, predecessor_id: AccountId, deposit: NearToken) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(deposit)
+            .predecessor_account_id(predecessor_id)
+            .build());
+    }
+
+    /// Contract owned by accounts(5) with a 2-of-3 multisig of accounts(1), accounts(2) and
+    /// accounts(3), and a mint of "0" to accounts(4) proposed by accounts(1).
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        let mut contract = Contract::new_default_meta(accounts(5));
+        contract.set_multisig(vec![accounts(1), accounts(2), accounts(3)], 2);
+        call(context, accounts(1), MINT_STORAGE_COST);
+        contract.multisig_propose(MultisigAction::Mint {
+            token_id: "0".to_string(),
+            receiver_id: accounts(4),
+            metadata: sample_token_metadata(),
+        });
+        contract
+    }
+
+    fn confirm(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        approver: AccountId,
+    ) -> bool {
+        call(context, approver, NearToken::from_yoctonear(0));
+        contract.multisig_confirm(0)
+    }
+
+    #[test]
+    fn test_mint_executes_on_threshold() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        assert!(contract.nft_token("0".to_string()).is_none());
+        assert_eq!(contract.multisig_confirmations(0), vec![accounts(1)]);
+
+        assert!(confirm(&mut context, &mut contract, accounts(2)));
+        assert_eq!(
+            contract.nft_token("0".to_string()).unwrap().owner_id,
+            accounts(4)
+        );
+        assert!(contract.multisig_proposals(None, None).is_empty());
+    }
+
+    #[test]
+    fn test_update_metadata() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        confirm(&mut context, &mut contract, accounts(2));
+
+        let metadata = TokenMetadata {
+            title: Some("Mount Everest".into()),
+            ..sample_token_metadata()
+        };
+        call(&mut context, accounts(2), MINT_STORAGE_COST);
+        contract.multisig_propose(MultisigAction::UpdateMetadata {
+            token_id: "0".to_string(),
+            metadata: metadata.clone(),
+        });
+        call(&mut context, accounts(3), NearToken::from_yoctonear(0));
+        assert!(contract.multisig_confirm(1));
+        assert_eq!(
+            contract.nft_token("0".to_string()).unwrap().metadata,
+            Some(metadata)
+        );
+        assert!(get_logs()[0].contains("\"event\":\"nft_metadata_update\""));
+    }
+
+    #[test]
+    #[should_panic(expected = "Already confirmed")]
+    fn test_confirm_twice() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        confirm(&mut context, &mut contract, accounts(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Predecessor is not an approver")]
+    fn test_confirm_by_non_approver() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        confirm(&mut context, &mut contract, accounts(4));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "The owner must be a separate account to hand its actions to a multisig"
+    )]
+    fn test_multisig_with_contract_as_owner() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        contract.set_multisig(vec![accounts(1), accounts(2)], 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Multisig actions cannot be time-locked")]
+    fn test_multisig_with_admin_delay() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        let mut contract = Contract::new_default_meta(accounts(5));
+        contract.set_admin_delay(U64(1_000));
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_multisig(vec![accounts(1), accounts(2)], 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Must attach")]
+    fn test_function_call_deposit_not_covered() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), MINT_STORAGE_COST);
+        contract.multisig_propose(MultisigAction::FunctionCall {
+            method_name: "sponsor_pool_deposit".to_string(),
+            args: Base64VecU8(b"{}".to_vec()),
+            deposit: NearToken::from_near(10),
+            gas: U64(Gas::from_tgas(10).as_gas()),
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "nft_transfer is not an owner-only method")]
+    fn test_function_call_to_non_owner_method() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), MINT_STORAGE_COST);
+        contract.multisig_propose(MultisigAction::FunctionCall {
+            method_name: "nft_transfer".to_string(),
+            args: Base64VecU8(b"{}".to_vec()),
+            deposit: NearToken::from_yoctonear(1),
+            gas: U64(Gas::from_tgas(10).as_gas()),
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "Owner actions require multisig confirmation")]
+    fn test_owner_key_alone_is_rejected() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(5), NearToken::from_yoctonear(0));
+        contract.grant_role(Role::Minter, accounts(4));
+    }
+}
@@ -0,0 +1,264 @@
+/*!
+Gasless approvals signed off-chain (permits).
+
+A token owner without gas signs a [NEP-413](https://github.com/near/NEPs/blob/master/neps/nep-0413.md)
+payload with the key registered through `register_signing_key`, and anyone, typically the
+spender, submits it with `nft_permit` to approve the spender as `nft_approve` would. The signed
+message is the JSON returned by `permit_message`, the recipient is this contract and each 32-byte
+nonce can only be used once per owner.
+*/
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::{
+    bytes_for_approved_account_id, refund_deposit, TokenId,
+};
+use near_sdk::json_types::{Base64VecU8, U64};
+use near_sdk::serde::Serialize;
+use near_sdk::{env, near, require, AccountId, PublicKey};
+use std::convert::TryFrom;
+
+/// Prefix of NEP-413 payloads, `2^31 + 413`, so they can never be valid transactions.
+const NEP413_TAG: u32 = (1 << 31) + 413;
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct PermitMessage<'a> {
+    token_id: &'a TokenId,
+    spender: &'a AccountId,
+    deadline: U64,
+}
+
+#[near(serializers = [borsh])]
+struct Nep413Payload {
+    message: String,
+    nonce: [u8; 32],
+    recipient: String,
+    callback_url: Option<String>,
+}
+
+fn build_permit_message(token_id: &TokenId, spender: &AccountId, deadline: U64) -> String {
+    near_sdk::serde_json::to_string(&PermitMessage {
+        token_id,
+        spender,
+        deadline,
+    })
+    .unwrap()
+}
+
+/// Hash of the NEP-413 payload signed for a permit, with this contract as recipient.
+fn permit_payload_hash(message: String, nonce: [u8; 32]) -> Vec<u8> {
+    let mut bytes = near_sdk::borsh::to_vec(&NEP413_TAG).unwrap();
+    bytes.extend(
+        near_sdk::borsh::to_vec(&Nep413Payload {
+            message,
+            nonce,
+            recipient: env::current_account_id().to_string(),
+            callback_url: None,
+        })
+        .unwrap(),
+    );
+    env::sha256(&bytes)
+}
+
+#[near]
+impl Contract {
+    /// Approves `spender` for `token_id` on behalf of its owner, who signed the permit with the
+    /// registered `public_key`. `deadline` is a timestamp in nanoseconds. The storage of the
+    /// approval and of the used nonce is paid from the attached deposit. Returns the approval id.
+    #[payable]
+    pub fn nft_permit(
+        &mut self,
+        token_id: TokenId,
+        spender: AccountId,
+        deadline: U64,
+        nonce: Base64VecU8,
+        public_key: PublicKey,
+        signature: Base64VecU8,
+    ) -> U64 {
+        let owner_id = self.owner_of(&token_id);
+        self.assert_not_frozen(&token_id);
+        self.assert_not_denied(&spender);
+        require!(env::block_timestamp() <= deadline.0, "Permit expired");
+        let nonce = <[u8; 32]>::try_from(nonce.0.as_slice())
+            .unwrap_or_else(|_| env::panic_str("Nonce must be 32 bytes"));
+        require!(
+            self.signing_keys.get(&owner_id).as_ref() == Some(&public_key),
+            "Public key is not registered by the token owner"
+        );
+        let hash = permit_payload_hash(build_permit_message(&token_id, &spender, deadline), nonce);
+        require!(
+            self.is_signed_by(&owner_id, &hash, &signature),
+            "Invalid permit signature"
+        );
+
+        let initial_storage_usage = env::storage_usage();
+        require!(
+            self.permit_nonces.insert(&(owner_id, nonce)),
+            "Nonce already used"
+        );
+        let nonce_bytes = env::storage_usage() - initial_storage_usage;
+        let approvals_by_id = self.tokens.approvals_by_id.as_mut().unwrap();
+        let next_approval_id_by_id = self.tokens.next_approval_id_by_id.as_mut().unwrap();
+        let mut approved_account_ids = approvals_by_id.get(&token_id).unwrap_or_default();
+        let approval_id = next_approval_id_by_id.get(&token_id).unwrap_or(1);
+        let old_approval_id = approved_account_ids.insert(spender.clone(), approval_id);
+        approvals_by_id.insert(&token_id, &approved_account_ids);
+        next_approval_id_by_id.insert(&token_id, &(approval_id + 1));
+        // As in `nft_approve`, approvals are charged by their logical size.
+        let approval_bytes = if old_approval_id.is_none() {
+            bytes_for_approved_account_id(&spender)
+        } else {
+            0
+        };
+        refund_deposit(nonce_bytes + approval_bytes);
+        approval_id.into()
+    }
+
+    /// Message the owner of `token_id` signs to permit `spender` until `deadline`.
+    pub fn permit_message(&self, token_id: TokenId, spender: AccountId, deadline: U64) -> String {
+        build_permit_message(&token_id, &spender, deadline)
+    }
+
+    pub fn is_permit_nonce_used(&self, owner_id: AccountId, nonce: Base64VecU8) -> bool {
+        <[u8; 32]>::try_from(nonce.0.as_slice())
+            .is_ok_and(|nonce| self.permit_nonces.contains(&(owner_id, nonce)))
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use ed25519_dalek::{Signer, SigningKey};
+    use near_contract_standards::non_fungible_token::approval::NonFungibleTokenApproval;
+
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, CurveType, NearToken};
+
+    use super::*;
+
+    const PERMIT_STORAGE_COST: NearToken = NearToken::from_millinear(10);
+    const DEADLINE: U64 = U64(1_000);
+
+    fn call(context: &mut VMContextBuilder, predecessor_id: AccountId, deposit: NearToken) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(deposit)
+            .predecessor_account_id(predecessor_id)
+            .build());
+    }
+
+    fn owner_key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn owner_public_key() -> PublicKey {
+        PublicKey::from_parts(
+            CurveType::ED25519,
+            owner_key().verifying_key().to_bytes().to_vec(),
+        )
+        .unwrap()
+    }
+
+    /// Signs the NEP-413 payload permitting `spender` to transfer `token_id`, as a wallet would.
+    fn sign_permit(
+        key: &SigningKey,
+        token_id: &str,
+        spender: &AccountId,
+        deadline: U64,
+        nonce: [u8; 32],
+    ) -> Base64VecU8 {
+        let message = build_permit_message(&token_id.to_string(), spender, deadline);
+        let hash = permit_payload_hash(message, nonce);
+        Base64VecU8(key.sign(&hash).to_bytes().to_vec())
+    }
+
+    /// Token "0" owned by accounts(1), who registered `owner_key`.
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        call(context, accounts(0), MINT_STORAGE_COST);
+        contract.nft_mint("0".to_string(), accounts(1), sample_token_metadata(), None);
+        call(context, accounts(1), PERMIT_STORAGE_COST);
+        contract.register_signing_key(owner_public_key());
+        contract
+    }
+
+    /// accounts(2) submits a permit for itself signed with `key`.
+    fn permit(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        key: &SigningKey,
+        nonce: [u8; 32],
+    ) -> U64 {
+        let signature = sign_permit(key, "0", &accounts(2), DEADLINE, nonce);
+        call(context, accounts(2), PERMIT_STORAGE_COST);
+        contract.nft_permit(
+            "0".to_string(),
+            accounts(2),
+            DEADLINE,
+            Base64VecU8(nonce.to_vec()),
+            owner_public_key(),
+            signature,
+        )
+    }
+
+    #[test]
+    fn test_permit_approves_spender() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        assert_eq!(
+            permit(&mut context, &mut contract, &owner_key(), [1; 32]),
+            U64(1)
+        );
+        assert!(contract.nft_is_approved("0".to_string(), accounts(2), Some(1)));
+        assert!(contract.is_permit_nonce_used(accounts(1), Base64VecU8(vec![1; 32])));
+        assert!(!contract.is_permit_nonce_used(accounts(1), Base64VecU8(vec![2; 32])));
+    }
+
+    #[test]
+    #[should_panic(expected = "Nonce already used")]
+    fn test_permit_replay() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        permit(&mut context, &mut contract, &owner_key(), [1; 32]);
+        permit(&mut context, &mut contract, &owner_key(), [1; 32]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid permit signature")]
+    fn test_permit_signed_by_other_key() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        permit(
+            &mut context,
+            &mut contract,
+            &SigningKey::from_bytes(&[8; 32]),
+            [1; 32],
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid permit signature")]
+    fn test_permit_for_other_spender() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        let signature = sign_permit(&owner_key(), "0", &accounts(2), DEADLINE, [1; 32]);
+        call(&mut context, accounts(3), PERMIT_STORAGE_COST);
+        contract.nft_permit(
+            "0".to_string(),
+            accounts(3),
+            DEADLINE,
+            Base64VecU8(vec![1; 32]),
+            owner_public_key(),
+            signature,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Permit expired")]
+    fn test_permit_after_deadline() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        context.block_timestamp(DEADLINE.0 + 1);
+        permit(&mut context, &mut contract, &owner_key(), [1; 32]);
+    }
+}
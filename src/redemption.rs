@@ -0,0 +1,336 @@
+/*!
+Ticket redemption.
+
+Accounts with the `Verifier` role mark tokens as used at the door with `nft_redeem`. The verifier
+either vouches that the holder is present, or passes a signature by the holder's registered
+signing key (see `register_signing_key`) of the message returned by `redemption_message`.
+
+A token can only be redeemed once. When the owner enabled `redemption_lock`, redeemed tokens can
+no longer be transferred. Redemptions are kept in the order they happened, even once the token is
+burnt, but a burnt id minted again starts out unredeemed.
+*/
+use crate::events::{ContractEvent, NftRedeem};
+use crate::roles::Role;
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::{refund_deposit, TokenId};
+use near_sdk::json_types::{Base64VecU8, U128, U64};
+use near_sdk::{env, near, require, AccountId};
+
+/// How the holder of a ticket proved they are at the door.
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum RedemptionProof {
+    /// The verifier checked the holder in person.
+    Presence,
+    /// Signature of `redemption_message(token_id)` by the holder's registered signing key.
+    OwnerSignature { signature: Base64VecU8 },
+}
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RedemptionMethod {
+    Presence,
+    OwnerSignature,
+}
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Redemption {
+    pub token_id: TokenId,
+    pub owner_id: AccountId,
+    pub verifier_id: AccountId,
+    pub method: RedemptionMethod,
+    /// Block timestamp in nanoseconds.
+    pub redeemed_at: U64,
+}
+
+fn build_redemption_message(token_id: &TokenId) -> String {
+    format!("nft_redeem:{}:{}", env::current_account_id(), token_id)
+}
+
+impl Contract {
+    pub(crate) fn assert_not_locked_by_redemption(&self, token_id: &TokenId) {
+        if self.redemption_lock {
+            require!(
+                !self.redemption_index.contains_key(token_id),
+                "Token was redeemed"
+            );
+        }
+    }
+
+    /// Forgets that `token_id` was redeemed, keeping the redemption itself. Called when the
+    /// token is burnt.
+    pub(crate) fn remove_redemption_index(&mut self, token_id: &TokenId) {
+        self.redemption_index.remove(token_id);
+    }
+}
+
+#[near]
+impl Contract {
+    /// Marks `token_id` as redeemed. Only verifiers can call it. The storage of the redemption is
+    /// paid from the attached deposit.
+    #[payable]
+    pub fn nft_redeem(&mut self, token_id: TokenId, proof: RedemptionProof) -> Redemption {
+        self.assert_role(Role::Verifier);
+        let owner_id = self.owner_of(&token_id);
+        require!(
+            !self.redemption_index.contains_key(&token_id),
+            "Token already redeemed"
+        );
+        let method = match proof {
+            RedemptionProof::Presence => RedemptionMethod::Presence,
+            RedemptionProof::OwnerSignature { signature } => {
+                require!(
+                    self.is_signed_by(
+                        &owner_id,
+                        build_redemption_message(&token_id).as_bytes(),
+                        &signature
+                    ),
+                    "Invalid owner signature"
+                );
+                RedemptionMethod::OwnerSignature
+            }
+        };
+
+        let initial_storage_usage = env::storage_usage();
+        let redemption = Redemption {
+            token_id,
+            owner_id,
+            verifier_id: env::predecessor_account_id(),
+            method,
+            redeemed_at: env::block_timestamp().into(),
+        };
+        self.redemption_index
+            .insert(&redemption.token_id, &self.redemptions.len());
+        self.redemptions.push(&redemption);
+        refund_deposit(env::storage_usage() - initial_storage_usage);
+
+        ContractEvent::NftRedeem(&[NftRedeem {
+            owner_id: &redemption.owner_id,
+            token_id: &redemption.token_id,
+            verifier_id: &redemption.verifier_id,
+        }])
+        .emit();
+        redemption
+    }
+
+    /// Makes redeemed tokens non-transferable, or transferable again.
+    pub fn set_redemption_lock(&mut self, enabled: bool) {
+        self.assert_owner();
+        self.redemption_lock = enabled;
+    }
+
+    pub fn redemption_lock(&self) -> bool {
+        self.redemption_lock
+    }
+
+    /// Message the holder signs to redeem `token_id` with `RedemptionProof::OwnerSignature`.
+    pub fn redemption_message(&self, token_id: TokenId) -> String {
+        build_redemption_message(&token_id)
+    }
+
+    pub fn nft_is_redeemed(&self, token_id: TokenId) -> bool {
+        self.redemption_index.contains_key(&token_id)
+    }
+
+    pub fn nft_redemption(&self, token_id: TokenId) -> Option<Redemption> {
+        self.redemption_index
+            .get(&token_id)
+            .and_then(|index| self.redemptions.get(index))
+    }
+
+    /// Redemptions in the order they happened.
+    pub fn nft_redemptions(&self, from_index: Option<U128>, limit: Option<u64>) -> Vec<Redemption> {
+        let start = u128::from(from_index.unwrap_or(U128(0))) as u64;
+        (start..self.redemptions.len())
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .filter_map(|index| self.redemptions.get(index))
+            .collect()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use ed25519_dalek::{Signer, SigningKey};
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, CurveType, NearToken, PublicKey};
+
+    use super::*;
+
+    const REDEMPTION_STORAGE_COST: NearToken = NearToken::from_millinear(10);
+    const ONE_YOCTONEAR: NearToken = NearToken::from_yoctonear(1);
+
+    fn call(context: &mut VMContextBuilder, predecessor_id: AccountId, deposit: NearToken) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(deposit)
+            .predecessor_account_id(predecessor_id)
+            .build());
+    }
+
+    /// Tickets "0" and "1" owned by accounts(1), with accounts(2) as a verifier.
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        contract.grant_role(Role::Verifier, accounts(2));
+        for token_id in ["0", "1"] {
+            call(context, accounts(0), MINT_STORAGE_COST);
+            contract.nft_mint(
+                token_id.to_string(),
+                accounts(1),
+                sample_token_metadata(),
+                None,
+            );
+        }
+        contract
+    }
+
+    fn redeem(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        token_id: &str,
+        proof: RedemptionProof,
+    ) -> Redemption {
+        call(context, accounts(2), REDEMPTION_STORAGE_COST);
+        contract.nft_redeem(token_id.to_string(), proof)
+    }
+
+    fn holder_key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn register_holder_key(context: &mut VMContextBuilder, contract: &mut Contract) {
+        call(context, accounts(1), REDEMPTION_STORAGE_COST);
+        contract.register_signing_key(
+            PublicKey::from_parts(
+                CurveType::ED25519,
+                holder_key().verifying_key().to_bytes().to_vec(),
+            )
+            .unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_redeem_in_person() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        context.block_timestamp(42);
+
+        let redemption = redeem(&mut context, &mut contract, "0", RedemptionProof::Presence);
+        assert_eq!(redemption.verifier_id, accounts(2));
+        assert_eq!(redemption.owner_id, accounts(1));
+        assert_eq!(redemption.redeemed_at, U64(42));
+        assert!(contract.nft_is_redeemed("0".to_string()));
+        assert!(!contract.nft_is_redeemed("1".to_string()));
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.starts_with("EVENT_JSON:") && log.contains("\"event\":\"nft_redeem\"")));
+    }
+
+    #[test]
+    fn test_redeem_with_owner_signature() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        register_holder_key(&mut context, &mut contract);
+
+        let message = contract.redemption_message("0".to_string());
+        let signature = holder_key().sign(message.as_bytes()).to_bytes().to_vec();
+        let redemption = redeem(
+            &mut context,
+            &mut contract,
+            "0",
+            RedemptionProof::OwnerSignature {
+                signature: signature.into(),
+            },
+        );
+        assert_eq!(redemption.method, RedemptionMethod::OwnerSignature);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid owner signature")]
+    fn test_redeem_with_signature_for_other_token() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        register_holder_key(&mut context, &mut contract);
+
+        let message = contract.redemption_message("1".to_string());
+        let signature = holder_key().sign(message.as_bytes()).to_bytes().to_vec();
+        redeem(
+            &mut context,
+            &mut contract,
+            "0",
+            RedemptionProof::OwnerSignature {
+                signature: signature.into(),
+            },
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Token already redeemed")]
+    fn test_redeem_twice() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        redeem(&mut context, &mut contract, "0", RedemptionProof::Presence);
+        redeem(&mut context, &mut contract, "0", RedemptionProof::Presence);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unauthorized")]
+    fn test_redeem_by_non_verifier() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), REDEMPTION_STORAGE_COST);
+        contract.nft_redeem("0".to_string(), RedemptionProof::Presence);
+    }
+
+    #[test]
+    #[should_panic(expected = "Token was redeemed")]
+    fn test_redemption_lock_blocks_transfer() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        contract.set_redemption_lock(true);
+        redeem(&mut context, &mut contract, "0", RedemptionProof::Presence);
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_transfer(accounts(3), "0".to_string(), None, None);
+    }
+
+    #[test]
+    fn test_reminted_token_is_not_redeemed() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        redeem(&mut context, &mut contract, "0", RedemptionProof::Presence);
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_burn("0".to_string());
+
+        call(&mut context, accounts(0), MINT_STORAGE_COST);
+        contract.nft_mint("0".to_string(), accounts(3), sample_token_metadata(), None);
+        assert!(!contract.nft_is_redeemed("0".to_string()));
+        assert_eq!(contract.nft_redemption("0".to_string()), None);
+        assert_eq!(contract.nft_redemptions(None, None).len(), 1);
+    }
+
+    #[test]
+    fn test_nft_redemptions() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        redeem(&mut context, &mut contract, "1", RedemptionProof::Presence);
+        redeem(&mut context, &mut contract, "0", RedemptionProof::Presence);
+
+        // Without the lock, redeemed tickets can still change hands.
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_transfer(accounts(3), "0".to_string(), None, None);
+
+        let token_ids: Vec<TokenId> = contract
+            .nft_redemptions(None, None)
+            .into_iter()
+            .map(|redemption| redemption.token_id)
+            .collect();
+        assert_eq!(token_ids, vec!["1", "0"]);
+        let page = contract.nft_redemptions(Some(U128(1)), Some(1));
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].token_id, "0");
+    }
+}
@@ -0,0 +1,264 @@
+/*!
+On-chain SVG rendering.
+
+The owner sets a canvas with `set_svg_canvas`, listing trait types from the bottom layer to the
+top one, and uploads an SVG fragment for each `(trait_type, value)` with `upload_svg_layers`.
+A token minted with attributes (see `attributes`) renders as the fragments of its values stacked
+in canvas order, wrapped in an `<svg>` element. Values without a fragment are left out.
+
+`nft_render` returns the image as a `data:image/svg+xml` URI, like `DATA_IMAGE_SVG_NEAR_ICON`.
+`nft_token` and the enumeration views also return it as `media` for tokens minted without one.
+*/
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::{refund_deposit, Token, TokenId};
+use near_sdk::{env, near, require};
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SvgCanvas {
+    pub width: u32,
+    pub height: u32,
+    /// Trait types, from the bottom layer to the top one.
+    pub layer_order: Vec<String>,
+}
+
+#[near(serializers = [json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SvgLayer {
+    pub trait_type: String,
+    pub value: String,
+    /// SVG elements drawn for this value, without the enclosing `<svg>`.
+    pub svg: String,
+}
+
+/// Percent-encodes the characters which are not safe as is in a data URI.
+fn svg_data_uri(svg: &str) -> String {
+    let mut uri = "data:image/svg+xml,".to_string();
+    for c in svg.chars() {
+        match c {
+            '%' | '#' | '<' | '>' | '"' | '{' | '}' | '|' | '\\' | '^' | '`' | '\n' | '\r'
+            | '\t' => uri.push_str(&format!("%{:02X}", c as u32)),
+            c => uri.push(c),
+        }
+    }
+    uri
+}
+
+impl Contract {
+    /// SVG of `token_id`, or `None` without a canvas or if the token has no attributes.
+    fn render_svg(&self, token_id: &TokenId) -> Option<String> {
+        let canvas = self.svg_canvas.as_ref()?;
+        let attributes = self.token_attributes.get(token_id)?;
+        let mut svg = format!(
+            "<svg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 {} {}'>",
+            canvas.width, canvas.height
+        );
+        for trait_type in &canvas.layer_order {
+            let layer = attributes
+                .iter()
+                .find(|attribute| &attribute.trait_type == trait_type)
+                .and_then(|attribute| {
+                    self.svg_layers
+                        .get(&(trait_type.clone(), attribute.value.clone()))
+                });
+            if let Some(layer) = layer {
+                svg.push_str(&layer);
+            }
+        }
+        svg.push_str("</svg>");
+        Some(svg)
+    }
+
+    /// Fills in the rendered image as `media` if the token has none.
+    pub(crate) fn with_rendered_media(&self, mut token: Token) -> Token {
+        if let Some(metadata) = token.metadata.as_mut() {
+            if metadata.media.is_none() {
+                metadata.media = self
+                    .render_svg(&token.token_id)
+                    .map(|svg| svg_data_uri(&svg));
+            }
+        }
+        token
+    }
+}
+
+#[near]
+impl Contract {
+    /// Sets the size of the image and the order in which the layers are drawn.
+    pub fn set_svg_canvas(&mut self, width: u32, height: u32, layer_order: Vec<String>) {
+        self.assert_owner();
+        require!(width > 0 && height > 0, "Canvas must not be empty");
+        self.svg_canvas = Some(SvgCanvas {
+            width,
+            height,
+            layer_order,
+        });
+    }
+
+    pub fn svg_canvas(&self) -> Option<SvgCanvas> {
+        self.svg_canvas.clone()
+    }
+
+    /// Adds or replaces layers, in as many chunks as needed. The storage is paid from the
+    /// attached deposit.
+    #[payable]
+    pub fn upload_svg_layers(&mut self, layers: Vec<SvgLayer>) {
+        self.assert_owner();
+        let initial_storage_usage = env::storage_usage();
+        for layer in layers {
+            self.svg_layers
+                .insert(&(layer.trait_type, layer.value), &layer.svg);
+        }
+        refund_deposit(env::storage_usage().saturating_sub(initial_storage_usage));
+    }
+
+    pub fn remove_svg_layer(&mut self, trait_type: String, value: String) {
+        self.assert_owner();
+        self.svg_layers.remove(&(trait_type, value));
+    }
+
+    pub fn svg_layer(&self, trait_type: String, value: String) -> Option<String> {
+        self.svg_layers.get(&(trait_type, value))
+    }
+
+    /// Image of `token_id` as a `data:image/svg+xml` URI, if it can be rendered on chain.
+    pub fn nft_render(&self, token_id: TokenId) -> Option<String> {
+        self.owner_of(&token_id);
+        self.render_svg(&token_id).map(|svg| svg_data_uri(&svg))
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::attributes::Attribute;
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+    use near_contract_standards::non_fungible_token::metadata::TokenMetadata;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    fn token_metadata(media: Option<&str>) -> TokenMetadata {
+        TokenMetadata {
+            media: media.map(str::to_string),
+            ..sample_token_metadata()
+        }
+    }
+
+    fn layer(trait_type: &str, value: &str, svg: &str) -> SvgLayer {
+        SvgLayer {
+            trait_type: trait_type.to_string(),
+            value: value.to_string(),
+            svg: svg.to_string(),
+        }
+    }
+
+    fn mint(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        token_id: &str,
+        media: Option<&str>,
+    ) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .build());
+        contract.nft_mint_with_attributes(
+            token_id.to_string(),
+            accounts(1),
+            token_metadata(media),
+            vec![
+                Attribute {
+                    trait_type: "eyes".to_string(),
+                    value: "laser".to_string(),
+                },
+                Attribute {
+                    trait_type: "background".to_string(),
+                    value: "red".to_string(),
+                },
+            ],
+        );
+    }
+
+    /// A 10x10 canvas with a background and an eyes layer, and token "0" with both.
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        contract.set_svg_canvas(10, 10, vec!["background".to_string(), "eyes".to_string()]);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .build());
+        contract.upload_svg_layers(vec![
+            layer(
+                "background",
+                "red",
+                "<rect width='10' height='10' fill='#f00'/>",
+            ),
+            layer("eyes", "laser", "<circle cx='5' cy='5' r='1'/>"),
+        ]);
+        mint(context, &mut contract, "0", None);
+        contract
+    }
+
+    #[test]
+    fn test_render_layers_in_order() {
+        let mut context = get_context(accounts(0));
+        let contract = setup(&mut context);
+        assert_eq!(
+            contract.nft_render("0".to_string()).unwrap(),
+            "data:image/svg+xml,%3Csvg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 10 10'%3E\
+             %3Crect width='10' height='10' fill='%23f00'/%3E%3Ccircle cx='5' cy='5' r='1'/%3E\
+             %3C/svg%3E"
+        );
+    }
+
+    #[test]
+    fn test_missing_layer_is_skipped() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        contract.remove_svg_layer("eyes".to_string(), "laser".to_string());
+        let uri = contract.nft_render("0".to_string()).unwrap();
+        assert!(uri.contains("rect"));
+        assert!(!uri.contains("circle"));
+    }
+
+    #[test]
+    fn test_media_in_nft_token() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        mint(&mut context, &mut contract, "1", Some("ipfs://image"));
+        let media = |token_id: &str| {
+            contract
+                .nft_token(token_id.to_string())
+                .unwrap()
+                .metadata
+                .unwrap()
+                .media
+        };
+        assert_eq!(media("0"), contract.nft_render("0".to_string()));
+        assert_eq!(media("1"), Some("ipfs://image".to_string()));
+    }
+
+    #[test]
+    fn test_no_render_without_attributes() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .build());
+        contract.nft_mint("2".to_string(), accounts(1), token_metadata(None), None);
+        assert_eq!(contract.nft_render("2".to_string()), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unauthorized")]
+    fn test_upload_by_non_owner() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.upload_svg_layers(vec![layer("eyes", "blue", "<circle/>")]);
+    }
+}
@@ -0,0 +1,428 @@
+/*!
+Randomized reveal for mystery-box drops.
+
+Tokens `"0"` to `"<size - 1>"` are minted with placeholder metadata. Beforehand, the owner commits
+to the final metadata set with `commit_reveal`. In a later block, `reveal` draws a random offset
+from `env::random_seed`, so the owner cannot pick which token gets which entry: token `i` gets
+entry `(i + offset) % size`. The owner then uploads the entries in order with
+`upload_reveal_metadata`; the upload is only accepted if it matches the commitment. A partial
+upload that went wrong can be dropped with `reset_reveal_upload` and started over. From then on
+anyone can `apply_reveal` to tokens to replace their placeholder metadata, which must pass the
+`metadata_rules` like any minted metadata.
+
+Once the offset is drawn, the ids of the drop can no longer be minted: everyone can then tell
+which entry each of them gets, so a minter could pick.
+
+The commitment is a hash chain over the entries,
+`h_{i+1} = sha256(h_i || sha256(borsh(entry_i)))` starting from 32 zero bytes, so that the entries
+can be checked chunk by chunk. The `reveal_commitment` view computes it.
+*/
+use crate::events::{ContractEvent, NftMetadataUpdate};
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::metadata::TokenMetadata;
+use near_contract_standards::non_fungible_token::{refund_deposit, TokenId};
+use near_sdk::json_types::{Base64VecU8, U64};
+use near_sdk::{env, near, require};
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RevealStage {
+    /// Waiting for `reveal`.
+    Committed,
+    /// Offset drawn, waiting for the metadata upload.
+    Revealed,
+    /// Metadata uploaded and checked against the commitment.
+    Verified,
+}
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reveal {
+    pub stage: RevealStage,
+    pub commitment: Base64VecU8,
+    pub size: u64,
+    pub committed_at_block: U64,
+    /// Seed of the block which drew the offset.
+    pub random_seed: Option<Base64VecU8>,
+    pub offset: Option<u64>,
+    /// Number of entries uploaded so far.
+    pub uploaded: u64,
+    /// Hash chain of the uploaded entries.
+    pub uploaded_hash: Base64VecU8,
+}
+
+fn chain_hash(previous: &[u8], entry: &TokenMetadata) -> Vec<u8> {
+    let mut bytes = previous.to_vec();
+    bytes.extend(env::sha256(&near_sdk::borsh::to_vec(entry).unwrap()));
+    env::sha256(&bytes)
+}
+
+impl Contract {
+    fn reveal_or_panic(&self) -> Reveal {
+        self.reveal
+            .clone()
+            .unwrap_or_else(|| env::panic_str("No reveal was committed"))
+    }
+
+    /// Index of the revealed entry of `token_id` once the offset is drawn.
+    fn reveal_index(&self, token_id: &TokenId) -> Option<u64> {
+        let reveal = self.reveal.as_ref()?;
+        let index: u64 = token_id.parse().ok()?;
+        // Only the canonical form counts, "01" or "+1" are other tokens than "1".
+        if index >= reveal.size || token_id != &index.to_string() {
+            return None;
+        }
+        reveal.offset.map(|offset| (index + offset) % reveal.size)
+    }
+
+    /// Panics if `token_id` is part of a drop whose offset was already drawn.
+    pub(crate) fn assert_not_revealed_id(&self, token_id: &TokenId) {
+        require!(
+            self.reveal_index(token_id).is_none(),
+            "Token ids of a revealed drop can no longer be minted"
+        );
+    }
+}
+
+#[near]
+impl Contract {
+    /// Commits to the `size` entries of the final metadata set, see `reveal_commitment`.
+    pub fn commit_reveal(&mut self, commitment: Base64VecU8, size: u64) {
+        self.assert_owner();
+        require!(self.reveal.is_none(), "A reveal was already committed");
+        require!(commitment.0.len() == 32, "Commitment must be 32 bytes");
+        require!(size > 0, "Size must not be zero");
+        self.reveal = Some(Reveal {
+            stage: RevealStage::Committed,
+            commitment,
+            size,
+            committed_at_block: env::block_height().into(),
+            random_seed: None,
+            offset: None,
+            uploaded: 0,
+            uploaded_hash: Base64VecU8(vec![0; 32]),
+        });
+    }
+
+    /// Draws the offset from the random seed of the current block, which must come after the
+    /// commitment. Anyone can call it.
+    pub fn reveal(&mut self) -> u64 {
+        let mut reveal = self.reveal_or_panic();
+        require!(
+            reveal.stage == RevealStage::Committed,
+            "Reveal already happened"
+        );
+        require!(
+            env::block_height() > reveal.committed_at_block.0,
+            "Reveal must happen in a later block than the commitment"
+        );
+        let seed = env::random_seed();
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&seed[..8]);
+        let offset = u64::from_le_bytes(bytes) % reveal.size;
+        reveal.stage = RevealStage::Revealed;
+        reveal.random_seed = Some(Base64VecU8(seed));
+        reveal.offset = Some(offset);
+        self.reveal = Some(reveal);
+        offset
+    }
+
+    /// Uploads the next entries of the committed metadata set. The last chunk must complete a
+    /// set matching the commitment. The storage is paid from the attached deposit.
+    #[payable]
+    pub fn upload_reveal_metadata(&mut self, entries: Vec<TokenMetadata>) {
+        self.assert_owner();
+        let mut reveal = self.reveal_or_panic();
+        require!(
+            reveal.stage == RevealStage::Revealed,
+            "Metadata can only be uploaded after the reveal"
+        );
+        require!(
+            reveal.uploaded + entries.len() as u64 <= reveal.size,
+            "Too many entries"
+        );
+
+        let initial_storage_usage = env::storage_usage();
+        for entry in entries {
+            reveal.uploaded_hash = Base64VecU8(chain_hash(&reveal.uploaded_hash.0, &entry));
+            self.reveal_entries.push(&entry);
+            reveal.uploaded += 1;
+        }
+        if reveal.uploaded == reveal.size {
+            require!(
+                reveal.uploaded_hash == reveal.commitment,
+                "Metadata does not match the commitment"
+            );
+            reveal.stage = RevealStage::Verified;
+        }
+        self.reveal = Some(reveal);
+        refund_deposit(env::storage_usage().saturating_sub(initial_storage_usage));
+    }
+
+    /// Drops the entries uploaded so far, so that the upload can start over. Only possible until
+    /// the upload completed. Released storage stays with the contract.
+    pub fn reset_reveal_upload(&mut self) {
+        self.assert_owner();
+        let mut reveal = self.reveal_or_panic();
+        require!(
+            reveal.stage == RevealStage::Revealed,
+            "Upload can only be reset before it completed"
+        );
+        self.reveal_entries.clear();
+        reveal.uploaded = 0;
+        reveal.uploaded_hash = Base64VecU8(vec![0; 32]);
+        self.reveal = Some(reveal);
+    }
+
+    /// Replaces the placeholder metadata of `token_ids` with their revealed entries. Anyone can
+    /// call it; added storage is paid from the attached deposit.
+    #[payable]
+    pub fn apply_reveal(&mut self, token_ids: Vec<TokenId>) {
+        let reveal = self.reveal_or_panic();
+        require!(
+            reveal.stage == RevealStage::Verified,
+            "Metadata was not verified yet"
+        );
+        let initial_storage_usage = env::storage_usage();
+        for token_id in &token_ids {
+            self.owner_of(token_id);
+            let index = self
+                .reveal_index(token_id)
+                .unwrap_or_else(|| env::panic_str("Token is not part of the reveal"));
+            let metadata = self.reveal_entries.get(index).unwrap();
+            self.metadata_rules.assert_valid(&metadata);
+            self.internal_update_metadata(token_id, &metadata);
+        }
+        refund_deposit(env::storage_usage().saturating_sub(initial_storage_usage));
+        ContractEvent::NftMetadataUpdate(&[NftMetadataUpdate {
+            token_ids: &token_ids.iter().collect::<Vec<_>>(),
+        }])
+        .emit();
+    }
+
+    pub fn reveal_status(&self) -> Option<Reveal> {
+        self.reveal.clone()
+    }
+
+    /// Index in the metadata set of the entry `token_id` reveals to, once the offset is drawn.
+    pub fn nft_reveal_index(&self, token_id: TokenId) -> Option<u64> {
+        self.reveal_index(&token_id)
+    }
+
+    /// Commitment to `entries`, in order, as expected by `commit_reveal`.
+    pub fn reveal_commitment(&self, entries: Vec<TokenMetadata>) -> Base64VecU8 {
+        Base64VecU8(
+            entries
+                .iter()
+                .fold(vec![0; 32], |hash, entry| chain_hash(&hash, entry)),
+        )
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    const SIZE: u64 = 4;
+
+    fn token_metadata(title: &str) -> TokenMetadata {
+        TokenMetadata {
+            title: Some(title.into()),
+            ..sample_token_metadata()
+        }
+    }
+
+    fn final_metadata() -> Vec<TokenMetadata> {
+        (0..SIZE)
+            .map(|i| token_metadata(&format!("Entry {}", i)))
+            .collect()
+    }
+
+    fn call(context: &mut VMContextBuilder, block_height: u64) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .block_height(block_height)
+            .attached_deposit(MINT_STORAGE_COST)
+            .build());
+    }
+
+    /// `SIZE` mystery boxes, committed at block 1.
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.block_height(1).build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        for i in 0..SIZE {
+            call(context, 1);
+            contract.nft_mint(
+                i.to_string(),
+                accounts(1),
+                token_metadata("Mystery box"),
+                None,
+            );
+        }
+        let commitment = contract.reveal_commitment(final_metadata());
+        contract.commit_reveal(commitment, SIZE);
+        contract
+    }
+
+    #[test]
+    fn test_reveal_and_apply() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        assert_eq!(contract.nft_reveal_index("0".to_string()), None);
+
+        testing_env!(context.block_height(2).random_seed([3; 32]).build());
+        let offset = contract.reveal();
+        assert_eq!(offset, u64::from_le_bytes([3; 8]) % SIZE);
+        assert_eq!(contract.reveal_status().unwrap().offset, Some(offset));
+        assert_eq!(
+            contract.nft_reveal_index("1".to_string()),
+            Some((1 + offset) % SIZE)
+        );
+
+        let entries = final_metadata();
+        call(&mut context, 2);
+        contract.upload_reveal_metadata(entries[..2].to_vec());
+        call(&mut context, 2);
+        contract.upload_reveal_metadata(entries[2..].to_vec());
+        assert_eq!(
+            contract.reveal_status().unwrap().stage,
+            RevealStage::Verified
+        );
+
+        call(&mut context, 2);
+        contract.apply_reveal(vec!["1".to_string()]);
+        let index = ((1 + offset) % SIZE) as usize;
+        assert_eq!(
+            contract.nft_token("1".to_string()).unwrap().metadata,
+            Some(entries[index].clone())
+        );
+    }
+
+    #[test]
+    fn test_reset_upload() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        testing_env!(context.block_height(2).build());
+        contract.reveal();
+
+        // The first chunk was uploaded in the wrong order.
+        let entries = final_metadata();
+        call(&mut context, 2);
+        contract.upload_reveal_metadata(vec![entries[1].clone(), entries[0].clone()]);
+        contract.reset_reveal_upload();
+        let status = contract.reveal_status().unwrap();
+        assert_eq!(status.uploaded, 0);
+        assert_eq!(status.uploaded_hash, Base64VecU8(vec![0; 32]));
+        assert!(contract.reveal_entries.is_empty());
+
+        call(&mut context, 2);
+        contract.upload_reveal_metadata(entries);
+        assert_eq!(
+            contract.reveal_status().unwrap().stage,
+            RevealStage::Verified
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Upload can only be reset before it completed")]
+    fn test_reset_after_verified() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        testing_env!(context.block_height(2).build());
+        contract.reveal();
+        call(&mut context, 2);
+        contract.upload_reveal_metadata(final_metadata());
+        contract.reset_reveal_upload();
+    }
+
+    #[test]
+    fn test_non_canonical_token_id() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        testing_env!(context.block_height(2).build());
+        contract.reveal();
+        assert!(contract.nft_reveal_index("1".to_string()).is_some());
+        assert_eq!(contract.nft_reveal_index("01".to_string()), None);
+        assert_eq!(contract.nft_reveal_index("+1".to_string()), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Token ids of a revealed drop can no longer be minted")]
+    fn test_mint_after_reveal() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.block_height(1).build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        let commitment = contract.reveal_commitment(final_metadata());
+        contract.commit_reveal(commitment, SIZE);
+
+        // Minting between the commitment and the reveal is fine, the offset is unknown yet.
+        call(&mut context, 1);
+        contract.nft_mint(
+            "0".to_string(),
+            accounts(1),
+            token_metadata("Mystery box"),
+            None,
+        );
+        testing_env!(context.block_height(2).build());
+        contract.reveal();
+        call(&mut context, 2);
+        contract.nft_mint(
+            "1".to_string(),
+            accounts(0),
+            token_metadata("Mystery box"),
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "title exceeds 4 bytes")]
+    fn test_apply_checks_metadata_rules() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        testing_env!(context.block_height(2).build());
+        contract.reveal();
+        call(&mut context, 2);
+        contract.upload_reveal_metadata(final_metadata());
+        contract.set_metadata_rules(crate::metadata_rules::MetadataRules {
+            max_title_len: Some(4),
+            ..Default::default()
+        });
+        contract.apply_reveal(vec!["0".to_string()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Reveal must happen in a later block than the commitment")]
+    fn test_reveal_in_commit_block() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        contract.reveal();
+    }
+
+    #[test]
+    #[should_panic(expected = "Metadata does not match the commitment")]
+    fn test_upload_other_metadata() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        testing_env!(context.block_height(2).build());
+        contract.reveal();
+        let mut entries = final_metadata();
+        entries.swap(0, 1);
+        call(&mut context, 2);
+        contract.upload_reveal_metadata(entries);
+    }
+
+    #[test]
+    #[should_panic(expected = "Metadata was not verified yet")]
+    fn test_apply_before_upload() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        testing_env!(context.block_height(2).build());
+        contract.reveal();
+        contract.apply_reveal(vec!["0".to_string()]);
+    }
+}
@@ -0,0 +1,78 @@
+/*!
+Role based access for accounts other than the contract owner.
+
+The owner (`tokens.owner_id`) implicitly holds every role and is the only account that can grant
+or revoke them, unless a multisig took over privileged calls.
+*/
+use crate::{Contract, ContractExt};
+use near_sdk::{env, near, require, AccountId};
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// May call `nft_mint`, including sponsored mints.
+    Minter,
+    /// May redeem tickets with `nft_redeem`.
+    Verifier,
+    /// May add accounts to and remove them from the denylist.
+    Compliance,
+    /// May update the gameplay stats of tokens.
+    GameServer,
+}
+
+impl Contract {
+    /// Panics unless called by the owner key, or by the contract itself, which is how queued and
+    /// multisig actions reach owner-only methods. Ignores the admin delay.
+    pub(crate) fn assert_admin(&self) {
+        let predecessor_id = env::predecessor_account_id();
+        if predecessor_id == env::current_account_id() {
+            return;
+        }
+        require!(predecessor_id == self.tokens.owner_id, "Unauthorized");
+        require!(
+            self.multisig.is_none(),
+            "Owner actions require multisig confirmation"
+        );
+    }
+
+    /// Like `assert_admin`, but only lets the owner key through while owner actions are not
+    /// time-locked.
+    pub(crate) fn assert_owner(&self) {
+        self.assert_admin();
+        require!(
+            self.admin_delay == 0 || env::predecessor_account_id() == env::current_account_id(),
+            "Owner actions are time-locked, queue them with `queue_action`"
+        );
+    }
+
+    pub(crate) fn has_role_internal(&self, role: Role, account_id: &AccountId) -> bool {
+        let is_owner = account_id == &self.tokens.owner_id && self.multisig.is_none();
+        is_owner || self.roles.contains(&(role, account_id.clone()))
+    }
+
+    pub(crate) fn assert_role(&self, role: Role) {
+        require!(
+            self.has_role_internal(role, &env::predecessor_account_id()),
+            "Unauthorized"
+        );
+    }
+}
+
+#[near]
+impl Contract {
+    /// Grants `role` to `account_id`. Only the owner can call it.
+    pub fn grant_role(&mut self, role: Role, account_id: AccountId) {
+        self.assert_owner();
+        self.roles.insert(&(role, account_id));
+    }
+
+    /// Revokes `role` from `account_id`. Only the owner can call it.
+    pub fn revoke_role(&mut self, role: Role, account_id: AccountId) {
+        self.assert_owner();
+        self.roles.remove(&(role, account_id));
+    }
+
+    pub fn has_role(&self, role: Role, account_id: AccountId) -> bool {
+        self.has_role_internal(role, &account_id)
+    }
+}
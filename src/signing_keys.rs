@@ -0,0 +1,52 @@
+/*!
+Ed25519 keys registered by accounts to sign messages verified by the contract, such as ticket
+redemptions and permits. An account has at most one registered key, independent of its access
+keys.
+*/
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::refund_deposit;
+use near_sdk::json_types::Base64VecU8;
+use near_sdk::{env, near, require, AccountId, CurveType, PublicKey};
+use std::convert::TryFrom;
+
+impl Contract {
+    /// Whether `signature` is a signature of `message` by the key registered by `account_id`.
+    pub(crate) fn is_signed_by(
+        &self,
+        account_id: &AccountId,
+        message: &[u8],
+        signature: &Base64VecU8,
+    ) -> bool {
+        let Some(public_key) = self.signing_keys.get(account_id) else {
+            return false;
+        };
+        let (Ok(signature), Ok(public_key)) = (
+            <[u8; 64]>::try_from(signature.0.as_slice()),
+            <[u8; 32]>::try_from(&public_key.as_bytes()[1..]),
+        ) else {
+            return false;
+        };
+        env::ed25519_verify(&signature, message, &public_key)
+    }
+}
+
+#[near]
+impl Contract {
+    /// Registers the ed25519 key the caller signs messages for the contract with, replacing any
+    /// previous one. The storage is paid from the attached deposit.
+    #[payable]
+    pub fn register_signing_key(&mut self, public_key: PublicKey) {
+        require!(
+            public_key.curve_type() == CurveType::ED25519,
+            "Only ed25519 keys are supported"
+        );
+        let initial_storage_usage = env::storage_usage();
+        self.signing_keys
+            .insert(&env::predecessor_account_id(), &public_key);
+        refund_deposit(env::storage_usage() - initial_storage_usage);
+    }
+
+    pub fn signing_key_of(&self, account_id: AccountId) -> Option<PublicKey> {
+        self.signing_keys.get(&account_id)
+    }
+}
@@ -0,0 +1,439 @@
+/*!
+Holder snapshots for airdrops.
+
+`take_snapshot` only bumps the current snapshot id. Ownership is captured lazily: the first time
+a token changes owner, or an account's balance changes, after a snapshot was taken, the value it
+had at that snapshot is copied aside. Reading a past snapshot then looks for the first value
+copied at or after it, and falls back to the live state when nothing was written since.
+
+Every copy is a record of its own, so a token or an account changing hands across many snapshots
+never rewrites the copies made before. Copies made while minting or burning are paid together
+with the token. Those made by transfers are paid by the contract, so that `nft_transfer` keeps
+working with 1 yoctoNEAR for any caller; the bond of governance proposals, which take a snapshot
+each, covers them.
+*/
+use crate::storage::{borsh_len, prefix_len, record_bytes};
+use crate::{Contract, ContractExt, StorageKey};
+use near_contract_standards::non_fungible_token::TokenId;
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{env, near, require, AccountId};
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub block_height: U64,
+    /// Block timestamp in nanoseconds.
+    pub timestamp: U64,
+}
+
+/// Value copied on the first write after `snapshot_id`, i.e. the value at that snapshot.
+type Checkpoint<T> = (u64, T);
+
+/// Checkpoints of each key, oldest first, stored under `(key, index)`.
+#[near]
+pub struct Checkpoints<K, V> {
+    /// Number of checkpoints of each key.
+    lens: LookupMap<K, u64>,
+    entries: LookupMap<(K, u64), Checkpoint<V>>,
+}
+
+impl<K, V> Checkpoints<K, V>
+where
+    K: BorshSerialize + BorshDeserialize + Clone,
+    V: BorshSerialize + BorshDeserialize,
+{
+    pub(crate) fn new(lens_prefix: StorageKey, entries_prefix: StorageKey) -> Self {
+        Self {
+            lens: LookupMap::new(lens_prefix),
+            entries: LookupMap::new(entries_prefix),
+        }
+    }
+
+    fn len(&self, key: &K) -> u64 {
+        self.lens.get(key).unwrap_or(0)
+    }
+
+    fn get(&self, key: &K, index: u64) -> Checkpoint<V> {
+        self.entries.get(&(key.clone(), index)).unwrap()
+    }
+
+    /// Whether the latest value of `key` was copied before `snapshot_id` was taken, so a new
+    /// copy is due.
+    fn is_stale(&self, key: &K, snapshot_id: u64) -> bool {
+        match self.len(key) {
+            0 => true,
+            len => self.get(key, len - 1).0 < snapshot_id,
+        }
+    }
+
+    fn push(&mut self, key: &K, snapshot_id: u64, value: V) {
+        let len = self.len(key);
+        self.entries
+            .insert(&(key.clone(), len), &(snapshot_id, value));
+        self.lens.insert(key, &(len + 1));
+    }
+
+    /// Value copied by the first checkpoint of `key` taken at or after `snapshot_id`.
+    fn at(&self, key: &K, snapshot_id: u64) -> Option<V> {
+        let len = self.len(key);
+        let (mut low, mut high) = (0, len);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.get(key, mid).0 < snapshot_id {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        (low < len).then(|| self.get(key, low).1)
+    }
+
+    /// Bytes `push` adds for `checkpoint`, given the prefixes the collection was created with.
+    fn push_bytes(
+        &self,
+        key: &K,
+        checkpoint: &Checkpoint<V>,
+        lens_prefix: StorageKey,
+        entries_prefix: StorageKey,
+    ) -> u64 {
+        let mut bytes = record_bytes(
+            prefix_len(entries_prefix) + borsh_len(&(key.clone(), 0u64)),
+            borsh_len(checkpoint),
+        );
+        if self.len(key) == 0 {
+            bytes += record_bytes(prefix_len(lens_prefix) + borsh_len(key), borsh_len(&0u64));
+        }
+        bytes
+    }
+}
+
+impl Contract {
+    fn current_snapshot_id(&self) -> u64 {
+        self.snapshots.len()
+    }
+
+    pub(crate) fn owner_balance(&self, account_id: &AccountId) -> u64 {
+        self.tokens
+            .tokens_per_owner
+            .as_ref()
+            .and_then(|by_owner| by_owner.get(account_id))
+            .map_or(0, |tokens| tokens.len())
+    }
+
+    /// Copies the values `token_id` moving from `old_owner_id` to `new_owner_id` is about to
+    /// overwrite, if they were not copied since the latest snapshot. `None` stands for a mint or
+    /// a burn. Must be called before the ownership records are updated.
+    pub(crate) fn checkpoint_owner_change(
+        &mut self,
+        token_id: &TokenId,
+        old_owner_id: Option<&AccountId>,
+        new_owner_id: Option<&AccountId>,
+    ) {
+        let snapshot_id = self.current_snapshot_id();
+        if snapshot_id == 0 {
+            return;
+        }
+
+        if self.token_checkpoints.is_stale(token_id, snapshot_id) {
+            self.token_checkpoints
+                .push(token_id, snapshot_id, old_owner_id.cloned());
+        }
+
+        for account_id in old_owner_id.into_iter().chain(new_owner_id) {
+            if self.balance_checkpoints.is_stale(account_id, snapshot_id) {
+                let balance = self.owner_balance(account_id);
+                self.balance_checkpoints
+                    .push(account_id, snapshot_id, balance);
+            }
+        }
+    }
+
+    /// Bytes `checkpoint_owner_change` adds when minting `token_id` to `owner_id`, used by the
+    /// mint storage estimate.
+    pub(crate) fn mint_checkpoint_storage_bytes(
+        &self,
+        token_id: &TokenId,
+        owner_id: &AccountId,
+    ) -> u64 {
+        let snapshot_id = self.current_snapshot_id();
+        if snapshot_id == 0 {
+            return 0;
+        }
+
+        let mut bytes = 0;
+        if self.token_checkpoints.is_stale(token_id, snapshot_id) {
+            bytes += self.token_checkpoints.push_bytes(
+                token_id,
+                &(snapshot_id, None),
+                StorageKey::TokenCheckpointLens,
+                StorageKey::TokenCheckpoints,
+            );
+        }
+        if self.balance_checkpoints.is_stale(owner_id, snapshot_id) {
+            bytes += self.balance_checkpoints.push_bytes(
+                owner_id,
+                &(snapshot_id, self.owner_balance(owner_id)),
+                StorageKey::BalanceCheckpointLens,
+                StorageKey::BalanceCheckpoints,
+            );
+        }
+        bytes
+    }
+
+    fn assert_snapshot_exists(&self, snapshot_id: u64) {
+        require!(
+            snapshot_id > 0 && snapshot_id <= self.current_snapshot_id(),
+            "Snapshot not found"
+        );
+    }
+
+    pub(crate) fn internal_take_snapshot(&mut self) -> u64 {
+        self.snapshots.push(&Snapshot {
+            block_height: env::block_height().into(),
+            timestamp: env::block_timestamp().into(),
+        });
+        self.current_snapshot_id()
+    }
+
+    pub(crate) fn internal_supply_for_owner_at(
+        &self,
+        snapshot_id: u64,
+        account_id: &AccountId,
+    ) -> u64 {
+        self.assert_snapshot_exists(snapshot_id);
+        self.balance_checkpoints
+            .at(account_id, snapshot_id)
+            .unwrap_or_else(|| self.owner_balance(account_id))
+    }
+}
+
+#[near]
+impl Contract {
+    /// Records a new snapshot of token ownership and returns its id. Ids start at 1.
+    pub fn take_snapshot(&mut self) -> u64 {
+        self.assert_owner();
+        self.internal_take_snapshot()
+    }
+
+    pub fn snapshot(&self, snapshot_id: u64) -> Option<Snapshot> {
+        snapshot_id
+            .checked_sub(1)
+            .and_then(|index| self.snapshots.get(index))
+    }
+
+    /// Id of the latest snapshot, `0` if none was taken yet.
+    pub fn latest_snapshot_id(&self) -> u64 {
+        self.current_snapshot_id()
+    }
+
+    /// Owner of `token_id` when `snapshot_id` was taken, `None` if it did not exist then.
+    pub fn owner_at(&self, snapshot_id: u64, token_id: TokenId) -> Option<AccountId> {
+        self.assert_snapshot_exists(snapshot_id);
+        match self.token_checkpoints.at(&token_id, snapshot_id) {
+            Some(owner_id) => owner_id,
+            None => self.tokens.owner_by_id.get(&token_id),
+        }
+    }
+
+    /// Number of tokens `account_id` held when `snapshot_id` was taken.
+    pub fn supply_for_owner_at(&self, snapshot_id: u64, account_id: AccountId) -> U128 {
+        U128(self.internal_supply_for_owner_at(snapshot_id, &account_id) as u128)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, NearToken};
+
+    use super::*;
+
+    const ONE_YOCTONEAR: NearToken = NearToken::from_yoctonear(1);
+
+    fn mint(context: &mut VMContextBuilder, contract: &mut Contract, token_id: &str) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .predecessor_account_id(accounts(0))
+            .build());
+        contract.nft_mint(
+            token_id.to_string(),
+            accounts(1),
+            sample_token_metadata(),
+            None,
+        );
+    }
+
+    fn transfer(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        token_id: &str,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+    ) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(ONE_YOCTONEAR)
+            .predecessor_account_id(sender_id)
+            .build());
+        contract.nft_transfer(receiver_id, token_id.to_string(), None, None);
+    }
+
+    fn take_snapshot(context: &mut VMContextBuilder, contract: &mut Contract) -> u64 {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(NearToken::from_yoctonear(0))
+            .predecessor_account_id(accounts(0))
+            .build());
+        contract.take_snapshot()
+    }
+
+    #[test]
+    fn test_owner_at_survives_later_transfers() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        mint(&mut context, &mut contract, "0");
+        mint(&mut context, &mut contract, "1");
+
+        let first = take_snapshot(&mut context, &mut contract);
+        transfer(&mut context, &mut contract, "0", accounts(1), accounts(2));
+        let second = take_snapshot(&mut context, &mut contract);
+        transfer(&mut context, &mut contract, "0", accounts(2), accounts(3));
+
+        assert_eq!(first, 1);
+        assert_eq!(second, 2);
+        assert_eq!(contract.owner_at(first, "0".to_string()), Some(accounts(1)));
+        assert_eq!(
+            contract.owner_at(second, "0".to_string()),
+            Some(accounts(2))
+        );
+        // Never written since the first snapshot, served from the live state.
+        assert_eq!(contract.owner_at(first, "1".to_string()), Some(accounts(1)));
+
+        assert_eq!(contract.supply_for_owner_at(first, accounts(1)), U128(2));
+        assert_eq!(contract.supply_for_owner_at(first, accounts(2)), U128(0));
+        assert_eq!(contract.supply_for_owner_at(second, accounts(1)), U128(1));
+        assert_eq!(contract.supply_for_owner_at(second, accounts(2)), U128(1));
+        assert_eq!(contract.supply_for_owner_at(second, accounts(3)), U128(0));
+    }
+
+    #[test]
+    fn test_owner_at_across_many_snapshots() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        mint(&mut context, &mut contract, "0");
+
+        let holders = [accounts(1), accounts(2), accounts(3), accounts(4)];
+        let mut snapshot_ids = vec![];
+        for pair in holders.windows(2) {
+            snapshot_ids.push(take_snapshot(&mut context, &mut contract));
+            // A snapshot without any transfer shares the checkpoint of the next one.
+            take_snapshot(&mut context, &mut contract);
+            transfer(
+                &mut context,
+                &mut contract,
+                "0",
+                pair[0].clone(),
+                pair[1].clone(),
+            );
+        }
+
+        for (snapshot_id, holder) in snapshot_ids.iter().zip(&holders) {
+            assert_eq!(
+                contract.owner_at(*snapshot_id, "0".to_string()),
+                Some(holder.clone())
+            );
+            assert_eq!(
+                contract.owner_at(snapshot_id + 1, "0".to_string()),
+                Some(holder.clone())
+            );
+            assert_eq!(
+                contract.supply_for_owner_at(*snapshot_id, holder.clone()),
+                U128(1)
+            );
+        }
+        let latest = take_snapshot(&mut context, &mut contract);
+        assert_eq!(
+            contract.owner_at(latest, "0".to_string()),
+            Some(accounts(4))
+        );
+    }
+
+    #[test]
+    fn test_transfer_after_snapshot_without_prepaid_storage() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        mint(&mut context, &mut contract, "0");
+        let snapshot_id = take_snapshot(&mut context, &mut contract);
+        transfer(&mut context, &mut contract, "0", accounts(1), accounts(2));
+
+        assert_eq!(
+            contract.nft_storage_balance_of(accounts(1)),
+            NearToken::from_yoctonear(0)
+        );
+        assert_eq!(
+            contract.owner_at(snapshot_id, "0".to_string()),
+            Some(accounts(1))
+        );
+    }
+
+    #[test]
+    fn test_mint_and_burn_after_snapshot() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        mint(&mut context, &mut contract, "0");
+
+        let snapshot_id = take_snapshot(&mut context, &mut contract);
+        mint(&mut context, &mut contract, "1");
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(ONE_YOCTONEAR)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.nft_burn("0".to_string());
+
+        assert_eq!(
+            contract.owner_at(snapshot_id, "0".to_string()),
+            Some(accounts(1))
+        );
+        assert_eq!(contract.owner_at(snapshot_id, "1".to_string()), None);
+        assert_eq!(
+            contract.supply_for_owner_at(snapshot_id, accounts(1)),
+            U128(1)
+        );
+    }
+
+    #[test]
+    fn test_estimate_mint_storage_includes_checkpoints() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        mint(&mut context, &mut contract, "0");
+        take_snapshot(&mut context, &mut contract);
+
+        let estimate =
+            contract.estimate_mint_storage("1".to_string(), accounts(1), sample_token_metadata());
+        let initial_storage_usage = env::storage_usage();
+        mint(&mut context, &mut contract, "1");
+        let actual = crate::storage::storage_cost(env::storage_usage() - initial_storage_usage);
+        assert_eq!(estimate, actual);
+    }
+
+    #[test]
+    #[should_panic(expected = "Snapshot not found")]
+    fn test_owner_at_unknown_snapshot() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let contract = Contract::new_default_meta(accounts(0));
+        contract.owner_at(1, "0".to_string());
+    }
+}
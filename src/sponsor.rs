@@ -0,0 +1,267 @@
+/*!
+Sponsored minting.
+
+The owner funds a pool held in the contract balance. Minters can then mint with
+`sponsored: true`, in which case the storage of the new token is paid from the pool instead of
+the attached deposit. Every recipient can receive at most `daily_budget` worth of sponsored
+storage per day, and the storage released by burning a sponsored token goes back to the pool.
+*/
+use crate::storage::storage_cost;
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::TokenId;
+use near_sdk::{assert_one_yocto, env, near, require, AccountId, NearToken, Promise};
+
+const NANOSECONDS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SponsorPool {
+    /// Amount still available to pay for storage.
+    pub balance: NearToken,
+    /// Total amount ever spent on sponsored storage.
+    pub consumed: NearToken,
+    /// Maximum amount a single recipient can be sponsored per day.
+    pub daily_budget: NearToken,
+}
+
+impl Default for SponsorPool {
+    fn default() -> Self {
+        Self {
+            balance: NearToken::from_yoctonear(0),
+            consumed: NearToken::from_yoctonear(0),
+            daily_budget: NearToken::from_yoctonear(0),
+        }
+    }
+}
+
+/// Sponsored storage received by an account on a given day.
+#[near]
+pub struct DailyUsage {
+    day: u64,
+    spent: NearToken,
+}
+
+fn current_day() -> u64 {
+    env::block_timestamp() / NANOSECONDS_PER_DAY
+}
+
+impl Contract {
+    /// Charges the storage used since `initial_storage_usage` to the sponsor pool on behalf of
+    /// `receiver_id` and remembers that `token_id` was sponsored.
+    pub(crate) fn charge_sponsor_pool(
+        &mut self,
+        token_id: &TokenId,
+        receiver_id: &AccountId,
+        initial_storage_usage: u64,
+    ) {
+        let today = current_day();
+        let spent_today = self.sponsor_usage_today(receiver_id);
+
+        // Write the bookkeeping records first so their own storage is part of the charge. Both
+        // have a fixed size, so updating them with the final values below costs nothing extra.
+        self.sponsored_tokens.insert(token_id);
+        self.sponsor_usage.insert(
+            receiver_id,
+            &DailyUsage {
+                day: today,
+                spent: spent_today,
+            },
+        );
+        let cost = storage_cost(env::storage_usage() - initial_storage_usage);
+
+        let spent = spent_today.saturating_add(cost);
+        require!(
+            spent <= self.sponsor_pool.daily_budget,
+            "Daily sponsorship budget exceeded for receiver"
+        );
+        require!(
+            cost <= self.sponsor_pool.balance,
+            "Sponsor pool balance too low"
+        );
+        self.sponsor_usage
+            .insert(receiver_id, &DailyUsage { day: today, spent });
+        self.sponsor_pool.balance = self.sponsor_pool.balance.saturating_sub(cost);
+        self.sponsor_pool.consumed = self.sponsor_pool.consumed.saturating_add(cost);
+    }
+
+    /// Returns storage released by a burnt sponsored token to the pool.
+    pub(crate) fn refill_sponsor_pool(&mut self, released: NearToken) {
+        self.sponsor_pool.balance = self.sponsor_pool.balance.saturating_add(released);
+    }
+
+    fn sponsor_usage_today(&self, account_id: &AccountId) -> NearToken {
+        self.sponsor_usage
+            .get(account_id)
+            .filter(|usage| usage.day == current_day())
+            .map(|usage| usage.spent)
+            .unwrap_or(NearToken::from_yoctonear(0))
+    }
+}
+
+#[near]
+impl Contract {
+    /// Adds the attached deposit to the sponsor pool. Only the owner can call it.
+    #[payable]
+    pub fn sponsor_pool_deposit(&mut self) -> SponsorPool {
+        self.assert_owner();
+        self.sponsor_pool.balance = self
+            .sponsor_pool
+            .balance
+            .saturating_add(env::attached_deposit());
+        self.sponsor_pool.clone()
+    }
+
+    /// Withdraws `amount` from the sponsor pool to the owner.
+    #[payable]
+    pub fn sponsor_pool_withdraw(&mut self, amount: NearToken) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(
+            amount <= self.sponsor_pool.balance,
+            "Sponsor pool balance too low"
+        );
+        self.sponsor_pool.balance = self.sponsor_pool.balance.saturating_sub(amount);
+        Promise::new(self.tokens.owner_id.clone()).transfer(amount)
+    }
+
+    /// Sets the maximum amount a single receiver can be sponsored per day.
+    pub fn set_sponsor_daily_budget(&mut self, daily_budget: NearToken) {
+        self.assert_owner();
+        self.sponsor_pool.daily_budget = daily_budget;
+    }
+
+    pub fn sponsor_pool(&self) -> SponsorPool {
+        self.sponsor_pool.clone()
+    }
+
+    /// Sponsored storage `account_id` has received today.
+    pub fn sponsor_usage(&self, account_id: AccountId) -> NearToken {
+        self.sponsor_usage_today(&account_id)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::roles::Role;
+    use crate::test_utils::{get_context, sample_token_metadata};
+
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    const POOL_FUNDING: NearToken = NearToken::from_near(1);
+    const DAILY_BUDGET: NearToken = NearToken::from_millinear(10);
+    const ONE_YOCTONEAR: NearToken = NearToken::from_yoctonear(1);
+    const ZERO_NEAR: NearToken = NearToken::from_yoctonear(0);
+
+    /// Contract owned by accounts(0) with a funded pool and accounts(1) as a minter.
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        contract.grant_role(Role::Minter, accounts(1));
+        contract.set_sponsor_daily_budget(DAILY_BUDGET);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(POOL_FUNDING)
+            .build());
+        contract.sponsor_pool_deposit();
+        contract
+    }
+
+    fn sponsored_mint(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        token_id: &str,
+        receiver_id: AccountId,
+    ) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(ZERO_NEAR)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.nft_mint(
+            token_id.to_string(),
+            receiver_id,
+            sample_token_metadata(),
+            Some(true),
+        );
+    }
+
+    #[test]
+    fn test_sponsored_mint_draws_from_pool() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+
+        sponsored_mint(&mut context, &mut contract, "0", accounts(2));
+
+        let pool = contract.sponsor_pool();
+        assert!(pool.consumed > ZERO_NEAR);
+        assert_eq!(pool.balance, POOL_FUNDING.saturating_sub(pool.consumed));
+        assert_eq!(contract.sponsor_usage(accounts(2)), pool.consumed);
+        assert_eq!(contract.sponsor_usage(accounts(3)), ZERO_NEAR);
+    }
+
+    #[test]
+    #[should_panic(expected = "Daily sponsorship budget exceeded for receiver")]
+    fn test_sponsored_mint_respects_daily_budget() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        testing_env!(context.build());
+        contract.set_sponsor_daily_budget(NearToken::from_yoctonear(1));
+
+        sponsored_mint(&mut context, &mut contract, "0", accounts(2));
+    }
+
+    #[test]
+    fn test_daily_budget_resets_next_day() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        sponsored_mint(&mut context, &mut contract, "0", accounts(2));
+        assert!(contract.sponsor_usage(accounts(2)) > ZERO_NEAR);
+
+        context.block_timestamp(NANOSECONDS_PER_DAY);
+        testing_env!(context.build());
+        assert_eq!(contract.sponsor_usage(accounts(2)), ZERO_NEAR);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unauthorized")]
+    fn test_sponsored_mint_requires_minter() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        testing_env!(context.build());
+        contract.revoke_role(Role::Minter, accounts(1));
+
+        sponsored_mint(&mut context, &mut contract, "0", accounts(2));
+    }
+
+    #[test]
+    fn test_burn_refunds_pool() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        sponsored_mint(&mut context, &mut contract, "0", accounts(2));
+        let after_mint = contract.sponsor_pool();
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(ONE_YOCTONEAR)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.nft_burn("0".to_string());
+
+        let pool = contract.sponsor_pool();
+        assert!(pool.balance > after_mint.balance);
+        assert!(pool.balance <= POOL_FUNDING);
+        assert_eq!(pool.consumed, after_mint.consumed);
+    }
+
+    #[test]
+    #[should_panic(expected = "Sponsor pool balance too low")]
+    fn test_withdraw_more_than_pool_panics() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        testing_env!(context.attached_deposit(ONE_YOCTONEAR).build());
+        contract.sponsor_pool_withdraw(POOL_FUNDING.saturating_add(ONE_YOCTONEAR));
+    }
+}
@@ -0,0 +1,378 @@
+/*!
+Token staking.
+
+A holder stakes a token to earn rewards while it stays in their wallet: the token keeps its owner
+but cannot be transferred or burnt until it is unstaked. Each staked token earns
+`reward_rate_per_second` per second, or the rate set for its series, where the series of a token
+is the part of its id before the first `:` (e.g. `"3"` for token `"3:14"`). The rate of a token
+is fixed when it is staked.
+
+Rewards accrue per account and are paid by `claim_rewards`, either as internal points or, once
+the owner set a reward token, as an NEP-141 transfer from the contract's balance of that token.
+*/
+use crate::storage::storage_cost;
+use crate::{Contract, ContractExt};
+use near_contract_standards::fungible_token::core::ext_ft_core;
+use near_contract_standards::non_fungible_token::{refund_deposit, TokenId};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{
+    assert_one_yocto, env, near, require, AccountId, Gas, NearToken, Promise, PromiseError,
+    PromiseOrValue,
+};
+
+const GAS_FOR_FT_TRANSFER: Gas = Gas::from_tgas(10);
+const GAS_FOR_ON_REWARDS_CLAIMED: Gas = Gas::from_tgas(10);
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stake {
+    pub owner_id: AccountId,
+    /// Block timestamp in nanoseconds.
+    pub staked_at: U64,
+    /// Reward earned per second while staked.
+    pub reward_rate: U128,
+}
+
+/// Rewards of an account, accrued up to `updated_at`.
+#[near]
+#[derive(Default)]
+pub struct RewardAccount {
+    /// Sum of the rates of the tokens staked by the account.
+    rate: u128,
+    accrued: u128,
+    updated_at: u64,
+}
+
+impl RewardAccount {
+    fn pending(&self, now: u64) -> u128 {
+        let elapsed = (now - self.updated_at) / NANOSECONDS_PER_SECOND;
+        self.accrued + self.rate * elapsed as u128
+    }
+
+    /// Accrues rewards up to `now`. Only whole seconds are accrued, the remainder carries over.
+    fn settle(&mut self, now: u64) {
+        let elapsed = (now - self.updated_at) / NANOSECONDS_PER_SECOND;
+        self.accrued += self.rate * elapsed as u128;
+        self.updated_at += elapsed * NANOSECONDS_PER_SECOND;
+    }
+}
+
+pub(crate) fn series_of(token_id: &TokenId) -> &str {
+    token_id.split(':').next().unwrap_or(token_id)
+}
+
+impl Contract {
+    pub(crate) fn assert_not_staked(&self, token_id: &TokenId) {
+        require!(!self.stakes.contains_key(token_id), "Token is staked");
+    }
+
+    fn reward_rate_of(&self, token_id: &TokenId) -> u128 {
+        self.series_reward_rates
+            .get(&series_of(token_id).to_string())
+            .unwrap_or(self.reward_rate_per_second)
+    }
+
+    fn settled_reward_account(&self, account_id: &AccountId) -> RewardAccount {
+        let now = env::block_timestamp();
+        let mut account = self
+            .reward_accounts
+            .get(account_id)
+            .unwrap_or(RewardAccount {
+                updated_at: now,
+                ..Default::default()
+            });
+        account.settle(now);
+        account
+    }
+}
+
+#[near]
+impl Contract {
+    /// Locks `token_id` in place to earn rewards. The storage is paid from the attached deposit.
+    #[payable]
+    pub fn stake(&mut self, token_id: TokenId) -> Stake {
+        let owner_id = self.owner_of(&token_id);
+        require!(
+            env::predecessor_account_id() == owner_id,
+            "Predecessor must be token owner."
+        );
+        self.assert_not_staked(&token_id);
+        self.assert_no_pending_force_transfer(&token_id);
+
+        let initial_storage_usage = env::storage_usage();
+        let stake = Stake {
+            owner_id: owner_id.clone(),
+            staked_at: env::block_timestamp().into(),
+            reward_rate: self.reward_rate_of(&token_id).into(),
+        };
+        let mut account = self.settled_reward_account(&owner_id);
+        account.rate += stake.reward_rate.0;
+        self.reward_accounts.insert(&owner_id, &account);
+        self.stakes.insert(&token_id, &stake);
+        refund_deposit(env::storage_usage() - initial_storage_usage);
+        stake
+    }
+
+    /// Unlocks `token_id` and refunds the storage of the stake. Rewards earned so far stay
+    /// claimable.
+    #[payable]
+    pub fn unstake(&mut self, token_id: TokenId) {
+        assert_one_yocto();
+        let stake = self
+            .stakes
+            .get(&token_id)
+            .unwrap_or_else(|| env::panic_str("Token is not staked"));
+        require!(
+            env::predecessor_account_id() == stake.owner_id,
+            "Predecessor must be token owner."
+        );
+
+        let initial_storage_usage = env::storage_usage();
+        let mut account = self.settled_reward_account(&stake.owner_id);
+        account.rate -= stake.reward_rate.0;
+        self.reward_accounts.insert(&stake.owner_id, &account);
+        self.stakes.remove(&token_id);
+        let released = storage_cost(initial_storage_usage - env::storage_usage());
+        if !released.is_zero() {
+            Promise::new(stake.owner_id).transfer(released);
+        }
+    }
+
+    /// Pays out the rewards of the caller and returns the amount claimed. The first claim of
+    /// points opens a points balance, paid from the attached deposit.
+    #[payable]
+    pub fn claim_rewards(&mut self) -> PromiseOrValue<U128> {
+        let account_id = env::predecessor_account_id();
+        let initial_storage_usage = env::storage_usage();
+        let mut account = self.settled_reward_account(&account_id);
+        let amount = account.accrued;
+        account.accrued = 0;
+        self.reward_accounts.insert(&account_id, &account);
+
+        match self.reward_token.clone() {
+            Some(reward_token) if amount > 0 => {
+                refund_deposit(env::storage_usage() - initial_storage_usage);
+                ext_ft_core::ext(reward_token)
+                    .with_attached_deposit(NearToken::from_yoctonear(1))
+                    .with_static_gas(GAS_FOR_FT_TRANSFER)
+                    .ft_transfer(account_id.clone(), U128(amount), None)
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(GAS_FOR_ON_REWARDS_CLAIMED)
+                            .on_rewards_claimed(account_id, U128(amount)),
+                    )
+                    .into()
+            }
+            None if amount > 0 => {
+                let points = self.reward_points.get(&account_id).unwrap_or(0);
+                self.reward_points.insert(&account_id, &(points + amount));
+                refund_deposit(env::storage_usage() - initial_storage_usage);
+                PromiseOrValue::Value(U128(amount))
+            }
+            _ => {
+                refund_deposit(env::storage_usage() - initial_storage_usage);
+                PromiseOrValue::Value(U128(0))
+            }
+        }
+    }
+
+    /// Credits `amount` back to `account_id` if the reward token transfer failed.
+    #[private]
+    pub fn on_rewards_claimed(
+        &mut self,
+        account_id: AccountId,
+        amount: U128,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) -> U128 {
+        if result.is_ok() {
+            return amount;
+        }
+        let mut account = self.settled_reward_account(&account_id);
+        account.accrued += amount.0;
+        self.reward_accounts.insert(&account_id, &account);
+        U128(0)
+    }
+
+    /// Sets the default reward per second of tokens staked from now on.
+    pub fn set_reward_rate(&mut self, rate_per_second: U128) {
+        self.assert_owner();
+        self.reward_rate_per_second = rate_per_second.0;
+    }
+
+    /// Overrides the reward per second of tokens of `series` staked from now on. `None` goes back
+    /// to the default rate.
+    pub fn set_series_reward_rate(&mut self, series: String, rate_per_second: Option<U128>) {
+        self.assert_owner();
+        match rate_per_second {
+            Some(rate) => self.series_reward_rates.insert(&series, &rate.0),
+            None => self.series_reward_rates.remove(&series),
+        };
+    }
+
+    /// Pays rewards in the NEP-141 `reward_token` instead of internal points. The contract must
+    /// be registered with, and hold enough of, that token.
+    pub fn set_reward_token(&mut self, reward_token: Option<AccountId>) {
+        self.assert_owner();
+        self.reward_token = reward_token;
+    }
+
+    pub fn reward_rate(&self, token_id: TokenId) -> U128 {
+        self.reward_rate_of(&token_id).into()
+    }
+
+    pub fn reward_token(&self) -> Option<AccountId> {
+        self.reward_token.clone()
+    }
+
+    pub fn nft_stake(&self, token_id: TokenId) -> Option<Stake> {
+        self.stakes.get(&token_id)
+    }
+
+    pub fn pending_rewards(&self, account_id: AccountId) -> U128 {
+        self.reward_accounts
+            .get(&account_id)
+            .map_or(0, |account| account.pending(env::block_timestamp()))
+            .into()
+    }
+
+    /// Points claimed by `account_id` while rewards were paid as internal points.
+    pub fn reward_points(&self, account_id: AccountId) -> U128 {
+        self.reward_points.get(&account_id).unwrap_or(0).into()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+
+    use near_sdk::test_utils::{accounts, get_created_receipts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    const STAKE_STORAGE_COST: NearToken = NearToken::from_millinear(10);
+    const ONE_YOCTONEAR: NearToken = NearToken::from_yoctonear(1);
+    const SECOND: u64 = NANOSECONDS_PER_SECOND;
+
+    fn call(context: &mut VMContextBuilder, predecessor_id: AccountId, deposit: NearToken) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(deposit)
+            .predecessor_account_id(predecessor_id)
+            .build());
+    }
+
+    /// Tokens "1:0" and "2:0" owned by accounts(1), with a default rate of 10 per second.
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        contract.set_reward_rate(U128(10));
+        for token_id in ["1:0", "2:0"] {
+            call(context, accounts(0), MINT_STORAGE_COST);
+            contract.nft_mint(
+                token_id.to_string(),
+                accounts(1),
+                sample_token_metadata(),
+                None,
+            );
+        }
+        contract
+    }
+
+    fn stake(context: &mut VMContextBuilder, contract: &mut Contract, token_id: &str) {
+        call(context, accounts(1), STAKE_STORAGE_COST);
+        contract.stake(token_id.to_string());
+    }
+
+    fn claim(context: &mut VMContextBuilder, contract: &mut Contract) -> PromiseOrValue<U128> {
+        call(context, accounts(1), STAKE_STORAGE_COST);
+        contract.claim_rewards()
+    }
+
+    #[test]
+    fn test_rewards_accrue_per_token_rate() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        contract.set_series_reward_rate("2".to_string(), Some(U128(5)));
+        stake(&mut context, &mut contract, "1:0");
+        stake(&mut context, &mut contract, "2:0");
+        assert_eq!(
+            contract.nft_stake("2:0".to_string()).unwrap().reward_rate,
+            U128(5)
+        );
+
+        context.block_timestamp(3 * SECOND);
+        testing_env!(context.build());
+        assert_eq!(contract.pending_rewards(accounts(1)), U128(45));
+
+        // Rewards earned before unstaking stay claimable.
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.unstake("1:0".to_string());
+        context.block_timestamp(5 * SECOND);
+        testing_env!(context.build());
+        assert_eq!(contract.pending_rewards(accounts(1)), U128(55));
+        assert!(contract.nft_stake("1:0".to_string()).is_none());
+    }
+
+    #[test]
+    fn test_claim_rewards_as_points() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        stake(&mut context, &mut contract, "1:0");
+
+        context.block_timestamp(4 * SECOND);
+        match claim(&mut context, &mut contract) {
+            PromiseOrValue::Value(amount) => assert_eq!(amount, U128(40)),
+            PromiseOrValue::Promise(_) => panic!("Expected points to be credited"),
+        }
+        assert_eq!(contract.reward_points(accounts(1)), U128(40));
+        assert_eq!(contract.pending_rewards(accounts(1)), U128(0));
+    }
+
+    #[test]
+    fn test_claim_rewards_in_reward_token() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        contract.set_reward_token(Some(accounts(4)));
+        stake(&mut context, &mut contract, "1:0");
+
+        context.block_timestamp(2 * SECOND);
+        claim(&mut context, &mut contract);
+        assert!(get_created_receipts()
+            .iter()
+            .any(|receipt| receipt.receiver_id == accounts(4)));
+        assert_eq!(contract.pending_rewards(accounts(1)), U128(0));
+
+        // A failed transfer gives the rewards back.
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            near_sdk::test_vm_config(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![near_sdk::PromiseResult::Failed],
+        );
+        contract.on_rewards_claimed(accounts(1), U128(20), Err(PromiseError::Failed));
+        assert_eq!(contract.pending_rewards(accounts(1)), U128(20));
+    }
+
+    #[test]
+    #[should_panic(expected = "Token is staked")]
+    fn test_staked_token_cannot_be_transferred() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        stake(&mut context, &mut contract, "1:0");
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_transfer(accounts(2), "1:0".to_string(), None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Predecessor must be token owner.")]
+    fn test_stake_by_non_owner() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(2), STAKE_STORAGE_COST);
+        contract.stake("1:0".to_string());
+    }
+}
@@ -0,0 +1,292 @@
+/*!
+Evolving tokens with gameplay stats.
+
+Each token has a level, experience points and named counters, which only accounts holding the
+`GameServer` role can update. The owner sets level thresholds: when a token's xp reaches the
+`min_xp` of a threshold, the token moves to its level and its media switches to the stage of the
+highest threshold reached that has a `media`. Levels only go up, as xp can only be added.
+
+Every update logs an `nft_stats_update` event, and a media switch an `nft_metadata_update` one.
+*/
+use crate::events::{ContractEvent, NftMetadataUpdate, NftStatsUpdate};
+use crate::roles::Role;
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::metadata::TokenMetadata;
+use near_contract_standards::non_fungible_token::{refund_deposit, TokenId};
+use near_sdk::json_types::{Base64VecU8, U64};
+use near_sdk::{env, near, require};
+use std::collections::BTreeMap;
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TokenStats {
+    pub level: u32,
+    pub xp: U64,
+    pub counters: BTreeMap<String, U64>,
+}
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct LevelThreshold {
+    pub level: u32,
+    pub min_xp: U64,
+    /// Media of the stage the token switches to at this level, if any.
+    pub media: Option<String>,
+    pub media_hash: Option<Base64VecU8>,
+}
+
+impl Contract {
+    fn stats_of(&self, token_id: &TokenId) -> TokenStats {
+        self.token_stats.get(token_id).unwrap_or_default()
+    }
+
+    /// Stores `stats` and logs the update, charging added storage to the game server.
+    fn save_stats(&mut self, token_id: &TokenId, stats: &TokenStats, initial_storage_usage: u64) {
+        self.token_stats.insert(token_id, stats);
+        refund_deposit(env::storage_usage().saturating_sub(initial_storage_usage));
+        ContractEvent::NftStatsUpdate(&[NftStatsUpdate {
+            token_id,
+            game_server_id: &env::predecessor_account_id(),
+            level: stats.level,
+            xp: stats.xp,
+        }])
+        .emit();
+    }
+
+    /// Switches the media of `token_id` to the stage of `threshold`.
+    fn switch_stage(&mut self, token_id: &TokenId, threshold: &LevelThreshold) {
+        let Some(media) = &threshold.media else {
+            return;
+        };
+        let metadata = self
+            .tokens
+            .token_metadata_by_id
+            .as_ref()
+            .and_then(|by_id| by_id.get(token_id))
+            .unwrap();
+        self.internal_update_metadata(
+            token_id,
+            &TokenMetadata {
+                media: Some(media.clone()),
+                media_hash: threshold.media_hash.clone(),
+                ..metadata
+            },
+        );
+        ContractEvent::NftMetadataUpdate(&[NftMetadataUpdate {
+            token_ids: &[token_id],
+        }])
+        .emit();
+    }
+
+    /// Removes the stats of `token_id`. Called when the token is burnt.
+    pub(crate) fn remove_stats(&mut self, token_id: &TokenId) {
+        self.token_stats.remove(token_id);
+    }
+}
+
+#[near]
+impl Contract {
+    /// Replaces the level thresholds. Levels and `min_xp` must both be increasing.
+    pub fn set_level_thresholds(&mut self, thresholds: Vec<LevelThreshold>) {
+        self.assert_owner();
+        for pair in thresholds.windows(2) {
+            require!(
+                pair[0].level < pair[1].level && pair[0].min_xp.0 < pair[1].min_xp.0,
+                "Thresholds must be sorted by level and xp"
+            );
+        }
+        self.level_thresholds = thresholds;
+    }
+
+    pub fn level_thresholds(&self) -> Vec<LevelThreshold> {
+        self.level_thresholds.clone()
+    }
+
+    /// Adds `amount` xp to `token_id`, leveling it up through any threshold reached. Only game
+    /// servers can call it; added storage is paid from the attached deposit.
+    #[payable]
+    pub fn add_xp(&mut self, token_id: TokenId, amount: U64) -> TokenStats {
+        self.assert_role(Role::GameServer);
+        self.owner_of(&token_id);
+        let initial_storage_usage = env::storage_usage();
+        let mut stats = self.stats_of(&token_id);
+        stats.xp = stats.xp.0.saturating_add(amount.0).into();
+        let reached: Vec<LevelThreshold> = self
+            .level_thresholds
+            .iter()
+            .filter(|threshold| threshold.min_xp.0 <= stats.xp.0)
+            .cloned()
+            .collect();
+        let previous_level = stats.level;
+        if let Some(top) = reached.last().filter(|top| top.level > previous_level) {
+            stats.level = top.level;
+            // A level without media of its own keeps the stage of the highest level with one,
+            // which only needs switching to if it was just reached.
+            let stage = reached
+                .iter()
+                .rev()
+                .find(|threshold| threshold.media.is_some());
+            if let Some(stage) = stage.filter(|stage| stage.level > previous_level) {
+                self.switch_stage(&token_id, stage);
+            }
+        }
+        self.save_stats(&token_id, &stats, initial_storage_usage);
+        stats
+    }
+
+    /// Sets the given counters of `token_id`, leaving the others as they are. Only game servers
+    /// can call it; added storage is paid from the attached deposit.
+    #[payable]
+    pub fn set_counters(
+        &mut self,
+        token_id: TokenId,
+        counters: BTreeMap<String, U64>,
+    ) -> TokenStats {
+        self.assert_role(Role::GameServer);
+        self.owner_of(&token_id);
+        let initial_storage_usage = env::storage_usage();
+        let mut stats = self.stats_of(&token_id);
+        stats.counters.extend(counters);
+        self.save_stats(&token_id, &stats, initial_storage_usage);
+        stats
+    }
+
+    pub fn nft_stats(&self, token_id: TokenId) -> TokenStats {
+        self.owner_of(&token_id);
+        self.stats_of(&token_id)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, AccountId};
+
+    use super::*;
+
+    fn hatchling_metadata() -> TokenMetadata {
+        TokenMetadata {
+            title: Some("Hatchling".into()),
+            media: Some("ipfs://egg".into()),
+            ..sample_token_metadata()
+        }
+    }
+
+    fn threshold(level: u32, min_xp: u64, media: Option<&str>) -> LevelThreshold {
+        LevelThreshold {
+            level,
+            min_xp: U64(min_xp),
+            media: media.map(str::to_string),
+            media_hash: None,
+        }
+    }
+
+    fn call(context: &mut VMContextBuilder, predecessor_id: AccountId) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .predecessor_account_id(predecessor_id)
+            .build());
+    }
+
+    /// Token "0" owned by accounts(1), accounts(2) as game server, and levels 1 at 100 xp and
+    /// 2 at 500 xp, the latter switching the media.
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        call(context, accounts(0));
+        contract.nft_mint("0".to_string(), accounts(1), hatchling_metadata(), None);
+        contract.grant_role(Role::GameServer, accounts(2));
+        contract.set_level_thresholds(vec![
+            threshold(1, 100, None),
+            threshold(2, 500, Some("ipfs://dragon")),
+        ]);
+        contract
+    }
+
+    fn media(contract: &Contract) -> Option<String> {
+        contract
+            .nft_token("0".to_string())
+            .unwrap()
+            .metadata
+            .unwrap()
+            .media
+    }
+
+    #[test]
+    fn test_level_up_switches_media() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        assert_eq!(contract.nft_stats("0".to_string()), TokenStats::default());
+
+        call(&mut context, accounts(2));
+        let stats = contract.add_xp("0".to_string(), U64(150));
+        assert_eq!((stats.level, stats.xp), (1, U64(150)));
+        assert_eq!(media(&contract), Some("ipfs://egg".to_string()));
+        assert!(get_logs()[0].contains(r#""event":"nft_stats_update""#));
+
+        call(&mut context, accounts(2));
+        let stats = contract.add_xp("0".to_string(), U64(400));
+        assert_eq!((stats.level, stats.xp), (2, U64(550)));
+        assert_eq!(media(&contract), Some("ipfs://dragon".to_string()));
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.contains(r#""event":"nft_metadata_update""#)));
+    }
+
+    #[test]
+    fn test_skipped_stage_media_is_applied() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(0));
+        contract.set_level_thresholds(vec![
+            threshold(1, 100, Some("ipfs://hatchling")),
+            threshold(2, 500, None),
+        ]);
+
+        // Straight to level 2, which has no media: the level 1 stage applies.
+        call(&mut context, accounts(2));
+        let stats = contract.add_xp("0".to_string(), U64(600));
+        assert_eq!(stats.level, 2);
+        assert_eq!(media(&contract), Some("ipfs://hatchling".to_string()));
+    }
+
+    #[test]
+    fn test_set_counters() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(2));
+        contract.set_counters(
+            "0".to_string(),
+            BTreeMap::from([("wins".to_string(), U64(3)), ("losses".to_string(), U64(1))]),
+        );
+        call(&mut context, accounts(2));
+        contract.set_counters(
+            "0".to_string(),
+            BTreeMap::from([("wins".to_string(), U64(4))]),
+        );
+        let counters = contract.nft_stats("0".to_string()).counters;
+        assert_eq!(counters.get("wins"), Some(&U64(4)));
+        assert_eq!(counters.get("losses"), Some(&U64(1)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unauthorized")]
+    fn test_add_xp_by_token_owner() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1));
+        contract.add_xp("0".to_string(), U64(1_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "Thresholds must be sorted by level and xp")]
+    fn test_unsorted_thresholds() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(0));
+        contract.set_level_thresholds(vec![threshold(1, 500, None), threshold(2, 100, None)]);
+    }
+}
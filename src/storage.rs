@@ -0,0 +1,358 @@
+/*!
+Storage accounting helpers.
+
+The estimates returned here are derived from the exact layout `NonFungibleToken` writes to the
+trie, so a frontend can attach precisely the deposit that `nft_mint` and `nft_approve` will
+require instead of guessing.
+
+Calls which can only carry 1 yoctoNEAR, like `nft_transfer`, pay for any extra storage they
+write from a prepaid balance topped up with `nft_storage_deposit`.
+*/
+use crate::history::{HistoryEntry, HistoryEvent};
+use crate::{Contract, ContractExt, StorageKey};
+use near_contract_standards::non_fungible_token::core::StorageKey as TokensStorageKey;
+use near_contract_standards::non_fungible_token::metadata::TokenMetadata;
+use near_contract_standards::non_fungible_token::{bytes_for_approved_account_id, TokenId};
+use near_sdk::borsh::{self, BorshSerialize};
+use near_sdk::{
+    assert_one_yocto, env, near, require, AccountId, IntoStorageKey, NearToken, Promise,
+    StorageUsage,
+};
+
+/// Bytes the runtime accounts for every key-value record on top of the key and value lengths
+/// (`num_extra_bytes_record` in the NEAR runtime config).
+pub(crate) const STORAGE_RECORD_OVERHEAD: StorageUsage = 40;
+
+/// Size of a `u64` as written by Borsh, used for vector indexes and tree links.
+const U64_LEN: StorageUsage = 8;
+
+/// Number of bytes `value` takes once serialized with Borsh.
+pub(crate) fn borsh_len<T: BorshSerialize>(value: &T) -> StorageUsage {
+    borsh::to_vec(value)
+        .unwrap_or_else(|_| env::panic_str("Cannot serialize value"))
+        .len() as StorageUsage
+}
+
+/// Storage taken by a single trie record with the given key and value lengths.
+pub(crate) fn record_bytes(key_len: StorageUsage, value_len: StorageUsage) -> StorageUsage {
+    STORAGE_RECORD_OVERHEAD + key_len + value_len
+}
+
+/// Cost in yoctoNEAR of `bytes` of storage at the current protocol price.
+pub(crate) fn storage_cost(bytes: StorageUsage) -> NearToken {
+    env::storage_byte_cost().saturating_mul(bytes.into())
+}
+
+pub(crate) fn prefix_len<S: IntoStorageKey>(prefix: S) -> StorageUsage {
+    prefix.into_storage_key().len() as StorageUsage
+}
+
+/// Bytes inserting a new key into a `TreeMap` adds. The value is stored in a `LookupMap`
+/// (prefix + `v`) and the key in an AVL node pushed onto a `Vector` (prefix + `n`). A new node is
+/// written with both links empty; its parent, if any, gains exactly one `Some` link, whatever
+/// rotations follow.
+pub(crate) fn tree_map_insert_bytes(
+    prefix_len: StorageUsage,
+    key_len: StorageUsage,
+    value_len: StorageUsage,
+    is_empty: bool,
+) -> StorageUsage {
+    // Value record, then the node: id, key, two empty links and the height.
+    let mut bytes = record_bytes(prefix_len + 1 + key_len, value_len);
+    bytes += record_bytes(
+        prefix_len + 1 + U64_LEN,
+        U64_LEN + key_len + 1 + 1 + U64_LEN,
+    );
+    if !is_empty {
+        bytes += U64_LEN;
+    }
+    bytes
+}
+
+impl Contract {
+    /// Bytes that minting `token_id` to `owner_id` with `metadata` adds to the trie.
+    ///
+    /// Mirrors `NonFungibleToken::internal_mint_with_refund`:
+    /// * `owner_by_id` is a `TreeMap`, see `tree_map_insert_bytes`.
+    /// * the metadata is a plain `LookupMap` record.
+    /// * the enumeration extension keeps an `UnorderedSet` per owner, created on first mint.
+    ///
+    /// On top of that come the records of the contract's own extensions, such as the history,
+    /// snapshot checkpoints and expiry index.
+    pub(crate) fn mint_storage_bytes(
+        &self,
+        token_id: &TokenId,
+        owner_id: &AccountId,
+        metadata: &TokenMetadata,
+    ) -> StorageUsage {
+        let token_id_len = borsh_len(token_id);
+        let owner_id_len = borsh_len(owner_id);
+        let mut bytes = tree_map_insert_bytes(
+            prefix_len(StorageKey::NonFungibleToken),
+            token_id_len,
+            owner_id_len,
+            self.tokens.owner_by_id.is_empty(),
+        );
+
+        bytes += record_bytes(
+            prefix_len(StorageKey::TokenMetadata) + token_id_len,
+            borsh_len(metadata),
+        );
+
+        if let Some(tokens_per_owner) = &self.tokens.tokens_per_owner {
+            let set_prefix_len = prefix_len(TokensStorageKey::TokensPerOwner {
+                account_hash: env::sha256(owner_id.as_bytes()),
+            });
+            if tokens_per_owner.get(owner_id).is_none() {
+                // `UnorderedSet` is serialized as its index prefix plus a `Vector` (len, prefix).
+                let set_len = (4 + set_prefix_len + 1) + (U64_LEN + 4 + set_prefix_len + 1);
+                bytes += record_bytes(prefix_len(StorageKey::Enumeration) + owner_id_len, set_len);
+            }
+            // Element index record and the element itself.
+            bytes += record_bytes(set_prefix_len + 1 + token_id_len, U64_LEN);
+            bytes += record_bytes(set_prefix_len + 1 + U64_LEN, token_id_len);
+        }
+
+        let mint_entry = HistoryEntry::new(HistoryEvent::Mint, None, Some(owner_id), None);
+        let history_delta = self.history_entry_storage_delta(token_id, &mint_entry);
+
+        bytes += self.mint_checkpoint_storage_bytes(token_id, owner_id);
+        bytes += self.expiry_index_storage_bytes(token_id, metadata);
+
+        (bytes as i64 + history_delta) as StorageUsage
+    }
+
+    /// Settles the storage used since `initial_storage_usage` against the prepaid balance of
+    /// `account_id`: growth is debited and released storage credited back.
+    pub(crate) fn settle_prepaid_storage(
+        &mut self,
+        account_id: &AccountId,
+        initial_storage_usage: StorageUsage,
+    ) {
+        let final_storage_usage = env::storage_usage();
+        if final_storage_usage == initial_storage_usage {
+            return;
+        }
+        let balance = self
+            .storage_balances
+            .get(account_id)
+            .unwrap_or(NearToken::from_yoctonear(0));
+        let balance = if final_storage_usage > initial_storage_usage {
+            let required = storage_cost(final_storage_usage - initial_storage_usage);
+            require!(
+                required <= balance,
+                format!(
+                    "Prepaid storage balance of {} is too low, {} more is required",
+                    account_id,
+                    required.saturating_sub(balance).exact_amount_display()
+                )
+            );
+            balance.saturating_sub(required)
+        } else {
+            balance.saturating_add(storage_cost(initial_storage_usage - final_storage_usage))
+        };
+        self.storage_balances.insert(account_id, &balance);
+    }
+}
+
+#[near]
+impl Contract {
+    /// Adds the attached deposit to the prepaid storage balance of `account_id` (the caller by
+    /// default). The first deposit for an account also pays for the balance record itself.
+    #[payable]
+    pub fn nft_storage_deposit(&mut self, account_id: Option<AccountId>) -> NearToken {
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let initial_storage_usage = env::storage_usage();
+        let balance = self
+            .storage_balances
+            .get(&account_id)
+            .unwrap_or(NearToken::from_yoctonear(0));
+        self.storage_balances.insert(&account_id, &balance);
+        let record_cost = storage_cost(env::storage_usage() - initial_storage_usage);
+
+        let deposit = env::attached_deposit();
+        require!(
+            deposit > record_cost,
+            format!(
+                "Must attach more than {} to open a storage balance",
+                record_cost.exact_amount_display()
+            )
+        );
+        let balance = balance.saturating_add(deposit.saturating_sub(record_cost));
+        self.storage_balances.insert(&account_id, &balance);
+        balance
+    }
+
+    /// Withdraws `amount` (everything by default) from the caller's prepaid storage balance.
+    #[payable]
+    pub fn nft_storage_withdraw(&mut self, amount: Option<NearToken>) -> NearToken {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let balance = self
+            .storage_balances
+            .get(&account_id)
+            .unwrap_or(NearToken::from_yoctonear(0));
+        let amount = amount.unwrap_or(balance);
+        require!(amount <= balance, "Prepaid storage balance is too low");
+        let balance = balance.saturating_sub(amount);
+        self.storage_balances.insert(&account_id, &balance);
+        if !amount.is_zero() {
+            Promise::new(account_id).transfer(amount);
+        }
+        balance
+    }
+
+    pub fn nft_storage_balance_of(&self, account_id: AccountId) -> NearToken {
+        self.storage_balances
+            .get(&account_id)
+            .unwrap_or(NearToken::from_yoctonear(0))
+    }
+
+    /// Exact deposit `nft_mint` requires to store `token_id` owned by `owner_id` with `metadata`.
+    pub fn estimate_mint_storage(
+        &self,
+        token_id: TokenId,
+        owner_id: AccountId,
+        metadata: TokenMetadata,
+    ) -> NearToken {
+        storage_cost(self.mint_storage_bytes(&token_id, &owner_id, &metadata))
+    }
+
+    /// Exact deposit `nft_approve` requires to add `account_id` as a new approved account.
+    /// Re-approving an account which is already approved for the token is free.
+    pub fn estimate_approve_storage(&self, account_id: AccountId) -> NearToken {
+        storage_cost(bytes_for_approved_account_id(&account_id))
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_contract_standards::non_fungible_token::approval::NonFungibleTokenApproval;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    fn token_metadata(title: &str) -> TokenMetadata {
+        TokenMetadata {
+            title: Some(title.into()),
+            ..sample_token_metadata()
+        }
+    }
+
+    /// Mints through `nft_mint` and returns the cost of the storage it actually used.
+    fn mint_and_measure(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        token_id: &str,
+        owner_id: AccountId,
+        metadata: TokenMetadata,
+    ) -> NearToken {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .predecessor_account_id(accounts(0))
+            .build());
+        let before = env::storage_usage();
+        contract.nft_mint(token_id.to_string(), owner_id, metadata, None);
+        storage_cost(env::storage_usage() - before)
+    }
+
+    #[test]
+    fn test_estimate_mint_storage_matches_actual() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+
+        // First token of the contract and first token of the owner, then a token for an owner
+        // who already holds one, then a long id for a brand new owner.
+        let cases = [
+            ("0", accounts(1), token_metadata("Olympus Mons")),
+            ("1", accounts(1), token_metadata("Mauna Kea")),
+            (
+                "a-much-longer-token-identifier",
+                accounts(2),
+                token_metadata("Everest"),
+            ),
+        ];
+        for (token_id, owner_id, metadata) in cases {
+            let estimate = contract.estimate_mint_storage(
+                token_id.to_string(),
+                owner_id.clone(),
+                metadata.clone(),
+            );
+            let actual =
+                mint_and_measure(&mut context, &mut contract, token_id, owner_id, metadata);
+            assert_eq!(estimate, actual);
+        }
+    }
+
+    #[test]
+    fn test_estimate_mint_storage_is_enough_deposit() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+
+        let estimate =
+            contract.estimate_mint_storage("0".to_string(), accounts(1), token_metadata("Olympus"));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(estimate)
+            .predecessor_account_id(accounts(0))
+            .build());
+        contract.nft_mint(
+            "0".to_string(),
+            accounts(1),
+            token_metadata("Olympus"),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_estimate_approve_storage_matches_charge() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        mint_and_measure(
+            &mut context,
+            &mut contract,
+            "0",
+            accounts(0),
+            token_metadata("Olympus"),
+        );
+
+        // Attaching exactly the estimate must succeed and leave nothing to refund.
+        let estimate = contract.estimate_approve_storage(accounts(1));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(estimate)
+            .predecessor_account_id(accounts(0))
+            .build());
+        contract.nft_approve("0".to_string(), accounts(1), None);
+        assert!(near_sdk::test_utils::get_created_receipts().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Must attach")]
+    fn test_approve_below_estimate_panics() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        mint_and_measure(
+            &mut context,
+            &mut contract,
+            "0",
+            accounts(0),
+            token_metadata("Olympus"),
+        );
+
+        let estimate = contract.estimate_approve_storage(accounts(1));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(estimate.saturating_sub(NearToken::from_yoctonear(1)))
+            .predecessor_account_id(accounts(0))
+            .build());
+        contract.nft_approve("0".to_string(), accounts(1), None);
+    }
+}
@@ -0,0 +1,36 @@
+/*!
+Fixtures shared by the unit tests of the contract and its extensions.
+*/
+use near_contract_standards::non_fungible_token::metadata::TokenMetadata;
+use near_sdk::test_utils::{accounts, VMContextBuilder};
+use near_sdk::{AccountId, NearToken};
+
+/// Covers the storage of any token minted in the unit tests. The excess is refunded.
+pub(crate) const MINT_STORAGE_COST: NearToken = NearToken::from_millinear(100);
+
+/// Context of a call from `predecessor_account_id` to the contract deployed at accounts(0).
+pub(crate) fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id);
+    builder
+}
+
+pub(crate) fn sample_token_metadata() -> TokenMetadata {
+    TokenMetadata {
+        title: Some("Olympus Mons".into()),
+        description: Some("The tallest mountain in the charted solar system".into()),
+        media: None,
+        media_hash: None,
+        copies: Some(1u64),
+        issued_at: None,
+        expires_at: None,
+        starts_at: None,
+        updated_at: None,
+        extra: None,
+        reference: None,
+        reference_hash: None,
+    }
+}
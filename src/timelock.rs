@@ -0,0 +1,394 @@
+/*!
+Time-locked admin actions.
+
+By default the owner calls owner-only methods directly. Once the owner sets an `admin_delay`,
+owner-only methods can only be reached through the queue: the owner queues a call to one of them
+with `queue_action`, anyone can review it with `pending_actions`, the owner can cancel it during
+the delay, and anyone can `execute_action` it once the delay passed. The queued call is made by
+the contract to itself, which is what `assert_owner` then accepts.
+
+Only the owner-only methods listed in `TIMELOCKED_METHODS` can be queued, so that the queue
+cannot be used to make the contract call any of its other methods as itself.
+
+Changing or removing the delay is itself an owner-only action, so it goes through the queue as
+well, and so is `upgrade`, which deploys new code to the contract. The owner must be an account
+other than the contract for the delay to be enforceable.
+*/
+use crate::storage::storage_cost;
+use crate::{Contract, ContractExt};
+use near_sdk::json_types::{Base64VecU8, U128, U64};
+use near_sdk::{assert_one_yocto, env, near, require, Gas, NearToken, Promise};
+
+/// Owner-only methods, i.e. the methods calling `assert_owner`, which can be queued.
+pub const TIMELOCKED_METHODS: &[&str] = &[
+    "set_base_uri",
+    "set_relative_uris",
+    "migrate_to_relative_uris",
+    "enable_clawback",
+    "renounce_clawback",
+    "propose_force_transfer",
+    "cancel_force_transfer",
+    "nft_force_transfer",
+    "set_proposal_threshold",
+    "set_proposal_bond",
+    "approve_proposal_action",
+    "set_history_max_entries",
+    "set_membership_config",
+    "treasury_withdraw",
+    "set_metadata_rules",
+    "load_mint_pool",
+    "set_random_mint_price",
+    "set_multisig",
+    "set_redemption_lock",
+    "set_svg_canvas",
+    "upload_svg_layers",
+    "remove_svg_layer",
+    "commit_reveal",
+    "upload_reveal_metadata",
+    "reset_reveal_upload",
+    "grant_role",
+    "revoke_role",
+    "take_snapshot",
+    "sponsor_pool_deposit",
+    "sponsor_pool_withdraw",
+    "set_sponsor_daily_budget",
+    "set_reward_rate",
+    "set_series_reward_rate",
+    "set_reward_token",
+    "set_level_thresholds",
+    "set_admin_delay",
+    "set_transfer_policy_mode",
+    "add_transfer_policy_accounts",
+    "remove_transfer_policy_accounts",
+    "set_series_transfer_policy_exempt",
+    "set_validity_enforcement",
+    "add_vault_contracts",
+    "remove_vault_contracts",
+    "upgrade",
+];
+
+pub(crate) fn assert_timelocked_method(method_name: &str) {
+    require!(
+        TIMELOCKED_METHODS.contains(&method_name),
+        format!("{} is not an owner-only method", method_name)
+    );
+}
+
+/// Call of an owner-only method of this contract, executable from `executable_at`.
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueuedAction {
+    pub id: u64,
+    pub method_name: String,
+    pub args: Base64VecU8,
+    /// Attached to the call, held by the contract meanwhile.
+    pub deposit: NearToken,
+    pub gas: U64,
+    /// Block timestamp in nanoseconds.
+    pub executable_at: U64,
+}
+
+impl Contract {
+    fn queued_action_or_panic(&self, action_id: u64) -> QueuedAction {
+        self.queued_actions
+            .get(&action_id)
+            .unwrap_or_else(|| env::panic_str("Action not found"))
+    }
+}
+
+#[near]
+impl Contract {
+    /// Sets how long owner actions wait in the queue, in nanoseconds. 0 makes them immediate.
+    pub fn set_admin_delay(&mut self, delay: U64) {
+        self.assert_owner();
+        require!(
+            delay.0 == 0 || self.tokens.owner_id != env::current_account_id(),
+            "The owner must be a separate account to time-lock its actions"
+        );
+        require!(
+            delay.0 == 0 || self.multisig.is_none(),
+            "Multisig actions cannot be time-locked"
+        );
+        self.admin_delay = delay.0;
+    }
+
+    pub fn admin_delay(&self) -> U64 {
+        self.admin_delay.into()
+    }
+
+    /// Deploys `code` to this contract. Owner-only, so it waits in the queue like any other
+    /// owner action once an admin delay is set.
+    pub fn upgrade(&mut self, code: Base64VecU8) -> Promise {
+        self.assert_owner();
+        Promise::new(env::current_account_id()).deploy_contract(code.0)
+    }
+
+    /// Queues a call to the owner-only `method_name` with `args`. The attached deposit must cover
+    /// `deposit`, which is attached to the call, and the storage of the queue entry.
+    #[payable]
+    pub fn queue_action(
+        &mut self,
+        method_name: String,
+        args: Base64VecU8,
+        deposit: Option<NearToken>,
+        gas: Option<U64>,
+    ) -> QueuedAction {
+        self.assert_admin();
+        assert_timelocked_method(&method_name);
+        let deposit = deposit.unwrap_or(NearToken::from_yoctonear(0));
+
+        let initial_storage_usage = env::storage_usage();
+        let action = QueuedAction {
+            id: self.next_action_id,
+            method_name,
+            args,
+            deposit,
+            gas: gas.unwrap_or(U64(Gas::from_tgas(30).as_gas())),
+            executable_at: env::block_timestamp()
+                .saturating_add(self.admin_delay)
+                .into(),
+        };
+        self.queued_actions.insert(&action.id, &action);
+        self.next_action_id += 1;
+        let required =
+            deposit.saturating_add(storage_cost(env::storage_usage() - initial_storage_usage));
+        let attached = env::attached_deposit();
+        require!(
+            attached >= required,
+            format!(
+                "Must attach {} to queue the action",
+                required.exact_amount_display()
+            )
+        );
+        let refund = attached.saturating_sub(required);
+        if !refund.is_zero() {
+            Promise::new(env::predecessor_account_id()).transfer(refund);
+        }
+        action
+    }
+
+    /// Drops a queued action, refunding its deposit and storage to the owner. Requires 1
+    /// yoctoNEAR, which is refunded along.
+    #[payable]
+    pub fn cancel_action(&mut self, action_id: u64) -> Promise {
+        assert_one_yocto();
+        self.assert_admin();
+        let action = self.queued_action_or_panic(action_id);
+        let initial_storage_usage = env::storage_usage();
+        self.queued_actions.remove(&action_id);
+        let refund = action
+            .deposit
+            .saturating_add(storage_cost(initial_storage_usage - env::storage_usage()))
+            .saturating_add(env::attached_deposit());
+        Promise::new(self.tokens.owner_id.clone()).transfer(refund)
+    }
+
+    /// Makes the call of a queued action whose delay passed. Anyone can call it.
+    pub fn execute_action(&mut self, action_id: u64) -> Promise {
+        let action = self.queued_action_or_panic(action_id);
+        assert_timelocked_method(&action.method_name);
+        require!(
+            env::block_timestamp() >= action.executable_at.0,
+            "Action is still time-locked"
+        );
+        let initial_storage_usage = env::storage_usage();
+        self.queued_actions.remove(&action_id);
+        Promise::new(self.tokens.owner_id.clone())
+            .transfer(storage_cost(initial_storage_usage - env::storage_usage()));
+        Promise::new(env::current_account_id()).function_call(
+            action.method_name,
+            action.args.0,
+            action.deposit,
+            Gas::from_gas(action.gas.0),
+        )
+    }
+
+    /// Queued actions, in the order they were queued.
+    pub fn pending_actions(
+        &self,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+    ) -> Vec<QueuedAction> {
+        let start = u128::from(from_index.unwrap_or(U128(0)));
+        self.queued_actions
+            .iter()
+            .skip(start as usize)
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .map(|(_, action)| action)
+            .collect()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::roles::Role;
+    use crate::test_utils::get_context;
+    use near_sdk::test_utils::{accounts, get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, AccountId};
+
+    use super::*;
+
+    const DELAY: u64 = 1_000;
+    const QUEUE_STORAGE_COST: NearToken = NearToken::from_millinear(10);
+
+    fn call(context: &mut VMContextBuilder, predecessor_id: AccountId, now: u64) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .block_timestamp(now)
+            .attached_deposit(QUEUE_STORAGE_COST)
+            .predecessor_account_id(predecessor_id)
+            .build());
+    }
+
+    /// Contract owned by accounts(1), with owner actions delayed by `DELAY` and a queued grant
+    /// of the minter role to accounts(2).
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1));
+        call(context, accounts(1), 0);
+        contract.set_admin_delay(U64(DELAY));
+        contract.queue_action(
+            "grant_role".to_string(),
+            Base64VecU8(
+                format!(r#"{{"role":"Minter","account_id":"{}"}}"#, accounts(2)).into_bytes(),
+            ),
+            None,
+            None,
+        );
+        contract
+    }
+
+    #[test]
+    fn test_queue_and_execute() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup(&mut context);
+        let pending = contract.pending_actions(None, None);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].method_name, "grant_role");
+        assert_eq!(pending[0].executable_at, U64(DELAY));
+
+        call(&mut context, accounts(3), DELAY);
+        contract.execute_action(0);
+        assert!(contract.pending_actions(None, None).is_empty());
+        assert!(get_created_receipts()
+            .iter()
+            .any(|receipt| receipt.receiver_id == accounts(0)));
+
+        // The queued call reaches the contract from itself.
+        call(&mut context, accounts(0), DELAY);
+        contract.grant_role(Role::Minter, accounts(2));
+        assert!(contract.has_role(Role::Minter, accounts(2)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Owner actions are time-locked")]
+    fn test_direct_owner_call_is_rejected() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), 0);
+        contract.grant_role(Role::Minter, accounts(2));
+    }
+
+    #[test]
+    fn test_queued_upgrade() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), 0);
+        contract.queue_action(
+            "upgrade".to_string(),
+            Base64VecU8(br#"{"code":"AGFzbQEAAAA="}"#.to_vec()),
+            None,
+            Some(U64(Gas::from_tgas(100).as_gas())),
+        );
+        call(&mut context, accounts(3), DELAY);
+        contract.execute_action(1);
+
+        call(&mut context, accounts(0), DELAY);
+        contract.upgrade(Base64VecU8(b"\0asm\x01\0\0\0".to_vec()));
+        assert!(get_created_receipts().iter().any(|receipt| {
+            receipt.receiver_id == accounts(0)
+                && matches!(
+                    receipt.actions.as_slice(),
+                    [near_sdk::mock::MockAction::DeployContract { .. }]
+                )
+        }));
+    }
+
+    #[test]
+    #[should_panic(expected = "Owner actions are time-locked")]
+    fn test_direct_upgrade_is_rejected() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), 0);
+        contract.upgrade(Base64VecU8(b"\0asm\x01\0\0\0".to_vec()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_cancel_action_without_deposit() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup(&mut context);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(NearToken::from_yoctonear(0))
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.cancel_action(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "nft_transfer is not an owner-only method")]
+    fn test_queue_non_owner_method() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), 0);
+        contract.queue_action(
+            "nft_transfer".to_string(),
+            Base64VecU8(br#"{"receiver_id":"alice","token_id":"0"}"#.to_vec()),
+            Some(NearToken::from_yoctonear(1)),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_pending_actions_pagination() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(1), 0);
+        contract.queue_action(
+            "take_snapshot".to_string(),
+            Base64VecU8(b"{}".to_vec()),
+            None,
+            None,
+        );
+        let page = contract.pending_actions(Some(U128(1)), Some(1));
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].method_name, "take_snapshot");
+        assert_eq!(contract.pending_actions(None, Some(1))[0].id, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Action is still time-locked")]
+    fn test_execute_before_delay() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(3), DELAY - 1);
+        contract.execute_action(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Action not found")]
+    fn test_cancel_action() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup(&mut context);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(NearToken::from_yoctonear(1))
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.cancel_action(0);
+        assert!(contract.pending_actions(None, None).is_empty());
+        call(&mut context, accounts(3), DELAY);
+        contract.execute_action(0);
+    }
+}
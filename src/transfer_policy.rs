@@ -0,0 +1,244 @@
+/*!
+Transfer policy for approved senders.
+
+Creators can restrict which accounts may transfer tokens on behalf of their owners, e.g. to
+marketplaces known to pay royalties. Depending on the mode, the policy accounts are either the
+only accounts allowed to use an approval (`Allowlist`) or the accounts which cannot use one
+(`Denylist`). Owners moving their own tokens are never restricted.
+
+A series (the part of a token id before the first `:`) can opt out of the policy.
+*/
+use crate::staking::series_of;
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::TokenId;
+use near_sdk::json_types::U128;
+use near_sdk::{env, near, require, AccountId};
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferPolicyMode {
+    /// Any approved account can transfer.
+    Open,
+    /// Only the policy accounts can transfer with an approval.
+    Allowlist,
+    /// The policy accounts cannot transfer with an approval.
+    Denylist,
+}
+
+#[near(serializers = [json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferPolicy {
+    pub mode: TransferPolicyMode,
+    pub accounts_count: u64,
+}
+
+impl Contract {
+    fn is_sender_allowed(&self, token_id: &TokenId, sender_id: &AccountId) -> bool {
+        if self.transfer_policy_mode == TransferPolicyMode::Open
+            || self
+                .transfer_policy_exempt_series
+                .contains(&series_of(token_id).to_string())
+        {
+            return true;
+        }
+        let listed = self.transfer_policy_accounts.contains(sender_id);
+        match self.transfer_policy_mode {
+            TransferPolicyMode::Allowlist => listed,
+            _ => !listed,
+        }
+    }
+
+    /// Panics if the caller transfers `token_id` of `owner_id` with an approval the policy does
+    /// not honor.
+    pub(crate) fn assert_sender_allowed(&self, token_id: &TokenId, owner_id: &AccountId) {
+        let sender_id = env::predecessor_account_id();
+        if &sender_id != owner_id {
+            require!(
+                self.is_sender_allowed(token_id, &sender_id),
+                "Sender is not allowed by the transfer policy"
+            );
+        }
+    }
+}
+
+#[near]
+impl Contract {
+    pub fn set_transfer_policy_mode(&mut self, mode: TransferPolicyMode) {
+        self.assert_owner();
+        self.transfer_policy_mode = mode;
+    }
+
+    /// Adds `account_ids` to the policy accounts, allowed or denied depending on the mode.
+    pub fn add_transfer_policy_accounts(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for account_id in account_ids {
+            self.transfer_policy_accounts.insert(&account_id);
+        }
+    }
+
+    pub fn remove_transfer_policy_accounts(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for account_id in account_ids {
+            self.transfer_policy_accounts.remove(&account_id);
+        }
+    }
+
+    /// Exempts tokens of `series` from the policy, or subjects them to it again.
+    pub fn set_series_transfer_policy_exempt(&mut self, series: String, exempt: bool) {
+        self.assert_owner();
+        if exempt {
+            self.transfer_policy_exempt_series.insert(&series);
+        } else {
+            self.transfer_policy_exempt_series.remove(&series);
+        }
+    }
+
+    pub fn transfer_policy(&self) -> TransferPolicy {
+        TransferPolicy {
+            mode: self.transfer_policy_mode,
+            accounts_count: self.transfer_policy_accounts.len(),
+        }
+    }
+
+    pub fn transfer_policy_accounts(
+        &self,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+    ) -> Vec<AccountId> {
+        let start = u128::from(from_index.unwrap_or(U128(0)));
+        self.transfer_policy_accounts
+            .iter()
+            .skip(start as usize)
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .collect()
+    }
+
+    pub fn is_series_transfer_policy_exempt(&self, series: String) -> bool {
+        self.transfer_policy_exempt_series.contains(&series)
+    }
+
+    /// Whether `sender_id` may transfer `token_id` with an approval under the current policy.
+    pub fn is_transfer_allowed(&self, token_id: TokenId, sender_id: AccountId) -> bool {
+        self.is_sender_allowed(&token_id, &sender_id)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_contract_standards::non_fungible_token::approval::NonFungibleTokenApproval;
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, NearToken};
+
+    use super::*;
+
+    const ONE_YOCTONEAR: NearToken = NearToken::from_yoctonear(1);
+
+    fn call(context: &mut VMContextBuilder, predecessor_id: AccountId, deposit: NearToken) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(deposit)
+            .predecessor_account_id(predecessor_id)
+            .build());
+    }
+
+    /// Tokens "1:1" and "2:1" of accounts(1), both approved for accounts(2) and accounts(3),
+    /// with accounts(2) as the only allowed marketplace.
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        contract.set_transfer_policy_mode(TransferPolicyMode::Allowlist);
+        contract.add_transfer_policy_accounts(vec![accounts(2)]);
+        for token_id in ["1:1", "2:1"] {
+            call(context, accounts(0), MINT_STORAGE_COST);
+            contract.nft_mint(
+                token_id.to_string(),
+                accounts(1),
+                sample_token_metadata(),
+                None,
+            );
+            for marketplace in [accounts(2), accounts(3)] {
+                call(context, accounts(1), MINT_STORAGE_COST);
+                contract.nft_approve(token_id.to_string(), marketplace, None);
+            }
+        }
+        contract
+    }
+
+    fn transfer(context: &mut VMContextBuilder, contract: &mut Contract, sender: AccountId) {
+        call(context, sender, ONE_YOCTONEAR);
+        contract.nft_transfer(accounts(4), "1:1".to_string(), None, None);
+    }
+
+    #[test]
+    fn test_allowlisted_marketplace_can_transfer() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        transfer(&mut context, &mut contract, accounts(2));
+        assert_eq!(
+            contract.nft_token("1:1".to_string()).unwrap().owner_id,
+            accounts(4)
+        );
+        assert_eq!(
+            contract.transfer_policy(),
+            TransferPolicy {
+                mode: TransferPolicyMode::Allowlist,
+                accounts_count: 1,
+            }
+        );
+        assert_eq!(
+            contract.transfer_policy_accounts(None, None),
+            vec![accounts(2)]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Sender is not allowed by the transfer policy")]
+    fn test_other_marketplace_cannot_transfer() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        transfer(&mut context, &mut contract, accounts(3));
+    }
+
+    #[test]
+    fn test_owner_is_never_restricted() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        transfer(&mut context, &mut contract, accounts(1));
+        assert_eq!(
+            contract.nft_token("1:1".to_string()).unwrap().owner_id,
+            accounts(4)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Sender is not allowed by the transfer policy")]
+    fn test_denylist() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_transfer_policy_mode(TransferPolicyMode::Denylist);
+        assert!(contract.is_transfer_allowed("1:1".to_string(), accounts(3)));
+        transfer(&mut context, &mut contract, accounts(2));
+    }
+
+    #[test]
+    fn test_exempt_series() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_series_transfer_policy_exempt("2".to_string(), true);
+        assert!(contract.is_series_transfer_policy_exempt("2".to_string()));
+        assert!(contract.is_transfer_allowed("2:1".to_string(), accounts(3)));
+        assert!(!contract.is_transfer_allowed("1:1".to_string(), accounts(3)));
+
+        call(&mut context, accounts(3), ONE_YOCTONEAR);
+        contract.nft_transfer(accounts(4), "2:1".to_string(), None, None);
+        assert_eq!(
+            contract.nft_token("2:1".to_string()).unwrap().owner_id,
+            accounts(4)
+        );
+    }
+}
@@ -0,0 +1,349 @@
+/*!
+Validity windows from `TokenMetadata.starts_at` and `expires_at`.
+
+Both fields hold a Unix timestamp in milliseconds, as described by NEP-177. They are validated at
+mint, and tokens with an `expires_at` are indexed by expiry so that expired tickets can be found
+without scanning the whole collection.
+
+A token is active from `starts_at` (inclusive) until `expires_at` (exclusive). What happens to an
+inactive token is up to the owner, see `ValidityEnforcement`.
+*/
+use crate::storage::{borsh_len, prefix_len, tree_map_insert_bytes};
+use crate::{Contract, ContractExt, StorageKey};
+use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+use near_contract_standards::non_fungible_token::metadata::TokenMetadata;
+use near_contract_standards::non_fungible_token::{Token, TokenId};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{env, near, require, StorageUsage};
+use std::ops::Bound;
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidityEnforcement {
+    /// Validity windows are informative only.
+    Disabled,
+    /// Inactive tokens can neither be transferred nor burnt.
+    NonTransferable,
+    /// Inactive tokens cannot be transferred but can still be burnt.
+    BurnOnly,
+}
+
+fn parse_timestamp(value: &Option<String>, field: &str) -> Option<u64> {
+    value.as_ref().map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| env::panic_str(&format!("{} must be a timestamp in ms", field)))
+    })
+}
+
+/// `starts_at` and `expires_at` of `metadata` in milliseconds.
+fn validity_window(metadata: &TokenMetadata) -> (Option<u64>, Option<u64>) {
+    (
+        parse_timestamp(&metadata.starts_at, "starts_at"),
+        parse_timestamp(&metadata.expires_at, "expires_at"),
+    )
+}
+
+/// Panics if `starts_at` or `expires_at` is not a timestamp, or if they do not form a range.
+pub(crate) fn assert_valid_validity_window(metadata: &TokenMetadata) {
+    if let (Some(starts_at), Some(expires_at)) = validity_window(metadata) {
+        require!(
+            starts_at < expires_at,
+            "starts_at must be before expires_at"
+        );
+    }
+}
+
+impl Contract {
+    fn token_metadata(&self, token_id: &TokenId) -> Option<TokenMetadata> {
+        self.tokens
+            .token_metadata_by_id
+            .as_ref()
+            .and_then(|by_id| by_id.get(token_id))
+    }
+
+    fn is_active(&self, token_id: &TokenId) -> bool {
+        let (starts_at, expires_at) = self
+            .token_metadata(token_id)
+            .map_or((None, None), |metadata| validity_window(&metadata));
+        let now = env::block_timestamp_ms();
+        starts_at.is_none_or(|starts_at| now >= starts_at)
+            && expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+
+    pub(crate) fn assert_transferable_in_time(&self, token_id: &TokenId) {
+        if self.validity_enforcement != ValidityEnforcement::Disabled {
+            require!(self.is_active(token_id), "Token is not active");
+        }
+    }
+
+    pub(crate) fn assert_burnable_in_time(&self, token_id: &TokenId) {
+        if self.validity_enforcement == ValidityEnforcement::NonTransferable {
+            require!(self.is_active(token_id), "Token is not active");
+        }
+    }
+
+    pub(crate) fn index_expiry(&mut self, token_id: &TokenId, metadata: &TokenMetadata) {
+        if let (_, Some(expires_at)) = validity_window(metadata) {
+            self.expiring_tokens
+                .insert(&(expires_at, token_id.clone()), &());
+        }
+    }
+
+    /// Removes `token_id` from the expiry index. Must be called before its metadata is removed.
+    pub(crate) fn unindex_expiry(&mut self, token_id: &TokenId) {
+        let expires_at = self
+            .token_metadata(token_id)
+            .and_then(|metadata| validity_window(&metadata).1);
+        if let Some(expires_at) = expires_at {
+            self.expiring_tokens.remove(&(expires_at, token_id.clone()));
+        }
+    }
+
+    /// Bytes `index_expiry` adds for a new token, used by the mint storage estimate.
+    pub(crate) fn expiry_index_storage_bytes(
+        &self,
+        token_id: &TokenId,
+        metadata: &TokenMetadata,
+    ) -> StorageUsage {
+        match validity_window(metadata) {
+            (_, Some(expires_at)) => tree_map_insert_bytes(
+                prefix_len(StorageKey::ExpiringTokens),
+                borsh_len(&(expires_at, token_id)),
+                0,
+                self.expiring_tokens.is_empty(),
+            ),
+            _ => 0,
+        }
+    }
+}
+
+#[near]
+impl Contract {
+    /// Sets what inactive tokens can still do.
+    pub fn set_validity_enforcement(&mut self, enforcement: ValidityEnforcement) {
+        self.assert_owner();
+        self.validity_enforcement = enforcement;
+    }
+
+    pub fn validity_enforcement(&self) -> ValidityEnforcement {
+        self.validity_enforcement
+    }
+
+    /// Whether the current time is within the validity window of `token_id`.
+    pub fn nft_is_active(&self, token_id: TokenId) -> bool {
+        self.owner_of(&token_id);
+        self.is_active(&token_id)
+    }
+
+    /// Tokens with an `expires_at` strictly before `timestamp` (in ms), soonest first.
+    pub fn nft_tokens_expiring_before(
+        &self,
+        timestamp: U64,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+    ) -> Vec<Token> {
+        let start = u128::from(from_index.unwrap_or(U128(0)));
+        let first = (0, String::new());
+        let end = (timestamp.0, String::new());
+        self.expiring_tokens
+            .range((Bound::Included(first), Bound::Excluded(end)))
+            .skip(start as usize)
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .filter_map(|((_, token_id), _)| self.tokens.nft_token(token_id))
+            .map(|token| self.token_view(token))
+            .collect()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::storage::storage_cost;
+    use crate::test_utils::{get_context, sample_token_metadata, MINT_STORAGE_COST};
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, AccountId, NearToken};
+
+    use super::*;
+
+    const ONE_YOCTONEAR: NearToken = NearToken::from_yoctonear(1);
+    const NANOSECONDS_PER_MILLISECOND: u64 = 1_000_000;
+
+    fn ticket_metadata(starts_at: Option<&str>, expires_at: Option<&str>) -> TokenMetadata {
+        TokenMetadata {
+            expires_at: expires_at.map(str::to_string),
+            starts_at: starts_at.map(str::to_string),
+            ..sample_token_metadata()
+        }
+    }
+
+    fn mint(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        token_id: &str,
+        metadata: TokenMetadata,
+    ) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(MINT_STORAGE_COST)
+            .predecessor_account_id(accounts(0))
+            .build());
+        contract.nft_mint(token_id.to_string(), accounts(1), metadata, None);
+    }
+
+    fn at_ms(context: &mut VMContextBuilder, ms: u64, predecessor_id: AccountId) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(ONE_YOCTONEAR)
+            .block_timestamp(ms * NANOSECONDS_PER_MILLISECOND)
+            .predecessor_account_id(predecessor_id)
+            .build());
+    }
+
+    /// Ticket "0" valid in [100, 200) ms, owned by accounts(1).
+    fn setup(context: &mut VMContextBuilder, enforcement: ValidityEnforcement) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        contract.set_validity_enforcement(enforcement);
+        mint(
+            context,
+            &mut contract,
+            "0",
+            ticket_metadata(Some("100"), Some("200")),
+        );
+        contract
+    }
+
+    #[test]
+    fn test_nft_is_active() {
+        let mut context = get_context(accounts(0));
+        let contract = setup(&mut context, ValidityEnforcement::Disabled);
+        for (ms, active) in [(99, false), (100, true), (199, true), (200, false)] {
+            at_ms(&mut context, ms, accounts(1));
+            assert_eq!(contract.nft_is_active("0".to_string()), active);
+        }
+    }
+
+    #[test]
+    fn test_disabled_enforcement_allows_transfer() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context, ValidityEnforcement::Disabled);
+        at_ms(&mut context, 300, accounts(1));
+        contract.nft_transfer(accounts(2), "0".to_string(), None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Token is not active")]
+    fn test_transfer_before_starts_at() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context, ValidityEnforcement::BurnOnly);
+        at_ms(&mut context, 50, accounts(1));
+        contract.nft_transfer(accounts(2), "0".to_string(), None, None);
+    }
+
+    #[test]
+    fn test_burn_only_allows_burning_expired_token() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context, ValidityEnforcement::BurnOnly);
+        at_ms(&mut context, 300, accounts(1));
+        contract.nft_burn("0".to_string());
+        assert!(contract
+            .nft_tokens_expiring_before(U64(1_000), None, None)
+            .is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Token is not active")]
+    fn test_non_transferable_blocks_burning_expired_token() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context, ValidityEnforcement::NonTransferable);
+        at_ms(&mut context, 300, accounts(1));
+        contract.nft_burn("0".to_string());
+    }
+
+    #[test]
+    fn test_tokens_expiring_before() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context, ValidityEnforcement::Disabled);
+        mint(
+            &mut context,
+            &mut contract,
+            "1",
+            ticket_metadata(None, Some("150")),
+        );
+        mint(
+            &mut context,
+            &mut contract,
+            "2",
+            ticket_metadata(None, None),
+        );
+        mint(
+            &mut context,
+            &mut contract,
+            "3",
+            ticket_metadata(None, Some("300")),
+        );
+
+        let token_ids = |tokens: Vec<Token>| {
+            tokens
+                .into_iter()
+                .map(|token| token.token_id)
+                .collect::<Vec<_>>()
+        };
+        assert!(contract
+            .nft_tokens_expiring_before(U64(0), None, None)
+            .is_empty());
+        assert_eq!(
+            token_ids(contract.nft_tokens_expiring_before(U64(200), None, None)),
+            vec!["1"]
+        );
+        assert_eq!(
+            token_ids(contract.nft_tokens_expiring_before(U64(301), None, None)),
+            vec!["1", "0", "3"]
+        );
+        assert_eq!(
+            token_ids(contract.nft_tokens_expiring_before(U64(301), Some(U128(1)), Some(1))),
+            vec!["0"]
+        );
+    }
+
+    #[test]
+    fn test_estimate_mint_storage_includes_expiry_index() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context, ValidityEnforcement::Disabled);
+        let metadata = ticket_metadata(None, Some("150"));
+        let estimate =
+            contract.estimate_mint_storage("1".to_string(), accounts(1), metadata.clone());
+        let initial_storage_usage = env::storage_usage();
+        mint(&mut context, &mut contract, "1", metadata);
+        assert_eq!(
+            estimate,
+            storage_cost(env::storage_usage() - initial_storage_usage)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "starts_at must be before expires_at")]
+    fn test_mint_with_inverted_range() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context, ValidityEnforcement::Disabled);
+        mint(
+            &mut context,
+            &mut contract,
+            "1",
+            ticket_metadata(Some("200"), Some("100")),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "expires_at must be a timestamp in ms")]
+    fn test_mint_with_malformed_timestamp() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context, ValidityEnforcement::Disabled);
+        mint(
+            &mut context,
+            &mut contract,
+            "1",
+            ticket_metadata(None, Some("2024-01-01")),
+        );
+    }
+}
@@ -0,0 +1,368 @@
+/*!
+Vault for NFTs of other NEP-171 contracts.
+
+Sending an external token to this contract with `nft_transfer_call` locks it in the vault and
+mints a wrapper token, `<contract id>:<token id>`, to its previous owner. Only NEP-171 contracts
+added by the owner with `add_vault_contracts` are accepted, as `nft_on_transfer` can only trust
+the arguments of a contract it knows, and only tokens sent by their owner themselves: tokens sent
+with an approval are returned. The wrapper is an
+ordinary token of this contract. Burning it sends the underlying token back to the account
+burning it with `nft_transfer`; if that transfer fails, `vault_retry_release` tries again.
+
+Since `nft_on_transfer` carries no deposit, the storage of the wrapper is paid from the prepaid
+storage balance (`nft_storage_deposit`) of the previous owner. If it is too low the deposit fails
+and the external contract returns the token.
+*/
+use crate::{Contract, ContractExt};
+use near_contract_standards::non_fungible_token::core::{ext_nft_core, NonFungibleTokenReceiver};
+use near_contract_standards::non_fungible_token::events::NftMint;
+use near_contract_standards::non_fungible_token::metadata::TokenMetadata;
+use near_contract_standards::non_fungible_token::TokenId;
+use near_sdk::json_types::U128;
+use near_sdk::{
+    env, near, require, AccountId, Gas, NearToken, Promise, PromiseError, PromiseOrValue,
+};
+
+const GAS_FOR_NFT_TRANSFER: Gas = Gas::from_tgas(15);
+const GAS_FOR_ON_VAULT_RELEASED: Gas = Gas::from_tgas(10);
+
+#[near(serializers = [borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct VaultEntry {
+    /// NEP-171 contract the underlying token belongs to.
+    pub contract_id: AccountId,
+    pub token_id: TokenId,
+    /// Account the underlying token is being sent to once the wrapper was burnt.
+    pub released_to: Option<AccountId>,
+}
+
+impl Contract {
+    /// Sends the underlying token of `wrapper_id`, whose wrapper was just burnt, to `receiver_id`.
+    pub(crate) fn release_from_vault(&mut self, wrapper_id: &TokenId, receiver_id: &AccountId) {
+        let Some(mut entry) = self.vault.get(wrapper_id) else {
+            return;
+        };
+        entry.released_to = Some(receiver_id.clone());
+        self.vault.insert(wrapper_id, &entry);
+        self.send_from_vault(wrapper_id.clone(), entry);
+    }
+
+    fn send_from_vault(&self, wrapper_id: TokenId, entry: VaultEntry) -> Promise {
+        let receiver_id = entry
+            .released_to
+            .unwrap_or_else(|| env::panic_str("Wrapper was not burnt"));
+        ext_nft_core::ext(entry.contract_id)
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(GAS_FOR_NFT_TRANSFER)
+            .nft_transfer(receiver_id, entry.token_id, None, None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_ON_VAULT_RELEASED)
+                    .on_vault_released(wrapper_id),
+            )
+    }
+}
+
+#[near]
+impl NonFungibleTokenReceiver for Contract {
+    /// Locks the token in the vault and mints its wrapper to `previous_owner_id`. Tokens of this
+    /// contract or of contracts not added to the vault, and tokens not sent by their owner, are
+    /// returned.
+    #[allow(unused_variables)]
+    fn nft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        previous_owner_id: AccountId,
+        token_id: TokenId,
+        msg: String,
+    ) -> PromiseOrValue<bool> {
+        let contract_id = env::predecessor_account_id();
+        if !self.vault_contracts.contains(&contract_id) || sender_id != previous_owner_id {
+            return PromiseOrValue::Value(true);
+        }
+
+        let wrapper_id = format!("{}:{}", contract_id, token_id);
+        let initial_storage_usage = env::storage_usage();
+        self.vault.insert(
+            &wrapper_id,
+            &VaultEntry {
+                contract_id: contract_id.clone(),
+                token_id: token_id.clone(),
+                released_to: None,
+            },
+        );
+        let token = self.internal_mint(
+            wrapper_id,
+            previous_owner_id.clone(),
+            TokenMetadata {
+                title: Some(format!("Wrapped {}", token_id)),
+                description: Some(format!(
+                    "Token {} of {} held in the vault",
+                    token_id, contract_id
+                )),
+                media: None,
+                media_hash: None,
+                copies: Some(1),
+                issued_at: Some(env::block_timestamp_ms().to_string()),
+                expires_at: None,
+                starts_at: None,
+                updated_at: None,
+                extra: None,
+                reference: None,
+                reference_hash: None,
+            },
+        );
+        self.settle_prepaid_storage(&previous_owner_id, initial_storage_usage);
+
+        NftMint {
+            owner_id: &token.owner_id,
+            token_ids: &[&token.token_id],
+            memo: None,
+        }
+        .emit();
+        PromiseOrValue::Value(false)
+    }
+}
+
+#[near]
+impl Contract {
+    /// Accepts tokens of the NEP-171 contracts `contract_ids` into the vault.
+    pub fn add_vault_contracts(&mut self, contract_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for contract_id in contract_ids {
+            require!(
+                contract_id != env::current_account_id(),
+                "Tokens of this contract cannot be vaulted"
+            );
+            self.vault_contracts.insert(&contract_id);
+        }
+    }
+
+    /// Stops accepting new tokens of `contract_ids`. Tokens already in the vault can still be
+    /// released.
+    pub fn remove_vault_contracts(&mut self, contract_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for contract_id in contract_ids {
+            self.vault_contracts.remove(&contract_id);
+        }
+    }
+
+    pub fn vault_contracts(&self, from_index: Option<U128>, limit: Option<u64>) -> Vec<AccountId> {
+        let start = u128::from(from_index.unwrap_or(U128(0)));
+        self.vault_contracts
+            .iter()
+            .skip(start as usize)
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .collect()
+    }
+
+    /// Forgets the underlying token of `wrapper_id` once it was sent back. The released storage
+    /// is credited to the account which burnt the wrapper if it has a prepaid storage balance,
+    /// and stays with the contract otherwise.
+    #[private]
+    pub fn on_vault_released(
+        &mut self,
+        wrapper_id: TokenId,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) -> bool {
+        if result.is_err() {
+            return false;
+        }
+        if let Some(entry) = self.vault.get(&wrapper_id) {
+            let initial_storage_usage = env::storage_usage();
+            self.vault.remove(&wrapper_id);
+            if let Some(receiver_id) = entry
+                .released_to
+                .filter(|receiver_id| self.storage_balances.get(receiver_id).is_some())
+            {
+                self.settle_prepaid_storage(&receiver_id, initial_storage_usage);
+            }
+        }
+        true
+    }
+
+    /// Sends the underlying token of a burnt wrapper again after a failed release. Only the
+    /// account which burnt the wrapper can call it.
+    pub fn vault_retry_release(&mut self, wrapper_id: TokenId) -> Promise {
+        let entry = self
+            .vault
+            .get(&wrapper_id)
+            .unwrap_or_else(|| env::panic_str("Token is not in the vault"));
+        require!(
+            entry.released_to.as_ref() == Some(&env::predecessor_account_id()),
+            "Only the account which burnt the wrapper can release it"
+        );
+        self.send_from_vault(wrapper_id, entry)
+    }
+
+    /// Underlying token of `wrapper_id`.
+    pub fn vault_entry(&self, wrapper_id: TokenId) -> Option<VaultEntry> {
+        self.vault.get(&wrapper_id)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::test_utils::get_context;
+    use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
+    use near_sdk::test_utils::{accounts, get_created_receipts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    const STORAGE_DEPOSIT: NearToken = NearToken::from_millinear(100);
+    const ONE_YOCTONEAR: NearToken = NearToken::from_yoctonear(1);
+
+    fn call(context: &mut VMContextBuilder, predecessor_id: AccountId, deposit: NearToken) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(deposit)
+            .predecessor_account_id(predecessor_id)
+            .build());
+    }
+
+    /// accounts(1) deposits token "7" of the external contract accounts(3).
+    fn deposit(context: &mut VMContextBuilder, contract: &mut Contract) -> PromiseOrValue<bool> {
+        call(context, accounts(3), NearToken::from_yoctonear(0));
+        contract.nft_on_transfer(accounts(1), accounts(1), "7".to_string(), String::new())
+    }
+
+    /// accounts(3) added to the vault contracts and a prepaid storage balance for accounts(1).
+    fn setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        contract.add_vault_contracts(vec![accounts(3)]);
+        call(context, accounts(1), STORAGE_DEPOSIT);
+        contract.nft_storage_deposit(None);
+        contract
+    }
+
+    fn wrapper_id() -> TokenId {
+        format!("{}:7", accounts(3))
+    }
+
+    #[test]
+    fn test_deposit_mints_wrapper() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        let balance = contract.nft_storage_balance_of(accounts(1));
+
+        assert!(matches!(
+            deposit(&mut context, &mut contract),
+            PromiseOrValue::Value(false)
+        ));
+        let wrapper = contract.nft_token(wrapper_id()).unwrap();
+        assert_eq!(wrapper.owner_id, accounts(1));
+        assert_eq!(
+            contract.vault_entry(wrapper_id()),
+            Some(VaultEntry {
+                contract_id: accounts(3),
+                token_id: "7".to_string(),
+                released_to: None,
+            })
+        );
+        assert!(contract.nft_storage_balance_of(accounts(1)) < balance);
+    }
+
+    #[test]
+    #[should_panic(expected = "Prepaid storage balance of bob is too low")]
+    fn test_deposit_without_storage_balance() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0));
+        contract.add_vault_contracts(vec![accounts(3)]);
+        deposit(&mut context, &mut contract);
+    }
+
+    #[test]
+    fn test_unknown_contract_is_returned() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        let balance = contract.nft_storage_balance_of(accounts(1));
+        call(&mut context, accounts(4), NearToken::from_yoctonear(0));
+        let result =
+            contract.nft_on_transfer(accounts(1), accounts(1), "7".to_string(), String::new());
+        assert!(matches!(result, PromiseOrValue::Value(true)));
+        assert!(contract.nft_token(format!("{}:7", accounts(4))).is_none());
+        assert_eq!(contract.nft_storage_balance_of(accounts(1)), balance);
+    }
+
+    #[test]
+    fn test_token_not_sent_by_owner_is_returned() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        let balance = contract.nft_storage_balance_of(accounts(1));
+        call(&mut context, accounts(3), NearToken::from_yoctonear(0));
+        let result =
+            contract.nft_on_transfer(accounts(2), accounts(1), "7".to_string(), String::new());
+        assert!(matches!(result, PromiseOrValue::Value(true)));
+        assert!(contract.vault_entry(wrapper_id()).is_none());
+        assert_eq!(contract.nft_storage_balance_of(accounts(1)), balance);
+    }
+
+    #[test]
+    fn test_own_tokens_are_returned() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        call(&mut context, accounts(0), NearToken::from_yoctonear(0));
+        let result =
+            contract.nft_on_transfer(accounts(1), accounts(1), "7".to_string(), String::new());
+        assert!(matches!(result, PromiseOrValue::Value(true)));
+    }
+
+    #[test]
+    fn test_burn_wrapper_releases_underlying() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        deposit(&mut context, &mut contract);
+
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_burn(wrapper_id());
+        assert!(contract.nft_token(wrapper_id()).is_none());
+        assert_eq!(
+            contract.vault_entry(wrapper_id()).unwrap().released_to,
+            Some(accounts(1))
+        );
+        assert!(get_created_receipts()
+            .iter()
+            .any(|receipt| receipt.receiver_id == accounts(3)));
+
+        call(&mut context, accounts(0), NearToken::from_yoctonear(0));
+        assert!(contract.on_vault_released(wrapper_id(), Ok(())));
+        assert!(contract.vault_entry(wrapper_id()).is_none());
+    }
+
+    #[test]
+    fn test_release_to_account_without_storage_balance() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        deposit(&mut context, &mut contract);
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_transfer(accounts(2), wrapper_id(), None, None);
+        call(&mut context, accounts(2), ONE_YOCTONEAR);
+        contract.nft_burn(wrapper_id());
+
+        call(&mut context, accounts(0), NearToken::from_yoctonear(0));
+        assert!(contract.on_vault_released(wrapper_id(), Ok(())));
+        assert!(contract.storage_balances.get(&accounts(2)).is_none());
+    }
+
+    #[test]
+    fn test_failed_release_can_be_retried() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup(&mut context);
+        deposit(&mut context, &mut contract);
+        call(&mut context, accounts(1), ONE_YOCTONEAR);
+        contract.nft_burn(wrapper_id());
+
+        call(&mut context, accounts(0), NearToken::from_yoctonear(0));
+        assert!(!contract.on_vault_released(wrapper_id(), Err(PromiseError::Failed)));
+        assert!(contract.vault_entry(wrapper_id()).is_some());
+
+        call(&mut context, accounts(1), NearToken::from_yoctonear(0));
+        contract.vault_retry_release(wrapper_id());
+        assert!(get_created_receipts()
+            .iter()
+            .any(|receipt| receipt.receiver_id == accounts(3)));
+    }
+}
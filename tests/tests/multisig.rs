@@ -0,0 +1,114 @@
+use near_contract_standards::non_fungible_token::Token;
+use near_sdk::serde_json::json;
+
+use near_workspaces::types::NearToken;
+
+const TOKEN_ID: &str = "id-0";
+
+#[tokio::test]
+async fn multisig_mint() -> anyhow::Result<()> {
+    let nft_wasm = near_workspaces::compile_project(".").await.unwrap();
+    let worker: near_workspaces::Worker<near_workspaces::network::Sandbox> =
+        near_workspaces::sandbox().await?;
+    let nft_contract = worker.dev_deploy(&nft_wasm).await?;
+    let owner = worker.dev_create_account().await?;
+    let alice = worker.dev_create_account().await?;
+    let bob = worker.dev_create_account().await?;
+    let carol = worker.dev_create_account().await?;
+    let res = nft_contract
+        .call("new_default_meta")
+        .args_json((owner.id(),))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(res.is_success());
+
+    let res = owner
+        .call(nft_contract.id(), "set_multisig")
+        .args_json(json!({
+            "approvers": [alice.id(), bob.id(), carol.id()],
+            "threshold": 2,
+        }))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(res.is_success());
+
+    // The owner key alone can no longer mint.
+    let res = owner
+        .call(nft_contract.id(), "nft_mint")
+        .args_json(json!({
+            "token_id": TOKEN_ID,
+            "token_owner_id": carol.id(),
+            "token_metadata": {"title": "Olympus Mons", "copies": 1},
+        }))
+        .max_gas()
+        .deposit(NearToken::from_millinear(100))
+        .transact()
+        .await?;
+    assert!(res.is_failure());
+
+    let proposal_id = alice
+        .call(nft_contract.id(), "multisig_propose")
+        .args_json(json!({
+            "action": {"Mint": {
+                "token_id": TOKEN_ID,
+                "receiver_id": carol.id(),
+                "metadata": {"title": "Olympus Mons", "copies": 1},
+            }},
+        }))
+        .max_gas()
+        .deposit(NearToken::from_millinear(100))
+        .transact()
+        .await?
+        .json::<u64>()?;
+
+    let confirmations = nft_contract
+        .call("multisig_confirmations")
+        .args_json(json!({"proposal_id": proposal_id}))
+        .view()
+        .await?
+        .json::<Vec<String>>()?;
+    assert_eq!(confirmations, vec![alice.id().to_string()]);
+
+    let executed = bob
+        .call(nft_contract.id(), "multisig_confirm")
+        .args_json(json!({"proposal_id": proposal_id}))
+        .max_gas()
+        .transact()
+        .await?
+        .json::<bool>()?;
+    assert!(executed);
+
+    let token = nft_contract
+        .call("nft_token")
+        .args_json((TOKEN_ID,))
+        .view()
+        .await?
+        .json::<Option<Token>>()?
+        .unwrap();
+    assert_eq!(token.owner_id.to_string(), carol.id().to_string());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn multisig_rejects_self_owned_contract() -> anyhow::Result<()> {
+    let nft_wasm = near_workspaces::compile_project(".").await.unwrap();
+    let worker: near_workspaces::Worker<near_workspaces::network::Sandbox> =
+        near_workspaces::sandbox().await?;
+    let nft_contract = worker.dev_deploy(&nft_wasm).await?;
+    let alice = worker.dev_create_account().await?;
+    crate::common::init_nft_contract(&nft_contract).await?;
+
+    // The contract key would still act as the owner, bypassing the approvers.
+    let res = nft_contract
+        .call("set_multisig")
+        .args_json(json!({"approvers": [alice.id()], "threshold": 1}))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(res.is_failure());
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use crate::common;
+use near_contract_standards::non_fungible_token::Token;
+use near_sdk::serde_json::json;
+
+use near_workspaces::types::NearToken;
+
+const ONE_YOCTO: NearToken = NearToken::from_yoctonear(1);
+const TOKEN_ID: &str = "id-0";
+
+#[tokio::test]
+async fn vault() -> anyhow::Result<()> {
+    let nft_wasm = near_workspaces::compile_project(".").await.unwrap();
+    let worker: near_workspaces::Worker<near_workspaces::network::Sandbox> =
+        near_workspaces::sandbox().await?;
+
+    // A second NFT contract plays the external collection whose tokens get wrapped.
+    let vault_contract = worker.dev_deploy(&nft_wasm).await?;
+    let external_contract = worker.dev_deploy(&nft_wasm).await?;
+    let alice = worker.dev_create_account().await?;
+    common::init_nft_contract(&vault_contract).await?;
+    common::init_nft_contract(&external_contract).await?;
+    common::mint_nft(
+        external_contract.as_account(),
+        external_contract.id(),
+        TOKEN_ID.into(),
+        alice.id(),
+    )
+    .await?;
+
+    let res = vault_contract
+        .call("add_vault_contracts")
+        .args_json(json!({ "contract_ids": [external_contract.id()] }))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(res.is_success());
+
+    // The wrapper is paid from alice's prepaid storage balance on the vault.
+    let res = alice
+        .call(vault_contract.id(), "nft_storage_deposit")
+        .args_json(json!({}))
+        .max_gas()
+        .deposit(NearToken::from_millinear(100))
+        .transact()
+        .await?;
+    assert!(res.is_success());
+
+    let res = alice
+        .call(external_contract.id(), "nft_transfer_call")
+        .args_json((
+            vault_contract.id(),
+            TOKEN_ID,
+            Option::<u64>::None,
+            Option::<String>::None,
+            "",
+        ))
+        .max_gas()
+        .deposit(ONE_YOCTO)
+        .transact()
+        .await?;
+    assert!(res.is_success());
+
+    let wrapper_id = format!("{}:{}", external_contract.id(), TOKEN_ID);
+    let underlying = external_contract
+        .call("nft_token")
+        .args_json((TOKEN_ID,))
+        .view()
+        .await?
+        .json::<Token>()?;
+    assert_eq!(
+        underlying.owner_id.to_string(),
+        vault_contract.id().to_string()
+    );
+    let wrapper = vault_contract
+        .call("nft_token")
+        .args_json((&wrapper_id,))
+        .view()
+        .await?
+        .json::<Option<Token>>()?
+        .unwrap();
+    assert_eq!(wrapper.owner_id.to_string(), alice.id().to_string());
+
+    // Burning the wrapper sends the underlying token back.
+    let res = alice
+        .call(vault_contract.id(), "nft_burn")
+        .args_json((&wrapper_id,))
+        .max_gas()
+        .deposit(ONE_YOCTO)
+        .transact()
+        .await?;
+    assert!(res.is_success());
+
+    let underlying = external_contract
+        .call("nft_token")
+        .args_json((TOKEN_ID,))
+        .view()
+        .await?
+        .json::<Token>()?;
+    assert_eq!(underlying.owner_id.to_string(), alice.id().to_string());
+    let entry = vault_contract
+        .call("vault_entry")
+        .args_json((&wrapper_id,))
+        .view()
+        .await?
+        .json::<Option<near_sdk::serde_json::Value>>()?;
+    assert!(entry.is_none());
+
+    Ok(())
+}